@@ -1,14 +1,18 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use anyhow::{bail, Result};
 use async_trait::async_trait;
 use aws_config::{from_env, meta::region::RegionProviderChain};
-use aws_sdk_dynamodb::model::{AttributeValue, Select};
+use aws_sdk_dynamodb::model::{AttributeValue, DeleteRequest, PutRequest, Select, WriteRequest};
+use aws_sdk_dynamodb::types::Blob;
 use aws_sdk_dynamodb::Client;
 
 use slight_common::BasicState;
 use slight_runtime_configs::get_from_state;
 use tracing::log;
 
-use super::KeyvalueImplementor;
+use super::{KeyNotFound, KeyvalueImplementor};
 
 /// This is the underlying struct behind the "AWS DynamoDB" variant of the `KeyvalueImplementor` enum.
 ///
@@ -41,10 +45,21 @@ impl AwsDynamoDbImplementor {
     ///       "S": <key>
     ///   },
     ///   "value": {
-    ///       "S": <value>
+    ///       "B": <value>
+    ///   },
+    ///   "ttl": {
+    ///       "N": <expiry as seconds since the Unix epoch> (optional)
     ///   }
     /// }
     /// ```
+    ///
+    /// Values are binary, so that any bytes (e.g., encrypted ones) can be stored.
+    /// Items w/ a string value (i.e., written before values were binary) can
+    /// still be read, and compared-and-swapped.
+    ///
+    /// For items set with a ttl to be removed from the table, enable DynamoDB's
+    /// Time to Live on the `ttl` attribute. Expired items that DynamoDB has not
+    /// deleted yet are never returned.
     pub async fn new(slight_state: &BasicState, name: &str) -> Self {
         let access_id = get_from_state("AWS_ACCESS_KEY_ID", slight_state)
             .await
@@ -76,8 +91,49 @@ impl AwsDynamoDbImplementor {
         );
         Self { client, table_name }
     }
+
+    /// Sends `requests` through `BatchWriteItem`, in chunks of at most 25
+    /// (DynamoDB's limit), resending whatever comes back unprocessed w/ an
+    /// exponential backoff (as AWS recommends), up to `BATCH_WRITE_MAX_ATTEMPTS` times.
+    async fn batch_write(&self, requests: Vec<WriteRequest>) -> Result<()> {
+        for chunk in requests.chunks(BATCH_WRITE_MAX_ITEMS) {
+            let mut request_items = HashMap::from([(self.table_name.clone(), chunk.to_vec())]);
+            let mut backoff = BATCH_WRITE_BACKOFF;
+            for attempt in 1.. {
+                if attempt > BATCH_WRITE_MAX_ATTEMPTS {
+                    let unprocessed: usize = request_items.values().map(Vec::len).sum();
+                    bail!(
+                        "failed to batch write {} item(s): DynamoDB kept leaving them unprocessed",
+                        unprocessed
+                    );
+                }
+                let res = self
+                    .client
+                    .batch_write_item()
+                    .set_request_items(Some(request_items))
+                    .send()
+                    .await?;
+                request_items = res
+                    .unprocessed_items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|(_, requests)| !requests.is_empty())
+                    .collect();
+                if request_items.is_empty() {
+                    break;
+                }
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        Ok(())
+    }
 }
 
+const BATCH_WRITE_MAX_ITEMS: usize = 25;
+const BATCH_WRITE_MAX_ATTEMPTS: u32 = 8;
+const BATCH_WRITE_BACKOFF: Duration = Duration::from_millis(50);
+
 #[async_trait]
 impl KeyvalueImplementor for AwsDynamoDbImplementor {
     async fn get(&self, key: &str) -> Result<Vec<u8>> {
@@ -94,20 +150,14 @@ impl KeyvalueImplementor for AwsDynamoDbImplementor {
             .send()
             .await?;
         match res.items.unwrap_or_default().pop() {
-            Some(item) => {
-                let value = item.get("value").unwrap();
-                let value = value.as_s().unwrap();
-                Ok(value.as_bytes().to_vec())
-            }
-            None => bail!("no value found for key: {}", key),
+            Some(item) if !is_expired(&item) => value_of(key, &item),
+            _ => Err(KeyNotFound(key.to_string()).into()),
         }
     }
 
     async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
         let key_attribute = AttributeValue::S(key.into());
-        let value = AttributeValue::S(
-            String::from_utf8(value.to_vec()).expect("failed to convert value to String"),
-        );
+        let value = to_attribute(value);
         log::info!("Setting key value pair: ({}, {:#?})", key, value);
 
         self.client
@@ -120,20 +170,154 @@ impl KeyvalueImplementor for AwsDynamoDbImplementor {
         Ok(())
     }
 
-    async fn keys(&self) -> Result<Vec<String>> {
-        let res = self
-            .client
-            .scan()
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let key_attribute = AttributeValue::S(key.into());
+        let value = to_attribute(value);
+        let expires_at = SystemTime::now().duration_since(UNIX_EPOCH)? + ttl;
+        log::info!(
+            "Setting key value pair: ({}, {:#?}) with ttl: {:?}",
+            key,
+            value,
+            ttl
+        );
+
+        self.client
+            .put_item()
             .table_name(&self.table_name)
-            .select(Select::AllAttributes)
+            .item("key", key_attribute)
+            .item("value", value)
+            .item("ttl", AttributeValue::N(expires_at.as_secs().to_string()))
             .send()
             .await?;
+        Ok(())
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let key_attribute = AttributeValue::S(key.into());
+        let new = to_attribute(new);
+        log::info!("Compare-and-swapping key: {}", key);
+
+        let put_item = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .item("key", key_attribute)
+            .item("value", new);
+        // an item that has expired, but that DynamoDB has yet to purge, counts as
+        // absent (just like it does for `get`)
+        let now = AttributeValue::N(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)?
+                .as_secs()
+                .to_string(),
+        );
+        let put_item = match old {
+            // a string value is compared too, as the item may have been written
+            // before values were binary
+            Some(old) => match std::str::from_utf8(old) {
+                Ok(old_string) => put_item
+                    .condition_expression(
+                        "(#value = :old OR #value = :old_string) AND (attribute_not_exists(#ttl) OR #ttl > :now)",
+                    )
+                    .expression_attribute_values(":old_string", AttributeValue::S(old_string.into())),
+                Err(_) => put_item.condition_expression(
+                    "#value = :old AND (attribute_not_exists(#ttl) OR #ttl > :now)",
+                ),
+            }
+            .expression_attribute_names("#value", "value")
+            .expression_attribute_names("#ttl", "ttl")
+            .expression_attribute_values(":old", to_attribute(old))
+            .expression_attribute_values(":now", now),
+            None => put_item
+                .condition_expression("attribute_not_exists(#key) OR #ttl <= :now")
+                .expression_attribute_names("#key", "key")
+                .expression_attribute_names("#ttl", "ttl")
+                .expression_attribute_values(":now", now),
+        };
+
+        match put_item.send().await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let e = e.into_service_error();
+                if e.is_conditional_check_failed_exception() {
+                    Ok(false)
+                } else {
+                    Err(e.into())
+                }
+            }
+        }
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        log::info!("Setting {} key value pairs", key_values.len());
+        let requests = key_values
+            .iter()
+            .map(|(key, value)| {
+                WriteRequest::builder()
+                    .put_request(
+                        PutRequest::builder()
+                            .item("key", AttributeValue::S(key.to_string()))
+                            .item("value", to_attribute(value))
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect();
+        self.batch_write(requests).await
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        log::info!("Deleting keys: {:?}", keys);
+        let requests = keys
+            .iter()
+            .map(|key| {
+                WriteRequest::builder()
+                    .delete_request(
+                        DeleteRequest::builder()
+                            .key("key", AttributeValue::S(key.to_string()))
+                            .build(),
+                    )
+                    .build()
+            })
+            .collect();
+        self.batch_write(requests).await
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let mut scan = self
+            .client
+            .scan()
+            .table_name(&self.table_name)
+            .select(Select::AllAttributes);
+        if !prefix.is_empty() {
+            scan = scan
+                .filter_expression("begins_with(#key, :prefix)")
+                .expression_attribute_names("#key", "key")
+                .expression_attribute_values(":prefix", AttributeValue::S(prefix.into()));
+        }
+        if let Some(cursor) = cursor {
+            scan = scan.exclusive_start_key("key", AttributeValue::S(cursor.into()));
+        }
+        if let Some(limit) = limit {
+            // DynamoDB applies the limit before the filter, so a page may hold fewer keys
+            scan = scan.limit(limit.try_into()?);
+        }
+        let res = scan.send().await?;
         let items = res.items.unwrap_or_default();
         let keys = items
             .iter()
+            .filter(|item| !is_expired(item))
             .map(|item| item.get("key").unwrap().as_s().unwrap().to_string())
             .collect();
-        Ok(keys)
+        let next_cursor = res
+            .last_evaluated_key
+            .and_then(|k| k.get("key").and_then(|k| k.as_s().ok()).cloned());
+        Ok((keys, next_cursor))
     }
 
     /// FIXME: should delete return a success if it is a noop
@@ -150,3 +334,31 @@ impl KeyvalueImplementor for AwsDynamoDbImplementor {
         Ok(())
     }
 }
+
+fn to_attribute(value: &[u8]) -> AttributeValue {
+    AttributeValue::B(Blob::new(value))
+}
+
+/// Gets an item's value, which is binary — or a string, if the item was written
+/// before values were binary.
+fn value_of(key: &str, item: &HashMap<String, AttributeValue>) -> Result<Vec<u8>> {
+    match item.get("value") {
+        Some(AttributeValue::B(value)) => Ok(value.as_ref().to_vec()),
+        Some(AttributeValue::S(value)) => Ok(value.as_bytes().to_vec()),
+        _ => bail!("the item for key '{}' has no value", key),
+    }
+}
+
+/// Checks an item's optional `ttl` attribute against the current time.
+fn is_expired(item: &HashMap<String, AttributeValue>) -> bool {
+    match item.get("ttl").and_then(|ttl| ttl.as_n().ok()) {
+        Some(expires_at) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            matches!(expires_at.parse::<u64>(), Ok(e) if e <= now)
+        }
+        None => false,
+    }
+}
@@ -1,25 +1,39 @@
-use anyhow::{Context, Result};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use azure_core::{error::ErrorKind, StatusCode};
 use azure_storage::prelude::*;
-use azure_storage_blobs::{container::operations::BlobItem, prelude::*};
+use azure_storage_blobs::prelude::*;
 use slight_common::BasicState;
 use slight_runtime_configs::get_from_state;
 use tracing::log;
 
 use crate::providers::azure;
 
-use super::KeyvalueImplementor;
+use super::{KeyNotFound, KeyvalueImplementor};
 
 /// This is the underlying struct behind the `AzBlob` variant of the `KeyvalueImplementor` enum.
 ///
-/// It provides a property that pertains solely to the azblob implementation
+/// It provides properties that pertain solely to the azblob implementation
 /// of this capability:
-///     - `container_client`
+///     - `container_client`,
+///     - `http_client`,
+///     - `storage_account_name`,
+///     - `storage_account_key`, and
+///     - `container_name`
+///
+/// The last four are for listing keys, and compare-and-swap, which go through the
+/// REST API.
 ///
 /// As per its' usage in `KeyvalueImplementor`, it must `derive` `Debug`, and `Clone`.
 #[derive(Debug, Clone)]
 pub struct AzBlobImplementor {
     container_client: ContainerClient,
+    http_client: reqwest::Client,
+    storage_account_name: String,
+    storage_account_key: String,
+    container_name: String,
 }
 
 impl AzBlobImplementor {
@@ -32,11 +46,18 @@ impl AzBlobImplementor {
             .unwrap();
 
         let storage_credentials =
-            StorageCredentials::Key(storage_account_name.clone(), storage_account_key);
-        let service_client = BlobServiceClient::new(storage_account_name, storage_credentials);
+            StorageCredentials::Key(storage_account_name.clone(), storage_account_key.clone());
+        let service_client =
+            BlobServiceClient::new(storage_account_name.clone(), storage_credentials);
 
         let container_client = service_client.container_client(name);
-        Self { container_client }
+        Self {
+            container_client,
+            http_client: reqwest::Client::new(),
+            storage_account_name,
+            storage_account_key,
+            container_name: name.to_string(),
+        }
     }
 }
 
@@ -44,10 +65,11 @@ impl AzBlobImplementor {
 impl KeyvalueImplementor for AzBlobImplementor {
     async fn get(&self, key: &str) -> Result<Vec<u8>> {
         let blob_client = self.container_client.blob_client(key);
-        let res = azure::get(blob_client)
-            .await
-            .with_context(|| format!("failed to get value for key {key}"))?;
-        Ok(res)
+        match azure::get(blob_client).await {
+            Ok(res) => Ok(res),
+            Err(e) if is_blob_not_found(&e) => Err(KeyNotFound(key.to_string()).into()),
+            Err(e) => Err(e).with_context(|| format!("failed to get value for key {key}")),
+        }
     }
 
     async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
@@ -59,19 +81,66 @@ impl KeyvalueImplementor for AzBlobImplementor {
         Ok(())
     }
 
-    async fn keys(&self) -> Result<Vec<String>> {
-        let blobs = azure::list_blobs(self.container_client.clone())
+    async fn set_with_ttl(&self, _key: &str, _value: &[u8], _ttl: Duration) -> Result<()> {
+        bail!("keyvalue.azblob does not support setting a ttl")
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        // azure checks the blob's ETag atomically w/ the put, so the swap only
+        // happens if nobody wrote the blob since we read it
+        let etag = match old {
+            None => None,
+            Some(old) => match azure::get_blob_with_etag(
+                &self.http_client,
+                &self.storage_account_name,
+                &self.storage_account_key,
+                &self.container_name,
+                key,
+            )
             .await
-            .with_context(|| "failed to list blobs")?;
-        log::debug!("found blobs: {:?}", blobs);
-        let keys = blobs
-            .iter()
-            .map(|blob| match blob {
-                BlobItem::Blob(b) => b.name.clone(),
-                BlobItem::BlobPrefix(b) => b.name.clone(),
-            })
-            .collect::<Vec<String>>();
-        Ok(keys)
+            .with_context(|| format!("failed to get value for key {key}"))?
+            {
+                Some((current, etag)) if current == old => Some(etag),
+                _ => return Ok(false),
+            },
+        };
+        let condition = match &etag {
+            None => azure::PutCondition::Absent,
+            Some(etag) => azure::PutCondition::Matches(etag),
+        };
+        azure::put_blob_if(
+            &self.http_client,
+            &self.storage_account_name,
+            &self.storage_account_key,
+            &self.container_name,
+            key,
+            new.to_vec(),
+            condition,
+        )
+        .await
+        .with_context(|| format!("failed to compare-and-swap value for key '{key}'"))
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        // azure pages listings itself, and hands back a marker to continue from
+        let (keys, next_marker) = azure::list_blob_names(
+            &self.http_client,
+            &self.storage_account_name,
+            &self.storage_account_key,
+            &self.container_name,
+            prefix,
+            cursor,
+            limit,
+        )
+        .await
+        .with_context(|| "failed to list blobs")?;
+        log::debug!("found blobs: {:?}", keys);
+        Ok((keys, next_marker))
     }
 
     async fn delete(&self, key: &str) -> Result<()> {
@@ -82,3 +151,11 @@ impl KeyvalueImplementor for AzBlobImplementor {
         Ok(())
     }
 }
+
+/// Whether `e` is Azure telling us that the blob we asked for doesn't exist.
+fn is_blob_not_found(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<azure_core::error::Error>().map(|e| e.kind()),
+        Some(ErrorKind::HttpResponse { status, .. }) if *status == StatusCode::NotFound
+    )
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use lru::LruCache;
+use once_cell::sync::Lazy;
+use slight_common::BasicState;
+use slight_runtime_configs::maybe_get_from_state;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::log;
+
+use super::{KeyChange, KeyvalueImplementor};
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// A cached value, and when it stops being served from the cache.
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Instant,
+}
+
+/// What a `Cache` guards w/ a single lock.
+#[derive(Debug)]
+struct CacheState {
+    entries: LruCache<String, Entry>,
+    /// When the keys written w/ `set_with_ttl` (through this host) expire in the
+    /// backend, so that they aren't cached for any longer than that.
+    expiries: HashMap<String, Instant>,
+    /// Bumped by every write, so that a `get` that went to the backend can tell
+    /// whether a write may have landed in the meantime (and its' value be stale).
+    version: u64,
+}
+
+/// The cache for a single store, shared by every instance of it in this process
+/// so that a write through any of them invalidates what the others see.
+#[derive(Debug)]
+struct Cache {
+    state: Mutex<CacheState>,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    fn new(size: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            state: Mutex::new(CacheState {
+                entries: LruCache::new(size),
+                expiries: HashMap::new(),
+                version: 0,
+            }),
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+}
+
+/// What tells caches apart: the capability a store belongs to (i.e., its'
+/// slightfile, name, resource, and configs), the store's name, and the cache's
+/// own settings — so that stores w/ the same name, but different backends (or
+/// cache settings), don't share a cache.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    slightfile_path: PathBuf,
+    capability: String,
+    resource: String,
+    /// Sorted, as the order of a `HashMap`'s entries isn't stable.
+    configs: Vec<(String, String)>,
+    store: String,
+    size: NonZeroUsize,
+    ttl: Duration,
+}
+
+impl CacheKey {
+    fn new(slight_state: &BasicState, store: &str, size: NonZeroUsize, ttl: Duration) -> Self {
+        let mut configs: Vec<(String, String)> = slight_state
+            .configs_map
+            .iter()
+            .flatten()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        configs.sort();
+        Self {
+            slightfile_path: slight_state.slightfile_path.clone(),
+            capability: slight_state.name.clone(),
+            resource: slight_state.implementor.to_string(),
+            configs,
+            store: store.to_string(),
+            size,
+            ttl,
+        }
+    }
+}
+
+/// Every cache in this process.
+static CACHES: Lazy<Mutex<HashMap<CacheKey, Arc<Cache>>>> = Lazy::new(Default::default);
+
+/// This wraps another `KeyvalueImplementor` with a host-side, read-through LRU
+/// cache, so hot keys don't have to go to the backend on every `get`.
+///
+/// Anything that writes to a key through this host invalidates its' cached value.
+/// Writes made by other hosts (and ttls set by them) are only picked up once the
+/// cached value expires.
+///
+/// As per its' usage in `KeyvalueImplementor`, it must `derive` `Debug`, and `Clone`.
+#[derive(Debug, Clone)]
+pub struct CachedImplementor {
+    inner: Arc<dyn KeyvalueImplementor + Send + Sync>,
+    cache: Arc<Cache>,
+}
+
+impl CachedImplementor {
+    /// Wraps `inner` if the capability sets the optional `CACHE_SIZE` config (i.e.,
+    /// the max. number of values to keep), and returns `inner` as-is otherwise.
+    /// Cached values expire after `CACHE_TTL_SECONDS` (defaults to 60).
+    pub async fn maybe_wrap(
+        inner: Arc<dyn KeyvalueImplementor + Send + Sync>,
+        slight_state: &BasicState,
+        name: &str,
+    ) -> Result<Arc<dyn KeyvalueImplementor + Send + Sync>> {
+        let size = match maybe_get_from_state("CACHE_SIZE", slight_state).await? {
+            Some(size) => size.parse::<NonZeroUsize>().with_context(|| {
+                format!("CACHE_SIZE must be a number greater than 0, but got '{size}'")
+            })?,
+            None => return Ok(inner),
+        };
+        let ttl = match maybe_get_from_state("CACHE_TTL_SECONDS", slight_state).await? {
+            Some(ttl) => match ttl.parse() {
+                Ok(ttl) => Duration::from_secs(ttl),
+                Err(_) => bail!("CACHE_TTL_SECONDS must be a number, but got '{ttl}'"),
+            },
+            None => DEFAULT_CACHE_TTL,
+        };
+
+        let cache = CACHES
+            .lock()
+            .unwrap()
+            .entry(CacheKey::new(slight_state, name, size, ttl))
+            .or_insert_with(|| Arc::new(Cache::new(size, ttl)))
+            .clone();
+        Ok(Arc::new(Self { inner, cache }))
+    }
+
+    /// Gets `key`'s cached value (if it is cached, and hasn't expired).
+    fn lookup(&self, key: &str) -> Option<Vec<u8>> {
+        let mut state = self.cache.state.lock().unwrap();
+        let value = match state.entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                state.entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        drop(state);
+
+        let (hits, misses) = match value {
+            Some(_) => (
+                self.cache.hits.fetch_add(1, Ordering::Relaxed) + 1,
+                self.cache.misses.load(Ordering::Relaxed),
+            ),
+            None => (
+                self.cache.hits.load(Ordering::Relaxed),
+                self.cache.misses.fetch_add(1, Ordering::Relaxed) + 1,
+            ),
+        };
+        log::debug!(
+            "keyvalue cache {} for key '{}' (hits: {}, misses: {})",
+            if value.is_some() { "hit" } else { "miss" },
+            key,
+            hits,
+            misses
+        );
+        value
+    }
+
+    /// The current version of the cache. Grab it before going to the backend, and
+    /// hand it to `insert` w/ what the backend returned.
+    fn version(&self) -> u64 {
+        self.cache.state.lock().unwrap().version
+    }
+
+    /// Caches `key`'s `value`, unless a write has happened since `version` (in
+    /// which case `value` may be stale), or the key has expired in the backend.
+    fn insert(&self, key: &str, value: &[u8], version: u64) {
+        let mut state = self.cache.state.lock().unwrap();
+        if state.version != version {
+            return;
+        }
+        let now = Instant::now();
+        let mut expires_at = now + self.cache.ttl;
+        if let Some(&expiry) = state.expiries.get(key) {
+            if expiry <= now {
+                state.expiries.remove(key);
+                return;
+            }
+            expires_at = expires_at.min(expiry);
+        }
+        state.entries.put(
+            key.to_string(),
+            Entry {
+                value: value.to_vec(),
+                expires_at,
+            },
+        );
+    }
+
+    /// Called after a write (whether it succeeded or not). Besides dropping the
+    /// cached values, this bumps the version, so that a `get` that read the old
+    /// value from the backend while the write was happening doesn't cache it
+    /// once the write is done.
+    fn invalidate(&self, keys: &[&str]) {
+        let mut state = self.cache.state.lock().unwrap();
+        state.version += 1;
+        for key in keys {
+            state.entries.pop(*key);
+            state.expiries.remove(*key);
+        }
+    }
+
+    /// Records that `key` expires in the backend at `expires_at`. This bumps the
+    /// version too, so that a `get` that raced w/ the write that set the ttl
+    /// can't cache `key` w/o knowing about it.
+    fn expire(&self, key: &str, expires_at: Instant) {
+        let mut state = self.cache.state.lock().unwrap();
+        state.version += 1;
+        state.entries.pop(key);
+        // forget about the keys that have expired since, so this doesn't grow forever
+        let now = Instant::now();
+        state.expiries.retain(|_, expiry| *expiry > now);
+        state.expiries.insert(key.to_string(), expires_at);
+    }
+}
+
+#[async_trait]
+impl KeyvalueImplementor for CachedImplementor {
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        if let Some(value) = self.lookup(key) {
+            return Ok(value);
+        }
+        let version = self.version();
+        let value = self.inner.get(key).await?;
+        self.insert(key, &value, version);
+        Ok(value)
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        let res = self.inner.set(key, value).await;
+        self.invalidate(&[key]);
+        res
+    }
+
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        // measured from before the write, to err on the side of expiring early
+        let expires_at = Instant::now() + ttl;
+        let res = self.inner.set_with_ttl(key, value, ttl).await;
+        self.invalidate(&[key]);
+        if res.is_ok() {
+            self.expire(key, expires_at);
+        }
+        res
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let res = self.inner.compare_and_swap(key, old, new).await;
+        self.invalidate(&[key]);
+        res
+    }
+
+    async fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let res = self.inner.increment(key, delta).await;
+        self.invalidate(&[key]);
+        res
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.inner.keys(prefix, cursor, limit).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let res = self.inner.delete(key).await;
+        self.invalidate(&[key]);
+        res
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut values = Vec::with_capacity(keys.len());
+        let mut uncached = Vec::new();
+        for key in keys {
+            match self.lookup(key) {
+                Some(value) => values.push((key.to_string(), value)),
+                None => uncached.push(*key),
+            }
+        }
+        if !uncached.is_empty() {
+            let version = self.version();
+            for (key, value) in self.inner.get_many(&uncached).await? {
+                self.insert(&key, &value, version);
+                values.push((key, value));
+            }
+        }
+        Ok(values)
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        let keys: Vec<&str> = key_values.iter().map(|(key, _)| *key).collect();
+        let res = self.inner.set_many(key_values).await;
+        self.invalidate(&keys);
+        res
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        let res = self.inner.delete_many(keys).await;
+        self.invalidate(keys);
+        res
+    }
+
+    async fn watch(&self, prefix: &str) -> Result<UnboundedReceiver<KeyChange>> {
+        self.inner.watch(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        num::NonZeroUsize,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+
+    use slight_common::BasicState;
+
+    use super::{Cache, CacheKey, CachedImplementor};
+    use crate::implementors::{KeyNotFound, KeyvalueImplementor};
+
+    /// A backend that counts its' `get`s, and can be made to hold one up.
+    #[derive(Default)]
+    struct Backend {
+        values: Mutex<HashMap<String, Vec<u8>>>,
+        gets: AtomicUsize,
+        /// If set, the next `get` reads the value, lets the first `Notify` know,
+        /// then waits for the second one before returning it.
+        hold: Mutex<Option<(Arc<Notify>, Arc<Notify>)>>,
+    }
+
+    #[async_trait]
+    impl KeyvalueImplementor for Backend {
+        async fn get(&self, key: &str) -> Result<Vec<u8>> {
+            self.gets.fetch_add(1, Ordering::SeqCst);
+            let value = self.values.lock().unwrap().get(key).cloned();
+            let hold = self.hold.lock().unwrap().take();
+            if let Some((read, release)) = hold {
+                read.notify_one();
+                release.notified().await;
+            }
+            value.ok_or_else(|| KeyNotFound(key.to_string()).into())
+        }
+
+        async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            self.values
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+
+        async fn set_with_ttl(&self, key: &str, value: &[u8], _ttl: Duration) -> Result<()> {
+            self.set(key, value).await
+        }
+
+        async fn compare_and_swap(
+            &self,
+            key: &str,
+            old: Option<&[u8]>,
+            new: &[u8],
+        ) -> Result<bool> {
+            let mut values = self.values.lock().unwrap();
+            if values.get(key).map(Vec::as_slice) != old {
+                return Ok(false);
+            }
+            values.insert(key.to_string(), new.to_vec());
+            Ok(true)
+        }
+
+        async fn keys(
+            &self,
+            prefix: &str,
+            _: Option<&str>,
+            _: Option<u32>,
+        ) -> Result<(Vec<String>, Option<String>)> {
+            let mut keys: Vec<String> = self
+                .values
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            keys.sort();
+            Ok((keys, None))
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.values.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn cached(backend: Arc<Backend>) -> CachedImplementor {
+        CachedImplementor {
+            inner: backend,
+            cache: Arc::new(Cache::new(
+                NonZeroUsize::new(10).unwrap(),
+                Duration::from_secs(60),
+            )),
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_hit_test() -> Result<()> {
+        let backend = Arc::new(Backend::default());
+        let cached = cached(backend.clone());
+        cached.set("key", b"value").await?;
+        assert_eq!(cached.get("key").await?, b"value");
+        assert_eq!(cached.get("key").await?, b"value");
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_invalidation_test() -> Result<()> {
+        let backend = Arc::new(Backend::default());
+        let cached = cached(backend.clone());
+        cached.set("key", b"old").await?;
+        assert_eq!(cached.get("key").await?, b"old");
+        cached.set("key", b"new").await?;
+        assert_eq!(cached.get("key").await?, b"new");
+        cached.delete("key").await?;
+        assert!(cached.get("key").await.is_err());
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_stale_read_test() -> Result<()> {
+        let (read, release) = (Arc::new(Notify::new()), Arc::new(Notify::new()));
+        let backend = Arc::new(Backend::default());
+        *backend.hold.lock().unwrap() = Some((read.clone(), release.clone()));
+        backend.set("key", b"old").await?;
+        let cached = cached(backend.clone());
+
+        // a miss reads the old value, then a write lands before the miss is done
+        let get = tokio::spawn({
+            let cached = cached.clone();
+            async move { cached.get("key").await }
+        });
+        read.notified().await;
+        cached.set("key", b"new").await?;
+        release.notify_one();
+        assert_eq!(get.await??, b"old");
+
+        // so the old value mustn't have been cached
+        assert_eq!(cached.get("key").await?, b"new");
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cache_ttl_test() -> Result<()> {
+        let backend = Arc::new(Backend::default());
+        let cached = cached(backend.clone());
+        cached
+            .set_with_ttl("key", b"value", Duration::from_millis(100))
+            .await?;
+        assert_eq!(cached.get("key").await?, b"value");
+        assert_eq!(cached.get("key").await?, b"value");
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 1);
+        // once the key expires in the backend, it isn't served from the cache anymore
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        cached.get("key").await?;
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn cache_key_test() {
+        let size = NonZeroUsize::new(10).unwrap();
+        let ttl = Duration::from_secs(60);
+        let state = |address: &str| BasicState {
+            name: "my-store".to_string(),
+            configs_map: Some(HashMap::from([
+                ("REDIS_ADDRESS".to_string(), address.to_string()),
+                ("CACHE_SIZE".to_string(), "10".to_string()),
+            ])),
+            ..Default::default()
+        };
+        let key = CacheKey::new(&state("redis://a"), "store", size, ttl);
+        assert_eq!(key, CacheKey::new(&state("redis://a"), "store", size, ttl));
+        // stores of capabilities w/ different backends, or cache settings, don't
+        // share a cache
+        assert_ne!(key, CacheKey::new(&state("redis://b"), "store", size, ttl));
+        assert_ne!(
+            key,
+            CacheKey::new(
+                &state("redis://a"),
+                "store",
+                NonZeroUsize::new(20).unwrap(),
+                ttl
+            )
+        );
+        assert_ne!(
+            key,
+            CacheKey::new(&state("redis://a"), "store", size, Duration::from_secs(1))
+        );
+        assert_ne!(key, CacheKey::new(&state("redis://a"), "other", size, ttl));
+    }
+
+    #[tokio::test]
+    async fn cache_compare_and_swap_test() -> Result<()> {
+        let backend = Arc::new(Backend::default());
+        let cached = cached(backend.clone());
+        assert!(cached.compare_and_swap("key", None, b"old").await?);
+        assert_eq!(cached.get("key").await?, b"old");
+        assert!(!cached.compare_and_swap("key", None, b"new").await?);
+        assert!(cached.compare_and_swap("key", Some(b"old"), b"new").await?);
+        // the swap invalidates the cached value
+        assert_eq!(cached.get("key").await?, b"new");
+        assert_eq!(backend.gets.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+}
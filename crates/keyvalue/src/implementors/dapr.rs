@@ -0,0 +1,316 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use reqwest::{Client, StatusCode, Url};
+use serde_json::{json, Value};
+use slight_common::BasicState;
+use slight_runtime_configs::maybe_get_from_state;
+use tracing::log;
+
+use super::{is_key_not_found, KeyNotFound, KeyvalueImplementor};
+
+const DEFAULT_DAPR_HTTP_PORT: &str = "3500";
+
+/// This is the underlying struct behind the `Dapr` variant of the `KeyvalueImplementor` enum.
+///
+/// It provides properties that pertain solely to the Dapr implementation
+/// of this capability:
+///     - `client`,
+///     - `state_url` (i.e., the sidecar's `/v1.0/state/<store>` endpoint), and
+///     - `query_url` (i.e., the sidecar's `/v1.0-alpha1/state/<store>/query` endpoint).
+///
+/// As per its' usage in `KeyvalueImplementor`, it must `derive` `Debug`, and `Clone`.
+#[derive(Debug, Clone)]
+pub struct DaprImplementor {
+    client: Client,
+    state_url: Url,
+    query_url: Url,
+}
+
+impl DaprImplementor {
+    /// Creates a new `DaprImplementor` instance.
+    ///
+    /// It talks to the Dapr sidecar's HTTP state API on `localhost`, using the optional configs:
+    ///   - `DAPR_HTTP_PORT` (defaults to 3500), and
+    ///   - `DAPR_STORE_NAME` (defaults to the capability's name).
+    ///
+    /// Values are stored as base64-encoded JSON strings, so that any payload
+    /// survives the trip through Dapr's JSON API.
+    pub async fn new(slight_state: &BasicState, name: &str) -> Self {
+        let port = maybe_get_from_state("DAPR_HTTP_PORT", slight_state)
+            .await
+            .unwrap()
+            .unwrap_or_else(|| DEFAULT_DAPR_HTTP_PORT.to_string());
+        let store_name = maybe_get_from_state("DAPR_STORE_NAME", slight_state)
+            .await
+            .unwrap()
+            .unwrap_or_else(|| name.to_string());
+
+        let base_url = Url::parse(&format!("http://localhost:{port}")).unwrap();
+        let mut state_url = base_url.clone();
+        state_url
+            .path_segments_mut()
+            .unwrap()
+            .extend(["v1.0", "state", store_name.as_str()]);
+        let mut query_url = base_url;
+        query_url.path_segments_mut().unwrap().extend([
+            "v1.0-alpha1",
+            "state",
+            store_name.as_str(),
+            "query",
+        ]);
+
+        log::info!("Creating a new Dapr keyvalue resource with store name: {store_name}");
+        Self {
+            client: Client::new(),
+            state_url,
+            query_url,
+        }
+    }
+
+    fn url(&self, segment: &str) -> Result<Url> {
+        let mut url = self.state_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid dapr state url"))?
+            .push(segment);
+        Ok(url)
+    }
+
+    /// Gets the value for `key` along with its etag.
+    async fn get_with_etag(&self, key: &str) -> Result<(Vec<u8>, Option<String>)> {
+        let res = self.client.get(self.url(key)?).send().await?;
+        match res.status() {
+            StatusCode::OK => {
+                let etag = res
+                    .headers()
+                    .get("etag")
+                    .and_then(|e| e.to_str().ok())
+                    .map(|e| e.to_string());
+                let value = decode_value(&res.json::<Value>().await?)?;
+                Ok((value, etag))
+            }
+            StatusCode::NO_CONTENT => Err(KeyNotFound(key.to_string()).into()),
+            status => bail!(
+                "failed to get key '{}' from dapr ({}): {}",
+                key,
+                status,
+                res.text().await?
+            ),
+        }
+    }
+
+    /// Saves `items` (i.e., Dapr state objects). Returns the response's status code
+    /// for callers that care about specific failures; any other failure is an error.
+    async fn save(&self, items: Vec<Value>) -> Result<StatusCode> {
+        let res = self
+            .client
+            .post(self.state_url.clone())
+            .json(&items)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() && status != StatusCode::CONFLICT {
+            bail!(
+                "failed to save state to dapr ({}): {}",
+                status,
+                res.text().await?
+            );
+        }
+        Ok(status)
+    }
+}
+
+fn encode_value(value: &[u8]) -> Value {
+    Value::String(STANDARD.encode(value))
+}
+
+fn decode_value(value: &Value) -> Result<Vec<u8>> {
+    let value = value
+        .as_str()
+        .with_context(|| "value stored in dapr is not a string")?;
+    Ok(STANDARD.decode(value)?)
+}
+
+#[async_trait]
+impl KeyvalueImplementor for DaprImplementor {
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        Ok(self.get_with_etag(key).await?.0)
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.save(vec![json!({ "key": key, "value": encode_value(value) })])
+            .await?;
+        Ok(())
+    }
+
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        self.save(vec![json!({
+            "key": key,
+            "value": encode_value(value),
+            "metadata": { "ttlInSeconds": ttl.as_secs().to_string() },
+        })])
+        .await?;
+        Ok(())
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let old = match old {
+            Some(old) => old,
+            None => {
+                // a key that doesn't exist yet has no etag to guard the write w/, but
+                // first-write concurrency w/o an etag only saves keys that don't exist
+                let status = self
+                    .save(vec![json!({
+                        "key": key,
+                        "value": encode_value(new),
+                        "options": { "concurrency": "first-write" },
+                    })])
+                    .await?;
+                return Ok(status != StatusCode::CONFLICT);
+            }
+        };
+        let (current, etag) = match self.get_with_etag(key).await {
+            Ok(current) => current,
+            Err(e) if is_key_not_found(&e) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if current != old {
+            return Ok(false);
+        }
+        let etag = etag.with_context(|| format!("dapr returned no etag for key '{key}'"))?;
+
+        let status = self
+            .save(vec![json!({
+                "key": key,
+                "value": encode_value(new),
+                "etag": etag,
+                "options": { "concurrency": "first-write" },
+            })])
+            .await?;
+        // 409 means someone else wrote to the key since we read it
+        Ok(status != StatusCode::CONFLICT)
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<(String, Vec<u8>)>> {
+        let res = self
+            .client
+            .post(self.url("bulk")?)
+            .json(&json!({ "keys": keys }))
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            bail!(
+                "failed to bulk get keys from dapr ({}): {}",
+                status,
+                res.text().await?
+            );
+        }
+
+        let mut values = Vec::with_capacity(keys.len());
+        for item in res.json::<Vec<Value>>().await? {
+            // keys that don't exist come back without `data`
+            let data = item.get("data").filter(|data| !data.is_null());
+            if let (Some(key), Some(data)) = (item["key"].as_str(), data) {
+                values.push((key.to_string(), decode_value(data)?));
+            }
+        }
+        Ok(values)
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        let items = key_values
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": encode_value(value) }))
+            .collect();
+        self.save(items).await?;
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        let operations: Vec<Value> = keys
+            .iter()
+            .map(|key| json!({ "operation": "delete", "request": { "key": key } }))
+            .collect();
+        let res = self
+            .client
+            .post(self.url("transaction")?)
+            .json(&json!({ "operations": operations }))
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            bail!(
+                "failed to delete keys from dapr ({}): {}",
+                status,
+                res.text().await?
+            );
+        }
+        Ok(())
+    }
+
+    /// Listing keys uses Dapr's (alpha) state query API, so it only works with
+    /// state stores that support querying. Prefixes are filtered on our side.
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let mut page = json!({});
+        if let Some(limit) = limit {
+            page["limit"] = json!(limit);
+        }
+        if let Some(cursor) = cursor {
+            page["token"] = json!(cursor);
+        }
+        let res = self
+            .client
+            .post(self.query_url.clone())
+            .json(&json!({ "filter": {}, "page": page }))
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            bail!(
+                "failed to query keys from dapr ({}): {}",
+                status,
+                res.text().await?
+            );
+        }
+
+        let res = res.json::<Value>().await?;
+        let keys = res["results"]
+            .as_array()
+            .map(|results| {
+                results
+                    .iter()
+                    .filter_map(|r| r["key"].as_str())
+                    .filter(|k| k.starts_with(prefix))
+                    .map(|k| k.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let next_cursor = res["token"]
+            .as_str()
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string());
+        Ok((keys, next_cursor))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let res = self.client.delete(self.url(key)?).send().await?;
+        let status = res.status();
+        if !status.is_success() {
+            bail!(
+                "failed to delete key '{}' from dapr ({}): {}",
+                key,
+                status,
+                res.text().await?
+            );
+        }
+        Ok(())
+    }
+}
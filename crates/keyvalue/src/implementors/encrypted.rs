@@ -0,0 +1,359 @@
+use std::{sync::Arc, time::Duration};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use slight_common::BasicState;
+use slight_runtime_configs::maybe_get_from_state;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use super::{is_key_not_found, KeyChange, KeyvalueImplementor};
+
+/// Length, in bytes, of the nonce that prefixes every encrypted value.
+const NONCE_LEN: usize = 12;
+
+/// This wraps another `KeyvalueImplementor`, encrypting values on their way
+/// in and decrypting them on their way out (with AES-256-GCM), so the backend
+/// only ever sees ciphertext.
+///
+/// Keys are not encrypted. Each value is bound to its' key (as associated data),
+/// so a value copied over to another key won't decrypt.
+///
+/// Encrypted values are base64-encoded, so that backends that only store strings
+/// can hold them too. Values encrypted before that (i.e., raw ciphertext) still
+/// decrypt.
+///
+/// As per its' usage in `KeyvalueImplementor`, it must implement `Debug`, and `Clone`.
+#[derive(Clone)]
+pub struct EncryptedImplementor {
+    inner: Arc<dyn KeyvalueImplementor + Send + Sync>,
+    cipher: Aes256Gcm,
+}
+
+// `Debug` is implemented by hand to keep the cipher (and its' key) out of logs.
+impl std::fmt::Debug for EncryptedImplementor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedImplementor")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedImplementor {
+    /// Wraps `inner` if the capability sets the optional `ENCRYPTION_KEY` config
+    /// (i.e., a base64-encoded, 32-byte key), and returns `inner` as-is otherwise.
+    pub async fn maybe_wrap(
+        inner: Arc<dyn KeyvalueImplementor + Send + Sync>,
+        slight_state: &BasicState,
+    ) -> Arc<dyn KeyvalueImplementor + Send + Sync> {
+        match maybe_get_from_state("ENCRYPTION_KEY", slight_state)
+            .await
+            .unwrap()
+        {
+            Some(key) => Arc::new(Self::new(inner, &key).unwrap()),
+            None => inner,
+        }
+    }
+
+    fn new(inner: Arc<dyn KeyvalueImplementor + Send + Sync>, key: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(key.trim())
+            .with_context(|| "ENCRYPTION_KEY must be base64-encoded")?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow!("ENCRYPTION_KEY must be 32 bytes long"))?;
+        Ok(Self { inner, cipher })
+    }
+
+    fn encrypt(&self, key: &str, value: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: value,
+                    aad: key.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("failed to encrypt value for key '{}'", key))?;
+
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(ciphertext);
+        Ok(STANDARD.encode(encrypted).into_bytes())
+    }
+
+    fn decrypt(&self, key: &str, encrypted: &[u8]) -> Result<Vec<u8>> {
+        // raw ciphertext won't authenticate if it is mistaken for base64 (or vice
+        // versa), so whichever way the value was stored, only one of these works
+        match STANDARD.decode(encrypted) {
+            Ok(decoded) => self
+                .decrypt_raw(key, &decoded)
+                .or_else(|_| self.decrypt_raw(key, encrypted)),
+            Err(_) => self.decrypt_raw(key, encrypted),
+        }
+    }
+
+    fn decrypt_raw(&self, key: &str, encrypted: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.len() < NONCE_LEN {
+            bail!("value for key '{}' is not encrypted", key);
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: key.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("failed to decrypt value for key '{}'", key))
+    }
+}
+
+#[async_trait]
+impl KeyvalueImplementor for EncryptedImplementor {
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.decrypt(key, &self.inner.get(key).await?)
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.inner.set(key, &self.encrypt(key, value)?).await
+    }
+
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        self.inner
+            .set_with_ttl(key, &self.encrypt(key, value)?, ttl)
+            .await
+    }
+
+    /// Encrypting the same value twice gives different ciphertexts, so `old` is
+    /// compared against the decrypted current value, and the swap is then guarded
+    /// by the ciphertext it was decrypted from.
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let new = self.encrypt(key, new)?;
+        match old {
+            Some(old) => {
+                let current = match self.inner.get(key).await {
+                    Ok(current) => current,
+                    Err(e) if is_key_not_found(&e) => return Ok(false),
+                    Err(e) => return Err(e),
+                };
+                if self.decrypt(key, &current)? != old {
+                    return Ok(false);
+                }
+                self.inner.compare_and_swap(key, Some(&current), &new).await
+            }
+            None => self.inner.compare_and_swap(key, None, &new).await,
+        }
+    }
+
+    // `increment` is left to the default implementation, since the backend can't
+    // do arithmetic on ciphertext
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        self.inner.keys(prefix, cursor, limit).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(key).await
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<(String, Vec<u8>)>> {
+        self.inner
+            .get_many(keys)
+            .await?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = self.decrypt(&key, &value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        let encrypted = key_values
+            .iter()
+            .map(|(key, value)| Ok((*key, self.encrypt(key, value)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let encrypted: Vec<(&str, &[u8])> = encrypted
+            .iter()
+            .map(|(key, value)| (*key, value.as_slice()))
+            .collect();
+        self.inner.set_many(&encrypted).await
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        self.inner.delete_many(keys).await
+    }
+
+    async fn watch(&self, prefix: &str) -> Result<UnboundedReceiver<KeyChange>> {
+        self.inner.watch(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use aes_gcm::{aead::KeyInit, Aes256Gcm};
+    use anyhow::{bail, Result};
+    use async_trait::async_trait;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::EncryptedImplementor;
+    use crate::implementors::{KeyNotFound, KeyvalueImplementor};
+
+    /// A backend that, like a store of strings, rejects values that aren't utf-8.
+    #[derive(Default)]
+    struct StringBackend {
+        values: Mutex<HashMap<String, String>>,
+    }
+
+    impl StringBackend {
+        fn to_string(value: &[u8]) -> Result<String> {
+            match String::from_utf8(value.to_vec()) {
+                Ok(value) => Ok(value),
+                Err(_) => bail!("values must be utf-8"),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl KeyvalueImplementor for StringBackend {
+        async fn get(&self, key: &str) -> Result<Vec<u8>> {
+            match self.values.lock().unwrap().get(key) {
+                Some(value) => Ok(value.as_bytes().to_vec()),
+                None => Err(KeyNotFound(key.to_string()).into()),
+            }
+        }
+
+        async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+            let value = Self::to_string(value)?;
+            self.values.lock().unwrap().insert(key.to_string(), value);
+            Ok(())
+        }
+
+        async fn set_with_ttl(&self, key: &str, value: &[u8], _ttl: Duration) -> Result<()> {
+            self.set(key, value).await
+        }
+
+        async fn compare_and_swap(
+            &self,
+            key: &str,
+            old: Option<&[u8]>,
+            new: &[u8],
+        ) -> Result<bool> {
+            let new = Self::to_string(new)?;
+            let mut values = self.values.lock().unwrap();
+            if values.get(key).map(String::as_bytes) != old {
+                return Ok(false);
+            }
+            values.insert(key.to_string(), new);
+            Ok(true)
+        }
+
+        async fn keys(
+            &self,
+            prefix: &str,
+            _: Option<&str>,
+            _: Option<u32>,
+        ) -> Result<(Vec<String>, Option<String>)> {
+            let mut keys: Vec<String> = self
+                .values
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| key.starts_with(prefix))
+                .cloned()
+                .collect();
+            keys.sort();
+            Ok((keys, None))
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            self.values.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    fn encrypted(backend: Arc<StringBackend>) -> EncryptedImplementor {
+        EncryptedImplementor::new(backend, &STANDARD.encode([7u8; 32])).unwrap()
+    }
+
+    #[tokio::test]
+    async fn string_backend_test() -> Result<()> {
+        let backend = Arc::new(StringBackend::default());
+        let encrypted = encrypted(backend.clone());
+
+        encrypted.set("key", &[0, 159, 146, 150]).await?;
+        assert_eq!(encrypted.get("key").await?, [0, 159, 146, 150]);
+        // the backend only ever sees ciphertext
+        assert_ne!(backend.get("key").await?, [0, 159, 146, 150]);
+
+        encrypted
+            .set_with_ttl("ttl", b"value", Duration::from_secs(60))
+            .await?;
+        assert_eq!(encrypted.get("ttl").await?, b"value");
+
+        encrypted
+            .set_many(&[("a", b"1".as_slice()), ("b", b"2".as_slice())])
+            .await?;
+        assert_eq!(
+            encrypted.get_many(&["a", "b"]).await?,
+            vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("b".to_string(), b"2".to_vec())
+            ]
+        );
+
+        assert!(encrypted.compare_and_swap("cas", None, b"old").await?);
+        assert!(
+            !encrypted
+                .compare_and_swap("cas", Some(b"other"), b"new")
+                .await?
+        );
+        assert!(
+            encrypted
+                .compare_and_swap("cas", Some(b"old"), b"new")
+                .await?
+        );
+        assert_eq!(encrypted.get("cas").await?, b"new");
+
+        assert_eq!(encrypted.increment("counter", 2).await?, 2);
+        assert_eq!(encrypted.increment("counter", 3).await?, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn decrypt_test() -> Result<()> {
+        let encrypted = encrypted(Arc::new(StringBackend::default()));
+        let value = encrypted.encrypt("key", b"value")?;
+        assert_eq!(encrypted.decrypt("key", &value)?, b"value");
+        // values are bound to their key
+        assert!(encrypted.decrypt("other", &value).is_err());
+        // raw ciphertext (as values used to be stored) still decrypts
+        let raw = STANDARD.decode(&value)?;
+        assert_eq!(encrypted.decrypt("key", &raw)?, b"value");
+        assert!(encrypted.decrypt("key", b"value").is_err());
+        // a different key doesn't decrypt it
+        let other = EncryptedImplementor {
+            cipher: Aes256Gcm::new_from_slice(&[8u8; 32]).unwrap(),
+            ..encrypted
+        };
+        assert!(other.decrypt("key", &value).is_err());
+        Ok(())
+    }
+}
@@ -1,15 +1,23 @@
 use std::{
-    env,
     fs::{self, File},
-    io::{Read, Write},
-    path::PathBuf,
+    io::{ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use fs2::FileExt;
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Config, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
 use slight_common::BasicState;
+use slight_runtime_configs::filesystem_root_from_state;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::log;
 
-use super::KeyvalueImplementor;
+use super::{is_key_not_found, KeyChange, KeyNotFound, KeyvalueImplementor};
 
 /// This is the underlying struct behind the `Filesystem` variant of the `KeyvalueImplementor` enum.
 ///
@@ -25,20 +33,187 @@ pub struct FilesystemImplementor {
 }
 
 impl FilesystemImplementor {
-    pub async fn new(_slight_state: &BasicState, name: &str) -> Self {
-        Self {
-            base: env::temp_dir().join(name).to_str().unwrap().to_owned(),
+    /// Creates a new `FilesystemImplementor` instance.
+    ///
+    /// The store lives under `<root>/<name>`, where `<root>` comes from the
+    /// optional `FILESYSTEM_ROOT` config (see `filesystem_root_from_state`), and
+    /// `<name>` is encoded like a key (see `store_dir`).
+    pub async fn new(slight_state: &BasicState, name: &str) -> Result<Self> {
+        let root = filesystem_root_from_state(slight_state).await?;
+        let base = store_dir(&root, name);
+        Ok(Self {
+            base: base
+                .to_str()
+                .with_context(|| format!("store path '{}' is not valid UTF-8", base.display()))?
+                .to_owned(),
+        })
+    }
+
+    fn key_file_name(key: &str) -> Result<String> {
+        if key.is_empty() {
+            bail!("invalid key: key cannot be empty");
+        }
+        Ok(encode_key(key))
+    }
+
+    fn ttl_dir(&self) -> PathBuf {
+        PathBuf::from(&self.base).join(TTL_DIR)
+    }
+
+    /// Lazily evicts a key whose ttl has passed. Returns whether the key was expired.
+    fn evict_if_expired(&self, file_name: &str) -> Result<bool> {
+        let ttl_path = self.ttl_dir().join(file_name);
+        let expires_at = match fs::read_to_string(&ttl_path) {
+            Ok(expires_at) => expires_at
+                .trim()
+                .parse::<u64>()
+                .with_context(|| "failed to parse key's expiry")?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).with_context(|| "failed to read key's expiry"),
+        };
+
+        if expires_at > SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() {
+            return Ok(false);
         }
+
+        remove_file_if_exists(&PathBuf::from(&self.base).join(file_name))?;
+        remove_file_if_exists(&ttl_path)?;
+        Ok(true)
     }
 }
 
+// Encoded keys never start with a `.`, so these can't collide with a key.
+/// Name of the hidden directory holding expiry metadata.
+const TTL_DIR: &str = ".ttl";
+/// Name of the hidden file used to serialize atomic operations on the store.
+const LOCK_FILE: &str = ".lock";
+
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove '{}'", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The directory the store called `name` lives in under `root`.
+///
+/// Names are encoded like keys, so that they can't escape `root`. Stores used to
+/// live in `<root>/<name>` as-is, though, so one whose name is a plain file name
+/// anyway (e.g., `my store`), but changes when encoded, keeps using its' old
+/// directory if that exists, and the encoded one doesn't, rather than have its'
+/// data orphaned. Moving the old directory to the encoded name migrates it.
+fn store_dir(root: &Path, name: &str) -> PathBuf {
+    let encoded = root.join(encode_key(name));
+    let mut components = Path::new(name).components();
+    let is_plain = matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    );
+    if is_plain && !encoded.exists() {
+        let old = root.join(name);
+        if old != encoded && old.is_dir() {
+            log::warn!(
+                "using store directory '{}', as it predates store names being encoded; move it to '{}' to migrate it",
+                old.display(),
+                encoded.display()
+            );
+            return old;
+        }
+    }
+    encoded
+}
+
+/// Encodes a key into a single, safe file name.
+///
+/// Alphanumerics, `-`, `_` and non-leading `.` are kept as-is; every other byte
+/// is percent-encoded. This keeps keys like `a/b` or `../../etc/passwd` from
+/// escaping the store's directory.
+fn encode_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for (i, b) in key.bytes().enumerate() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => encoded.push(b as char),
+            b'.' if i != 0 => encoded.push('.'),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Reverses `encode_key`.
+fn decode_key(file_name: &str) -> Result<String> {
+    let bytes = file_name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = file_name
+                .get(i + 1..i + 3)
+                .with_context(|| format!("malformed key file name '{file_name}'"))?;
+            decoded.push(
+                u8::from_str_radix(hex, 16)
+                    .with_context(|| format!("malformed key file name '{file_name}'"))?,
+            );
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Turns a filesystem event into the changes it made to the keys under `base`
+/// that start with `prefix`.
+fn key_changes(event: Event, base: &Path, prefix: &str) -> Vec<KeyChange> {
+    let key = |path: &PathBuf| -> Option<String> {
+        if path.parent() != Some(base) {
+            return None;
+        }
+        let file_name = path.file_name()?.to_str()?;
+        // skip our own hidden files (i.e., the ttl directory, and the lock file)
+        if file_name.starts_with('.') {
+            return None;
+        }
+        decode_key(file_name).ok().filter(|k| k.starts_with(prefix))
+    };
+
+    let mut changes = Vec::new();
+    match event.kind {
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+            changes.extend(key(&event.paths[0]).map(KeyChange::Delete));
+            changes.extend(key(&event.paths[1]).map(KeyChange::Set));
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            changes.extend(event.paths.iter().filter_map(key).map(KeyChange::Delete));
+        }
+        EventKind::Modify(ModifyKind::Metadata(_)) => {}
+        EventKind::Create(_) | EventKind::Modify(_) => {
+            changes.extend(event.paths.iter().filter_map(key).map(KeyChange::Set));
+        }
+        _ => {}
+    }
+    changes
+}
+
 #[async_trait]
 impl KeyvalueImplementor for FilesystemImplementor {
     async fn get(&self, key: &str) -> Result<Vec<u8>> {
         fs::create_dir_all(&self.base)
             .with_context(|| "failed to create base directory for keyvalue instance")?;
-        let mut file =
-            File::open(PathBuf::from(&self.base).join(key)).with_context(|| "failed to get key")?;
+        let file_name = Self::key_file_name(key)?;
+        if self.evict_if_expired(&file_name)? {
+            return Err(KeyNotFound(key.to_string()).into());
+        }
+        let mut file = match File::open(PathBuf::from(&self.base).join(file_name)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(KeyNotFound(key.to_string()).into())
+            }
+            Err(e) => return Err(e).with_context(|| "failed to get key"),
+        };
 
         let mut buf = Vec::new();
         file.read_to_end(&mut buf)
@@ -49,32 +224,198 @@ impl KeyvalueImplementor for FilesystemImplementor {
     async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
         fs::create_dir_all(&self.base)
             .with_context(|| "failed to create base directory for keyvalue instance")?;
+        let file_name = Self::key_file_name(key)?;
 
-        let mut file = File::create(PathBuf::from(&self.base).join(key))
+        let mut file = File::create(PathBuf::from(&self.base).join(&file_name))
             .with_context(|| "failed to create key")?;
 
         file.write_all(value)
             .with_context(|| "failed to set key's value")?;
+
+        // a plain set clears any previous ttl
+        remove_file_if_exists(&self.ttl_dir().join(file_name))?;
         Ok(())
     }
 
-    async fn keys(&self) -> Result<Vec<String>> {
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        self.set(key, value).await?;
+
+        fs::create_dir_all(self.ttl_dir())
+            .with_context(|| "failed to create ttl directory for keyvalue instance")?;
+        let expires_at = SystemTime::now().duration_since(UNIX_EPOCH)? + ttl;
+        fs::write(
+            self.ttl_dir().join(Self::key_file_name(key)?),
+            expires_at.as_secs().to_string(),
+        )
+        .with_context(|| "failed to set key's expiry")?;
+        Ok(())
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        fs::create_dir_all(&self.base)
+            .with_context(|| "failed to create base directory for keyvalue instance")?;
+
+        // the lock is released when `lock_file` is dropped
+        let lock_file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(PathBuf::from(&self.base).join(LOCK_FILE))
+            .with_context(|| "failed to open lock file for keyvalue instance")?;
+        lock_file
+            .lock_exclusive()
+            .with_context(|| "failed to lock keyvalue instance")?;
+
+        // only a missing key counts as absent, so that a value that can't be read
+        // isn't overwritten
+        let current = match self.get(key).await {
+            Ok(current) => Some(current),
+            Err(e) if is_key_not_found(&e) => None,
+            Err(e) => return Err(e),
+        };
+        if current.as_deref() != old {
+            return Ok(false);
+        }
+        self.set(key, new).await?;
+        Ok(true)
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
         fs::create_dir_all(&self.base)
             .with_context(|| "failed to create base directory for keyvalue instance")?;
 
         let mut keys = Vec::new();
         for entry in fs::read_dir(&self.base).with_context(|| "failed to read base directory")? {
             let entry = entry.with_context(|| "failed to read base directory entry")?;
-            keys.push(entry.file_name().to_str().unwrap().to_owned());
+            let file_name = entry.file_name().to_str().unwrap().to_owned();
+            if file_name.starts_with('.') {
+                continue;
+            }
+            let key = decode_key(&file_name)?;
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            // the cursor is the last key of the previous page
+            if let Some(cursor) = cursor {
+                if key.as_str() <= cursor {
+                    continue;
+                }
+            }
+            if self.evict_if_expired(&file_name)? {
+                continue;
+            }
+            keys.push(key);
+        }
+
+        // directory entries come in no particular order, so sort them to page consistently
+        keys.sort();
+        match limit {
+            Some(limit) if keys.len() > limit as usize => {
+                keys.truncate(limit as usize);
+                let cursor = keys.last().cloned();
+                Ok((keys, cursor))
+            }
+            _ => Ok((keys, None)),
         }
-        Ok(keys)
     }
 
     async fn delete(&self, key: &str) -> Result<()> {
         fs::create_dir_all(&self.base)
             .with_context(|| "failed to create base directory for keyvalue instance")?;
-        fs::remove_file(PathBuf::from(&self.base).join(key))
+        let file_name = Self::key_file_name(key)?;
+        fs::remove_file(PathBuf::from(&self.base).join(&file_name))
             .with_context(|| "failed to delete key's value")?;
+        remove_file_if_exists(&self.ttl_dir().join(file_name))?;
+        Ok(())
+    }
+
+    /// Watching relies on `notify`, so a key may be reported as set more than
+    /// once for a single write (e.g., once when its' file is created, and once
+    /// when it is written to).
+    async fn watch(&self, prefix: &str) -> Result<UnboundedReceiver<KeyChange>> {
+        fs::create_dir_all(&self.base)
+            .with_context(|| "failed to create base directory for keyvalue instance")?;
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let base = PathBuf::from(&self.base);
+        let event_tx = tx.clone();
+        let (event_base, event_prefix) = (base.clone(), prefix.to_string());
+        let mut watcher = RecommendedWatcher::new(
+            move |res: notify::Result<Event>| match res {
+                Ok(event) => {
+                    for change in key_changes(event, &event_base, &event_prefix) {
+                        let _ = event_tx.send(change);
+                    }
+                }
+                Err(e) => log::error!("failed to watch keyvalue instance: {}", e),
+            },
+            Config::default(),
+        )
+        .with_context(|| "failed to create watcher for keyvalue instance")?;
+        watcher
+            .watch(&base, RecursiveMode::NonRecursive)
+            .with_context(|| "failed to watch base directory for keyvalue instance")?;
+
+        // keep the watcher alive for as long as someone is listening to it
+        tokio::spawn(async move {
+            tx.closed().await;
+            drop(watcher);
+        });
+        Ok(rx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use anyhow::Result;
+
+    use super::{encode_key, store_dir, FilesystemImplementor};
+    use crate::implementors::KeyvalueImplementor;
+
+    #[tokio::test]
+    async fn compare_and_swap_unreadable_test() -> Result<()> {
+        let base = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let implementor = FilesystemImplementor {
+            base: base.to_str().unwrap().to_owned(),
+        };
+        assert!(implementor.compare_and_swap("key", None, b"new").await?);
+        assert!(!implementor.compare_and_swap("key", None, b"newer").await?);
+
+        // a key that exists, but can't be read, isn't taken to be absent
+        fs::create_dir_all(base.join(encode_key("dir")))?;
+        assert!(implementor
+            .compare_and_swap("dir", None, b"new")
+            .await
+            .is_err());
+        assert!(base.join(encode_key("dir")).is_dir());
+
+        fs::remove_dir_all(base)?;
+        Ok(())
+    }
+
+    #[test]
+    fn store_dir_test() -> Result<()> {
+        let root = env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        assert_eq!(store_dir(&root, "my store"), root.join("my%20store"));
+        assert_eq!(store_dir(&root, "../escape"), root.join("%2E.%2Fescape"));
+
+        // a store that predates names being encoded keeps its' directory, until
+        // it is migrated
+        fs::create_dir_all(root.join("my store"))?;
+        fs::create_dir_all(root.join("a"))?;
+        assert_eq!(store_dir(&root, "my store"), root.join("my store"));
+        assert_eq!(store_dir(&root, "../a"), root.join("%2E.%2Fa"));
+        fs::rename(root.join("my store"), root.join("my%20store"))?;
+        assert_eq!(store_dir(&root, "my store"), root.join("my%20store"));
+
+        fs::remove_dir_all(root)?;
         Ok(())
     }
 }
@@ -0,0 +1,171 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+
+use super::{KeyNotFound, KeyvalueImplementor};
+
+/// A stored value, and when it expires (if it was set with a ttl).
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= Instant::now())
+    }
+}
+
+type Store = Arc<Mutex<BTreeMap<String, Entry>>>;
+
+/// Every store in this process, by name. Instances opened with the same name
+/// share the same store, so writes made through one are seen by all of them.
+static STORES: Lazy<Mutex<HashMap<String, Store>>> = Lazy::new(Default::default);
+
+/// This is the underlying struct behind the `Memory` variant of the `KeyvalueImplementor` enum.
+///
+/// It provides one property that pertains solely to the in-memory implementation
+/// of this capability:
+///     - `store`
+///
+/// Nothing is persisted: the store lives as long as the `slight` process does.
+///
+/// As per its' usage in `KeyvalueImplementor`, it must `derive` `Debug`, and `Clone`.
+#[derive(Debug, Clone)]
+pub struct MemoryImplementor {
+    store: Store,
+}
+
+impl MemoryImplementor {
+    pub fn new(name: &str) -> Self {
+        let store = STORES
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        Self { store }
+    }
+
+    fn insert(&self, key: &str, value: &[u8], expires_at: Option<Instant>) {
+        self.store.lock().unwrap().insert(
+            key.to_string(),
+            Entry {
+                value: value.to_vec(),
+                expires_at,
+            },
+        );
+    }
+}
+
+/// Gets `key`'s value, evicting it if it has expired.
+fn get_live(store: &mut BTreeMap<String, Entry>, key: &str) -> Option<Vec<u8>> {
+    match store.get(key) {
+        Some(entry) if entry.is_expired() => {
+            store.remove(key);
+            None
+        }
+        Some(entry) => Some(entry.value.clone()),
+        None => None,
+    }
+}
+
+#[async_trait]
+impl KeyvalueImplementor for MemoryImplementor {
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match get_live(&mut self.store.lock().unwrap(), key) {
+            Some(value) => Ok(value),
+            None => Err(KeyNotFound(key.to_string()).into()),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        self.insert(key, value, None);
+        Ok(())
+    }
+
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(Instant::now() + ttl));
+        Ok(())
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let mut store = self.store.lock().unwrap();
+        if get_live(&mut store, key).as_deref() != old {
+            return Ok(false);
+        }
+        store.insert(
+            key.to_string(),
+            Entry {
+                value: new.to_vec(),
+                expires_at: None,
+            },
+        );
+        Ok(true)
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let mut store = self.store.lock().unwrap();
+        store.retain(|_, entry| !entry.is_expired());
+
+        // the cursor is the last key of the previous page
+        let start = match cursor {
+            Some(cursor) => Bound::Excluded(cursor.to_string()),
+            None => Bound::Included(prefix.to_string()),
+        };
+        let mut keys = store
+            .range((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .skip_while(|key| key.as_str() < prefix)
+            .take_while(|key| key.starts_with(prefix));
+        let page: Vec<String> = match limit {
+            Some(limit) => keys.by_ref().take(limit as usize).cloned().collect(),
+            None => keys.by_ref().cloned().collect(),
+        };
+        let next_cursor = match keys.next() {
+            Some(_) => page.last().cloned(),
+            None => None,
+        };
+        Ok((page, next_cursor))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.store.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        let mut store = self.store.lock().unwrap();
+        for (key, value) in key_values {
+            store.insert(
+                key.to_string(),
+                Entry {
+                    value: value.to_vec(),
+                    expires_at: None,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        let mut store = self.store.lock().unwrap();
+        for key in keys {
+            store.remove(*key);
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,142 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use tokio::sync::mpsc::UnboundedReceiver;
 
 #[cfg(feature = "awsdynamodb")]
 pub mod awsdynamodb;
 #[cfg(feature = "azblob")]
 pub mod azblob;
+#[cfg(feature = "cache")]
+pub mod cached;
+#[cfg(feature = "dapr")]
+pub mod dapr;
+#[cfg(feature = "encryption")]
+pub mod encrypted;
 #[cfg(feature = "filesystem")]
 pub mod filesystem;
+#[cfg(feature = "memory")]
+pub mod memory;
 #[cfg(feature = "redis")]
 pub mod redis;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// How many times the default `increment` tries to swap in its' new value
+/// before giving up on concurrent writers.
+const MAX_INCREMENT_ATTEMPTS: u32 = 10;
+/// How long the default `increment` waits before its' first retry. The wait
+/// doubles w/ each retry after that.
+const INCREMENT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// The error implementors return when a key doesn't exist (or has expired), so
+/// that it can be told apart from a failure to get it (e.g., a network error).
+#[derive(Debug)]
+pub struct KeyNotFound(pub String);
+
+impl std::fmt::Display for KeyNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no value found for key: {}", self.0)
+    }
+}
+
+impl std::error::Error for KeyNotFound {}
+
+/// Whether `e` is (or was caused by) a `KeyNotFound`.
+pub fn is_key_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<KeyNotFound>().is_some()
+}
+
+/// A change to a key, as seen by `KeyvalueImplementor::watch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyChange {
+    Set(String),
+    Delete(String),
+}
 
 #[async_trait]
 pub trait KeyvalueImplementor {
     async fn get(&self, key: &str) -> Result<Vec<u8>>;
     async fn set(&self, key: &str, value: &[u8]) -> Result<()>;
-    async fn keys(&self) -> Result<Vec<String>>;
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()>;
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool>;
+
+    /// Implementors without a native atomic increment get one built on top of
+    /// `compare_and_swap`, retrying (w/ an exponential backoff) until no
+    /// concurrent writer gets in between, or `MAX_INCREMENT_ATTEMPTS` are used up.
+    async fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let mut backoff = INCREMENT_BACKOFF;
+        for attempt in 1..=MAX_INCREMENT_ATTEMPTS {
+            let current = match self.get(key).await {
+                Ok(current) => Some(current),
+                Err(e) if is_key_not_found(&e) => None,
+                Err(e) => return Err(e),
+            };
+            let value = match &current {
+                Some(v) => std::str::from_utf8(v)?
+                    .parse::<i64>()
+                    .with_context(|| format!("value for key '{key}' is not an integer"))?,
+                None => 0,
+            };
+            let new = value
+                .checked_add(delta)
+                .with_context(|| format!("incrementing key '{key}' would overflow"))?;
+            if self
+                .compare_and_swap(key, current.as_deref(), new.to_string().as_bytes())
+                .await?
+            {
+                return Ok(new);
+            }
+            if attempt < MAX_INCREMENT_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        bail!("failed to increment key '{key}': it kept being changed concurrently")
+    }
+    /// Lists one page of the keys starting with `prefix`, resuming from `cursor`.
+    /// Returns the page, and the cursor to get the next one with (if there is one).
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)>;
     async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Keys that can't be read are left out of the result.
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.get(key).await {
+                Ok(value) => values.push((key.to_string(), value)),
+                Err(e) if is_key_not_found(&e) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(values)
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        for (key, value) in key_values {
+            self.set(key, value).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        for key in keys {
+            self.delete(key).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts watching the keys that start with `prefix`. Changes are sent down
+    /// the returned channel until the receiver is dropped.
+    async fn watch(&self, _prefix: &str) -> Result<UnboundedReceiver<KeyChange>> {
+        bail!("watching keys is not supported by this keyvalue implementor")
+    }
 }
 
 impl std::fmt::Debug for dyn KeyvalueImplementor + Send + Sync {
@@ -1,10 +1,28 @@
-use anyhow::{bail, Result};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use once_cell::sync::Lazy;
 use redis::{Client, Commands};
 use slight_common::BasicState;
-use slight_runtime_configs::get_from_state;
+use slight_runtime_configs::{get_from_state, maybe_get_from_state};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot, Mutex,
+};
+use tracing::log;
+
+use super::{KeyChange, KeyNotFound, KeyvalueImplementor};
 
-use super::KeyvalueImplementor;
+/// The keyspace notification classes `watch` relies on: keyspace events (`K`) for
+/// generic commands (`g`), string commands (`$`), and expired keys (`x`).
+const KEYSPACE_EVENTS: &str = "Kg$x";
+/// How often a watch checks whether it is still wanted while no changes come in.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 /// This is the underlying struct behind the `AzBlob` variant of the `KeyvalueImplementor` enum.
 ///
@@ -17,6 +35,7 @@ use super::KeyvalueImplementor;
 pub struct RedisImplementor {
     client: Client,
     container_name: String,
+    configure_keyspace_events: bool,
 }
 
 impl RedisImplementor {
@@ -24,9 +43,16 @@ impl RedisImplementor {
         let connection_string = get_from_state("REDIS_ADDRESS", slight_state).await.unwrap();
         let client = redis::Client::open(connection_string).unwrap();
         let container_name = name.to_string();
+        // changing the server's settings is up to the operator, unless they opt in
+        let configure_keyspace_events =
+            maybe_get_from_state("REDIS_CONFIGURE_KEYSPACE_EVENTS", slight_state)
+                .await
+                .unwrap()
+                .map_or(false, |v| v == "true");
         Self {
             client,
             container_name,
+            configure_keyspace_events,
         }
     }
 }
@@ -38,7 +64,7 @@ impl KeyvalueImplementor for RedisImplementor {
         let val: Vec<u8> = con.get(format!("{}:{}", self.container_name, key))?;
         // Redis GET returns [:ok; nil] for non-existent keys
         if val.is_empty() {
-            bail!("key not found");
+            return Err(KeyNotFound(key.to_string()).into());
         }
         Ok(val)
     }
@@ -50,15 +76,124 @@ impl KeyvalueImplementor for RedisImplementor {
         Ok(())
     }
 
-    async fn keys(&self) -> Result<Vec<String>> {
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let mut con = self.client.get_connection()?;
+        con.set_ex::<_, _, ()>(
+            format!("{}:{}", self.container_name, key),
+            value,
+            ttl.as_secs() as usize,
+        )?;
+
+        Ok(())
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let mut con = self.client.get_connection()?;
+        let key = format!("{}:{}", self.container_name, key);
+        // WATCH the key so EXEC aborts (and the closure is retried) if someone
+        // else writes to it between our GET and SET
+        let swapped = redis::transaction(&mut con, &[&key], |con, pipe| {
+            let current: Option<Vec<u8>> = con.get(&key)?;
+            if current.as_deref() != old {
+                return Ok(Some(false));
+            }
+            pipe.set(&key, new)
+                .ignore()
+                .query::<Option<()>>(con)
+                .map(|res| res.map(|_| true))
+        })?;
+
+        Ok(swapped)
+    }
+
+    async fn increment(&self, key: &str, delta: i64) -> Result<i64> {
+        let mut con = self.client.get_connection()?;
+        let value: i64 = con.incr(format!("{}:{}", self.container_name, key), delta)?;
+
+        Ok(value)
+    }
+
+    async fn get_many(&self, keys: &[&str]) -> Result<Vec<(String, Vec<u8>)>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut con = self.client.get_connection()?;
+        let prefixed_keys: Vec<String> = keys
+            .iter()
+            .map(|k| format!("{}:{}", self.container_name, k))
+            .collect();
+        let values: Vec<Option<Vec<u8>>> = redis::cmd("MGET").arg(prefixed_keys).query(&mut con)?;
+        // same as `get`, an empty value means the key doesn't exist
+        let values = keys
+            .iter()
+            .zip(values)
+            .filter_map(|(k, v)| v.filter(|v| !v.is_empty()).map(|v| (k.to_string(), v)))
+            .collect();
+        Ok(values)
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        if key_values.is_empty() {
+            return Ok(());
+        }
+        let mut con = self.client.get_connection()?;
+        let prefixed_key_values: Vec<(String, &[u8])> = key_values
+            .iter()
+            .map(|(k, v)| (format!("{}:{}", self.container_name, k), *v))
+            .collect();
+        con.set_multiple::<_, _, ()>(&prefixed_key_values)?;
+
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
         let mut con = self.client.get_connection()?;
-        let keys: Vec<String> = con.keys(format!("{}:*", self.container_name))?;
-        // remove prefix
-        let keys: Vec<String> = keys
+        let prefixed_keys: Vec<String> = keys
             .iter()
-            .map(|k| k.replace(format!("{}:", self.container_name).as_str(), ""))
+            .map(|k| format!("{}:{}", self.container_name, k))
             .collect();
-        Ok(keys)
+        con.del::<_, ()>(prefixed_keys)?;
+
+        Ok(())
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let (scan_cursor, leftover) = parse_cursor(cursor)?;
+        // the keys left over from the last SCAN are listed before scanning on
+        if !leftover.is_empty() {
+            return page(leftover, scan_cursor, limit);
+        }
+
+        let mut con = self.client.get_connection()?;
+        let container_prefix = format!("{}:", self.container_name);
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(&scan_cursor).arg("MATCH").arg(format!(
+            "{}{}*",
+            escape_glob(&container_prefix),
+            escape_glob(prefix)
+        ));
+        if let Some(limit) = limit {
+            // COUNT is only a hint, so SCAN may hand back more keys than `limit`
+            cmd.arg("COUNT").arg(limit);
+        }
+        let (next_scan_cursor, keys): (String, Vec<String>) = cmd.query(&mut con)?;
+        // remove prefix, and any key SCAN handed back more than once in this page
+        // (it may still hand a key back again in a later one)
+        let mut keys: Vec<String> = keys
+            .iter()
+            .map(|k| k.replacen(container_prefix.as_str(), "", 1))
+            .collect();
+        keys.sort();
+        keys.dedup();
+        page(keys, next_scan_cursor, limit)
     }
 
     async fn delete(&self, key: &str) -> Result<()> {
@@ -67,4 +202,282 @@ impl KeyvalueImplementor for RedisImplementor {
 
         Ok(())
     }
+
+    /// Watching relies on Redis keyspace notifications, which have to be enabled
+    /// on the server (i.e., `notify-keyspace-events` has to include `Kg$x`). We
+    /// only change the server's settings ourselves if `REDIS_CONFIGURE_KEYSPACE_EVENTS`
+    /// is set to `true`.
+    ///
+    /// All of the watches on a database share a single pub/sub connection.
+    async fn watch(&self, prefix: &str) -> Result<UnboundedReceiver<KeyChange>> {
+        let info = self.client.get_connection_info();
+        let watches = WATCHES
+            .lock()
+            .unwrap()
+            .entry(format!("{}/{}", info.addr, info.redis.db))
+            .or_default()
+            .clone();
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut listening = watches.listening.lock().await;
+        if !*listening {
+            let mut con = self.client.get_connection()?;
+            if self.configure_keyspace_events {
+                enable_keyspace_events(&mut con);
+            } else {
+                check_keyspace_events(&mut con);
+            }
+            listen(con, info.redis.db, watches.clone()).await?;
+            *listening = true;
+        }
+        watches.senders.lock().unwrap().push(Watch {
+            container_prefix: format!("{}:", self.container_name),
+            prefix: prefix.to_string(),
+            tx,
+        });
+        Ok(rx)
+    }
+}
+
+/// One call to `watch`: the keys it is after, and where their changes go.
+struct Watch {
+    container_prefix: String,
+    prefix: String,
+    tx: UnboundedSender<KeyChange>,
+}
+
+/// The watches on one Redis database.
+#[derive(Default)]
+struct Watches {
+    senders: StdMutex<Vec<Watch>>,
+    /// Whether a pub/sub connection is currently serving `senders`.
+    listening: Mutex<bool>,
+}
+
+/// The watches on every Redis database we have been asked to watch (by address
+/// and db), so that all of the watches on one of them share a pub/sub connection.
+static WATCHES: Lazy<StdMutex<HashMap<String, Arc<Watches>>>> = Lazy::new(Default::default);
+
+/// Subscribes `con` to every keyspace notification on `db`, and hands each
+/// change out to the `watches` that are after it in the background. Once none
+/// of the watches are wanted anymore, the connection is dropped.
+async fn listen(con: redis::Connection, db: i64, watches: Arc<Watches>) -> Result<()> {
+    let channel_prefix = format!("__keyspace@{db}__:");
+    let pattern = format!("{}*", channel_prefix);
+    let (subscribed_tx, subscribed_rx) = oneshot::channel();
+
+    tokio::task::spawn_blocking(move || {
+        let mut con = con;
+        let mut pubsub = con.as_pubsub();
+        let subscribed = pubsub
+            .psubscribe(&pattern)
+            .and_then(|_| pubsub.set_read_timeout(Some(WATCH_POLL_INTERVAL)));
+        let failed = subscribed.is_err();
+        let _ = subscribed_tx.send(subscribed);
+        if failed {
+            return;
+        }
+
+        loop {
+            match pubsub.get_message() {
+                Ok(msg) => {
+                    if let Some(key) = msg.get_channel_name().strip_prefix(channel_prefix.as_str())
+                    {
+                        if let Ok(event) = msg.get_payload::<String>() {
+                            dispatch(&watches, key, &event);
+                        }
+                    }
+                }
+                Err(e) if e.is_timeout() => {}
+                Err(e) => {
+                    log::error!("stopped watching '{}': {}", pattern, e);
+                    let mut listening = watches.listening.blocking_lock();
+                    // dropping the senders lets the watches know they are over
+                    watches.senders.lock().unwrap().clear();
+                    *listening = false;
+                    return;
+                }
+            }
+
+            // `watch` only adds to `senders` while holding `listening`, so once
+            // we see no one is left under it, no one can sneak in before we stop
+            let mut listening = watches.listening.blocking_lock();
+            let mut senders = watches.senders.lock().unwrap();
+            senders.retain(|w| !w.tx.is_closed());
+            if senders.is_empty() {
+                *listening = false;
+                return;
+            }
+        }
+    });
+
+    // make sure we are subscribed before handing back any receivers
+    subscribed_rx.await??;
+    Ok(())
+}
+
+/// Hands the `event` that happened to the (container-prefixed) `key` out to the
+/// watches that are after it.
+fn dispatch(watches: &Watches, key: &str, event: &str) {
+    for watch in watches.senders.lock().unwrap().iter() {
+        let key = match key.strip_prefix(watch.container_prefix.as_str()) {
+            Some(key) if key.starts_with(watch.prefix.as_str()) => key.to_string(),
+            _ => continue,
+        };
+        let change = match event {
+            // setting a ttl doesn't change the value
+            "expire" => continue,
+            "del" | "expired" | "evicted" | "rename_from" => KeyChange::Delete(key),
+            _ => KeyChange::Set(key),
+        };
+        // a watch that is gone gets cleaned up by the listener
+        let _ = watch.tx.send(change);
+    }
+}
+
+/// Warns if the server doesn't look like it publishes the keyspace events `watch`
+/// needs. Some managed Redis services don't allow `CONFIG GET` either, in which
+/// case we can't tell.
+fn check_keyspace_events(con: &mut redis::Connection) {
+    let current: Vec<String> = match redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query(con)
+    {
+        Ok(current) => current,
+        Err(_) => return,
+    };
+    let events = current.get(1).cloned().unwrap_or_default();
+    if !missing_keyspace_events(&events).is_empty() {
+        log::warn!(
+            "keyspace notifications are not enabled on the redis server (i.e., notify-keyspace-events is '{}', but needs '{}'), watches may not see any changes",
+            events,
+            KEYSPACE_EVENTS
+        );
+    }
+}
+
+/// The keyspace notification classes `watch` needs that `events` doesn't have.
+fn missing_keyspace_events(events: &str) -> String {
+    // `A` is an alias for every event class (but the `K` or `E` to publish them)
+    if events.contains('A') && events.contains('K') {
+        return String::new();
+    }
+    KEYSPACE_EVENTS
+        .chars()
+        .filter(|c| !events.contains(*c))
+        .collect()
+}
+
+/// Makes sure the server publishes the keyspace events `watch` needs, on top of
+/// its' current settings. This is best-effort: some managed Redis services don't
+/// allow `CONFIG SET`, in which case notifications have to be enabled on the
+/// server itself.
+fn enable_keyspace_events(con: &mut redis::Connection) {
+    let current: Vec<String> = redis::cmd("CONFIG")
+        .arg("GET")
+        .arg("notify-keyspace-events")
+        .query(con)
+        .unwrap_or_default();
+    let mut events = current.get(1).cloned().unwrap_or_default();
+    let missing = missing_keyspace_events(&events);
+    if missing.is_empty() {
+        return;
+    }
+    events.push_str(&missing);
+    if let Err(e) = redis::cmd("CONFIG")
+        .arg("SET")
+        .arg("notify-keyspace-events")
+        .arg(&events)
+        .query::<()>(con)
+    {
+        log::warn!(
+            "failed to enable keyspace notifications (i.e., notify-keyspace-events '{}'), watches may not see any changes: {}",
+            events,
+            e
+        );
+    }
+}
+
+/// Splits a cursor handed out by `keys` into the SCAN cursor to continue from, and
+/// the keys a SCAN handed back that didn't fit in their page. A cursor w/o any of
+/// the latter is just the SCAN cursor, and one w/ them is JSON (i.e., `[scan
+/// cursor, [keys]]`).
+fn parse_cursor(cursor: Option<&str>) -> Result<(String, Vec<String>)> {
+    match cursor {
+        None => Ok(("0".to_string(), Vec::new())),
+        Some(cursor) if cursor.starts_with('[') => {
+            serde_json::from_str(cursor).with_context(|| format!("invalid cursor '{cursor}'"))
+        }
+        Some(cursor) => Ok((cursor.to_string(), Vec::new())),
+    }
+}
+
+/// Turns `keys` into a page of at most `limit` of them, and the cursor for the
+/// next one, which carries the rest of `keys` over (see `parse_cursor`).
+fn page(
+    mut keys: Vec<String>,
+    scan_cursor: String,
+    limit: Option<u32>,
+) -> Result<(Vec<String>, Option<String>)> {
+    let leftover = match limit {
+        Some(limit) if keys.len() > limit as usize => keys.split_off(limit as usize),
+        _ => Vec::new(),
+    };
+    let next_cursor = if !leftover.is_empty() {
+        Some(serde_json::to_string(&(scan_cursor, leftover))?)
+    } else if scan_cursor == "0" {
+        // SCAN is done once it hands back cursor 0
+        None
+    } else {
+        Some(scan_cursor)
+    };
+    Ok((keys, next_cursor))
+}
+
+/// Escapes the characters that have a special meaning in Redis glob-style patterns.
+fn escape_glob(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{page, parse_cursor};
+
+    #[test]
+    fn page_test() -> Result<()> {
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+
+        // SCAN handed back more keys than fit in the page, so the rest are carried
+        // over, and listed before scanning on from its' cursor
+        let (listed, cursor) = page(keys(&["a", "b", "c"]), "17".to_string(), Some(2))?;
+        assert_eq!(listed, keys(&["a", "b"]));
+        let (scan_cursor, leftover) = parse_cursor(cursor.as_deref())?;
+        assert_eq!(scan_cursor, "17");
+        assert_eq!(leftover, keys(&["c"]));
+        let (listed, cursor) = page(leftover, scan_cursor, Some(2))?;
+        assert_eq!(listed, keys(&["c"]));
+        assert_eq!(cursor.as_deref(), Some("17"));
+        assert_eq!(parse_cursor(cursor.as_deref())?, ("17".to_string(), vec![]));
+
+        // the listing is only done once SCAN is, and nothing is left over
+        let (_, cursor) = page(keys(&["a", "b"]), "0".to_string(), Some(1))?;
+        assert!(cursor.is_some());
+        let (listed, cursor) = page(keys(&["a", "b"]), "0".to_string(), None)?;
+        assert_eq!(listed.len(), 2);
+        assert!(cursor.is_none());
+
+        assert_eq!(parse_cursor(None)?, ("0".to_string(), vec![]));
+        assert!(parse_cursor(Some("[not json")).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,235 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use slight_common::BasicState;
+use slight_runtime_configs::get_from_state;
+use tracing::log;
+
+use super::{KeyNotFound, KeyvalueImplementor};
+
+/// This is the underlying struct behind the `Sqlite` variant of the `KeyvalueImplementor` enum.
+///
+/// It provides properties that pertain solely to the SQLite implementation
+/// of this capability:
+///     - `connection`, and
+///     - `store` (i.e., the name every key of this instance is namespaced under).
+///
+/// As per its' usage in `KeyvalueImplementor`, it must `derive` `Debug`, and `Clone`.
+#[derive(Debug, Clone)]
+pub struct SqliteImplementor {
+    connection: Arc<Mutex<Connection>>,
+    store: String,
+}
+
+impl SqliteImplementor {
+    /// Creates a new `SqliteImplementor` instance.
+    ///
+    /// The database file is taken from the `SQLITE_PATH` config, and is created
+    /// if it doesn't exist yet. A relative `SQLITE_PATH` is resolved against the
+    /// directory of the slightfile. Every store shares the same table, keyed by
+    /// the capability's name.
+    pub async fn new(slight_state: &BasicState, name: &str) -> Self {
+        let path = PathBuf::from(get_from_state("SQLITE_PATH", slight_state).await.unwrap());
+        let path = if path.is_relative() {
+            slight_state
+                .slightfile_path
+                .parent()
+                .map(|p| p.join(&path))
+                .unwrap_or(path)
+        } else {
+            path
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+
+        let connection = Connection::open(&path)
+            .with_context(|| format!("failed to open sqlite database '{}'", path.display()))
+            .unwrap();
+        // other processes may be using the same database file
+        connection.busy_timeout(BUSY_TIMEOUT).unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS keyvalue (
+                    store TEXT NOT NULL,
+                    key TEXT NOT NULL,
+                    value BLOB NOT NULL,
+                    expires_at INTEGER,
+                    PRIMARY KEY (store, key)
+                );",
+            )
+            .unwrap();
+        connection
+            .execute(
+                "DELETE FROM keyvalue WHERE store = ?1 AND expires_at <= ?2",
+                params![name, now()],
+            )
+            .unwrap();
+
+        log::info!(
+            "Opened sqlite keyvalue store '{}' at: {}",
+            name,
+            path.display()
+        );
+        Self {
+            connection: Arc::new(Mutex::new(connection)),
+            store: name.to_string(),
+        }
+    }
+
+    fn upsert(
+        connection: &Connection,
+        store: &str,
+        key: &str,
+        value: &[u8],
+        expires_at: Option<i64>,
+    ) -> Result<()> {
+        connection
+            .execute(
+                "INSERT INTO keyvalue (store, key, value, expires_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (store, key) DO UPDATE SET
+                    value = excluded.value,
+                    expires_at = excluded.expires_at",
+                params![store, key, value, expires_at],
+            )
+            .with_context(|| format!("failed to set key '{key}'"))?;
+        Ok(())
+    }
+
+    fn get_live(connection: &Connection, store: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        connection
+            .query_row(
+                "SELECT value FROM keyvalue
+                 WHERE store = ?1 AND key = ?2 AND (expires_at IS NULL OR expires_at > ?3)",
+                params![store, key, now()],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(|| format!("failed to get key '{key}'"))
+    }
+}
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seconds since the unix epoch, which is what `expires_at` is stored as.
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+#[async_trait]
+impl KeyvalueImplementor for SqliteImplementor {
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let connection = self.connection.lock().unwrap();
+        match Self::get_live(&connection, &self.store, key)? {
+            Some(value) => Ok(value),
+            None => Err(KeyNotFound(key.to_string()).into()),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &[u8]) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        Self::upsert(&connection, &self.store, key, value, None)
+    }
+
+    async fn set_with_ttl(&self, key: &str, value: &[u8], ttl: Duration) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        let expires_at = now() + ttl.as_secs() as i64;
+        Self::upsert(&connection, &self.store, key, value, Some(expires_at))
+    }
+
+    async fn compare_and_swap(&self, key: &str, old: Option<&[u8]>, new: &[u8]) -> Result<bool> {
+        let mut connection = self.connection.lock().unwrap();
+        // an immediate transaction takes the write lock up front, so no other
+        // connection can write between our read and our write
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let current = Self::get_live(&tx, &self.store, key)?;
+        if current.as_deref() != old {
+            return Ok(false);
+        }
+        Self::upsert(&tx, &self.store, key, new, None)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    async fn keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<String>, Option<String>)> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT key FROM keyvalue
+             WHERE store = ?1
+                AND substr(key, 1, length(?2)) = ?2
+                AND (?3 IS NULL OR key > ?3)
+                AND (expires_at IS NULL OR expires_at > ?4)
+             ORDER BY key
+             LIMIT ?5",
+        )?;
+        // fetch one extra key to know whether there is another page
+        let sql_limit = limit.map_or(-1, |l| l as i64 + 1);
+        let mut keys = statement
+            .query_map(
+                params![self.store, prefix, cursor, now(), sql_limit],
+                |row| row.get(0),
+            )?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .with_context(|| "failed to list keys")?;
+
+        match limit {
+            Some(limit) if keys.len() > limit as usize => {
+                keys.truncate(limit as usize);
+                // the cursor is the last key of the previous page
+                let cursor = keys.last().cloned();
+                Ok((keys, cursor))
+            }
+            _ => Ok((keys, None)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .execute(
+                "DELETE FROM keyvalue WHERE store = ?1 AND key = ?2",
+                params![self.store, key],
+            )
+            .with_context(|| format!("failed to delete key '{key}'"))?;
+        Ok(())
+    }
+
+    async fn set_many(&self, key_values: &[(&str, &[u8])]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        for (key, value) in key_values {
+            Self::upsert(&tx, &self.store, key, value, None)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn delete_many(&self, keys: &[&str]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        for key in keys {
+            tx.execute(
+                "DELETE FROM keyvalue WHERE store = ?1 AND key = ?2",
+                params![self.store, key],
+            )
+            .with_context(|| format!("failed to delete key '{key}'"))?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
@@ -1,7 +1,12 @@
 mod implementors;
 pub mod providers;
 
-use std::{fmt::Debug, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::Result;
 use async_trait::async_trait;
@@ -15,6 +20,8 @@ use slight_common::{impl_resource, BasicState};
 use slight_file::capability_store::CapabilityStore;
 use slight_file::resource::KeyvalueResource::*;
 use slight_file::Resource;
+use tokio::sync::mpsc::{error::TryRecvError, UnboundedReceiver};
+use uuid::Uuid;
 wit_bindgen_wasmtime::export!({paths: ["../../wit/keyvalue.wit"], async: *});
 wit_error_rs::impl_error!(keyvalue::KeyvalueError);
 wit_error_rs::impl_from!(anyhow::Error, keyvalue::KeyvalueError::UnexpectedError);
@@ -49,8 +56,7 @@ impl Keyvalue {
 ///
 /// It holds:
 ///     - a `keyvalue_implementor` (i.e., a variant `KeyvalueImplementor` `enum`), and
-///     - a `resource_descriptor` (i.e., an UUID that uniquely identifies
-///     resource's instance).
+///     - `watches` (i.e., the changes seen by each of its' watches, by watch token).
 ///
 /// It must `derive`:
 ///     - `Debug` due to a constraint on the associated type.
@@ -62,6 +68,7 @@ impl Keyvalue {
 #[derive(Clone, Debug)]
 pub struct KeyvalueInner {
     keyvalue_implementor: Arc<dyn KeyvalueImplementor + Send + Sync>,
+    watches: Arc<Mutex<HashMap<String, UnboundedReceiver<KeyChange>>>>,
 }
 
 impl KeyvalueInner {
@@ -69,12 +76,12 @@ impl KeyvalueInner {
         keyvalue_implementor: KeyvalueImplementors,
         slight_state: &BasicState,
         name: &str,
-    ) -> Self {
-        Self {
-            keyvalue_implementor: match keyvalue_implementor {
+    ) -> Result<Self> {
+        let keyvalue_implementor: Arc<dyn KeyvalueImplementor + Send + Sync> =
+            match keyvalue_implementor {
                 #[cfg(feature = "filesystem")]
                 KeyvalueImplementors::Filesystem => {
-                    Arc::new(filesystem::FilesystemImplementor::new(slight_state, name).await)
+                    Arc::new(filesystem::FilesystemImplementor::new(slight_state, name).await?)
                 }
                 #[cfg(feature = "azblob")]
                 KeyvalueImplementors::AzBlob => {
@@ -88,8 +95,29 @@ impl KeyvalueInner {
                 KeyvalueImplementors::Redis => {
                     Arc::new(redis::RedisImplementor::new(slight_state, name).await)
                 }
-            },
-        }
+                #[cfg(feature = "dapr")]
+                KeyvalueImplementors::Dapr => {
+                    Arc::new(dapr::DaprImplementor::new(slight_state, name).await)
+                }
+                #[cfg(feature = "sqlite")]
+                KeyvalueImplementors::Sqlite => {
+                    Arc::new(sqlite::SqliteImplementor::new(slight_state, name).await)
+                }
+                #[cfg(feature = "memory")]
+                KeyvalueImplementors::Memory => Arc::new(memory::MemoryImplementor::new(name)),
+            };
+        #[cfg(feature = "encryption")]
+        let keyvalue_implementor =
+            encrypted::EncryptedImplementor::maybe_wrap(keyvalue_implementor, slight_state).await;
+        // the cache goes on the outside, so cache hits skip decryption too
+        #[cfg(feature = "cache")]
+        let keyvalue_implementor =
+            cached::CachedImplementor::maybe_wrap(keyvalue_implementor, slight_state, name).await?;
+
+        Ok(Self {
+            keyvalue_implementor,
+            watches: Arc::new(Mutex::new(HashMap::new())),
+        })
     }
 }
 
@@ -107,6 +135,12 @@ pub enum KeyvalueImplementors {
     AwsDynamoDb,
     #[cfg(feature = "redis")]
     Redis,
+    #[cfg(feature = "dapr")]
+    Dapr,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+    #[cfg(feature = "memory")]
+    Memory,
 }
 
 impl From<Resource> for KeyvalueImplementors {
@@ -122,6 +156,12 @@ impl From<Resource> for KeyvalueImplementors {
             }
             #[cfg(feature = "redis")]
             Resource::Keyvalue(Redis) | Resource::Keyvalue(V1Redis) => Self::Redis,
+            #[cfg(feature = "dapr")]
+            Resource::Keyvalue(Dapr) => Self::Dapr,
+            #[cfg(feature = "sqlite")]
+            Resource::Keyvalue(Sqlite) => Self::Sqlite,
+            #[cfg(feature = "memory")]
+            Resource::Keyvalue(Memory) => Self::Memory,
             p => panic!(
                 "failed to match provided name (i.e., '{p}') to any known host implementations"
             ),
@@ -168,7 +208,7 @@ impl keyvalue::Keyvalue for Keyvalue {
 
         tracing::log::info!("Opening implementor {}", &state.implementor);
 
-        let inner = Self::Keyvalue::new(state.implementor.into(), &state, name).await;
+        let inner = Self::Keyvalue::new(state.implementor.into(), &state, name).await?;
 
         Ok(inner)
     }
@@ -191,11 +231,112 @@ impl keyvalue::Keyvalue for Keyvalue {
         Ok(())
     }
 
+    async fn keyvalue_set_with_ttl(
+        &mut self,
+        self_: &Self::Keyvalue,
+        key: &str,
+        value: &[u8],
+        ttl_seconds: u32,
+    ) -> Result<(), KeyvalueError> {
+        if ttl_seconds == 0 {
+            return Err(KeyvalueError::InvalidValue(
+                "ttl-seconds must be greater than 0".to_string(),
+            ));
+        }
+        self_
+            .keyvalue_implementor
+            .set_with_ttl(key, value, Duration::from_secs(ttl_seconds.into()))
+            .await?;
+        Ok(())
+    }
+
+    async fn keyvalue_compare_and_swap(
+        &mut self,
+        self_: &Self::Keyvalue,
+        key: &str,
+        old: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool, KeyvalueError> {
+        Ok(self_
+            .keyvalue_implementor
+            .compare_and_swap(key, old, new)
+            .await?)
+    }
+
+    async fn keyvalue_increment(
+        &mut self,
+        self_: &Self::Keyvalue,
+        key: &str,
+        delta: i64,
+    ) -> Result<i64, KeyvalueError> {
+        Ok(self_.keyvalue_implementor.increment(key, delta).await?)
+    }
+
+    async fn keyvalue_get_many(
+        &mut self,
+        self_: &Self::Keyvalue,
+        keys: Vec<&str>,
+    ) -> Result<Vec<(String, Vec<u8>)>, KeyvalueError> {
+        Ok(self_.keyvalue_implementor.get_many(&keys).await?)
+    }
+
+    async fn keyvalue_set_many(
+        &mut self,
+        self_: &Self::Keyvalue,
+        key_values: Vec<(&str, &[u8])>,
+    ) -> Result<(), KeyvalueError> {
+        self_.keyvalue_implementor.set_many(&key_values).await?;
+        Ok(())
+    }
+
+    async fn keyvalue_delete_many(
+        &mut self,
+        self_: &Self::Keyvalue,
+        keys: Vec<&str>,
+    ) -> Result<(), KeyvalueError> {
+        self_.keyvalue_implementor.delete_many(&keys).await?;
+        Ok(())
+    }
+
     async fn keyvalue_keys(
         &mut self,
         self_: &Self::Keyvalue,
     ) -> Result<Vec<String>, KeyvalueError> {
-        Ok(self_.keyvalue_implementor.keys().await?)
+        // some implementors (e.g., keyvalue.redis) may list a key in more than one page
+        let mut keys = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cursor = None;
+        loop {
+            let (page, next_cursor) = self_
+                .keyvalue_implementor
+                .keys("", cursor.as_deref(), None)
+                .await?;
+            keys.extend(page.into_iter().filter(|key| seen.insert(key.clone())));
+            match next_cursor {
+                Some(c) => cursor = Some(c),
+                None => break,
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn keyvalue_list_keys(
+        &mut self,
+        self_: &Self::Keyvalue,
+        prefix: &str,
+        cursor: Option<&str>,
+        limit: Option<u32>,
+    ) -> Result<KeysPage, KeyvalueError> {
+        if limit == Some(0) {
+            return Err(KeyvalueError::InvalidValue(
+                "limit must be greater than 0".to_string(),
+            ));
+        }
+        let (keys, cursor) = self_
+            .keyvalue_implementor
+            .keys(prefix, cursor, limit)
+            .await?;
+        Ok(KeysPage { keys, cursor })
     }
 
     async fn keyvalue_delete(
@@ -206,4 +347,65 @@ impl keyvalue::Keyvalue for Keyvalue {
         self_.keyvalue_implementor.delete(key).await?;
         Ok(())
     }
+
+    async fn keyvalue_watch(
+        &mut self,
+        self_: &Self::Keyvalue,
+        prefix: &str,
+    ) -> Result<String, KeyvalueError> {
+        let changes = self_.keyvalue_implementor.watch(prefix).await?;
+        let watch_tok = Uuid::new_v4().to_string();
+        self_
+            .watches
+            .lock()
+            .unwrap()
+            .insert(watch_tok.clone(), changes);
+        Ok(watch_tok)
+    }
+
+    async fn keyvalue_changes(
+        &mut self,
+        self_: &Self::Keyvalue,
+        watch_tok: WatchTokenParam<'_>,
+    ) -> Result<Vec<ChangeEvent>, KeyvalueError> {
+        let mut watches = self_.watches.lock().unwrap();
+        let changes = watches.get_mut(watch_tok).ok_or_else(|| {
+            KeyvalueError::InvalidValue(format!("unknown watch token '{watch_tok}'"))
+        })?;
+
+        let mut events = Vec::new();
+        loop {
+            match changes.try_recv() {
+                Ok(KeyChange::Set(key)) => events.push(ChangeEvent::Set(key)),
+                Ok(KeyChange::Delete(key)) => events.push(ChangeEvent::Delete(key)),
+                Err(TryRecvError::Empty) => break,
+                // the implementor stopped watching (e.g., it lost its' connection)
+                Err(TryRecvError::Disconnected) if events.is_empty() => {
+                    watches.remove(watch_tok);
+                    return Err(KeyvalueError::UnexpectedError(format!(
+                        "watch '{watch_tok}' is no longer receiving changes"
+                    )));
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+        Ok(events)
+    }
+
+    async fn keyvalue_unwatch(
+        &mut self,
+        self_: &Self::Keyvalue,
+        watch_tok: WatchTokenParam<'_>,
+    ) -> Result<(), KeyvalueError> {
+        // dropping the receiver is what tells the implementor to stop watching
+        self_
+            .watches
+            .lock()
+            .unwrap()
+            .remove(watch_tok)
+            .ok_or_else(|| {
+                KeyvalueError::InvalidValue(format!("unknown watch token '{watch_tok}'"))
+            })?;
+        Ok(())
+    }
 }
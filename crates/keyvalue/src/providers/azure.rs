@@ -1,9 +1,15 @@
-use anyhow::Result;
-use azure_storage_blobs::{
-    container::operations::BlobItem,
-    prelude::{BlobClient, ContainerClient, DeleteSnapshotsMethod},
-};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use azure_storage_blobs::prelude::{BlobClient, DeleteSnapshotsMethod};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use futures::stream::StreamExt;
+use hmac::{Hmac, Mac};
+use quick_xml::{events::Event, Reader};
+use sha2::Sha256;
+
+/// The version of the blob storage REST API that requests are signed for.
+const API_VERSION: &str = "2021-08-06";
 
 /// Get the value given a `blob_client`
 pub async fn get(blob_client: BlobClient) -> Result<Vec<u8>> {
@@ -21,11 +27,14 @@ pub async fn get(blob_client: BlobClient) -> Result<Vec<u8>> {
     Ok(result)
 }
 
+/// The content type blobs are put w/.
+const CONTENT_TYPE: &str = "text/plain";
+
 /// Set the value given a `blob_client` and `value`
 pub async fn set(blob_client: BlobClient, value: Vec<u8>) -> Result<()> {
     blob_client
         .put_block_blob(value)
-        .content_type("text/plain")
+        .content_type(CONTENT_TYPE)
         .into_future()
         .await?;
     Ok(())
@@ -41,19 +50,276 @@ pub async fn delete(blob_client: BlobClient) -> Result<()> {
     Ok(())
 }
 
-pub async fn list_blobs(container_client: ContainerClient) -> Result<Vec<BlobItem>> {
-    let mut stream = container_client.list_blobs().into_stream();
-    let mut results = vec![];
-    while let Some(value) = stream.next().await {
-        let value = value?;
-        results.push(value);
+/// Lists one page of the names of the blobs in `container` that start w/ `prefix`,
+/// continuing from `marker` (if any), and returns the marker for the next page.
+///
+/// This talks to the blob storage REST API directly, b/c the blob client only
+/// streams a listing from its' start, and can't pick one up at a marker.
+pub async fn list_blob_names(
+    http_client: &reqwest::Client,
+    storage_account_name: &str,
+    storage_account_key: &str,
+    container: &str,
+    prefix: &str,
+    marker: Option<&str>,
+    max_results: Option<u32>,
+) -> Result<(Vec<String>, Option<String>)> {
+    let mut query = vec![
+        ("comp", "list".to_string()),
+        ("restype", "container".to_string()),
+    ];
+    if !prefix.is_empty() {
+        query.push(("prefix", prefix.to_string()));
+    }
+    if let Some(marker) = marker {
+        query.push(("marker", marker.to_string()));
     }
+    if let Some(max_results) = max_results {
+        query.push(("maxresults", max_results.to_string()));
+    }
+    // the signature covers the query parameters, sorted by name
+    query.sort();
 
-    let mut result = vec![];
-    for list_blob in results {
-        for blob in list_blob.blobs.items {
-            result.push(blob);
+    let date = httpdate::fmt_http_date(SystemTime::now());
+    let canonicalized_resource = query.iter().fold(
+        format!("/{storage_account_name}/{container}"),
+        |resource, (name, value)| format!("{resource}\n{name}:{value}"),
+    );
+    // a GET w/o a body leaves all of the standard headers blank
+    let string_to_sign = format!(
+        "GET\n\n\n\n\n\n\n\n\n\n\n\nx-ms-date:{date}\nx-ms-version:{API_VERSION}\n{canonicalized_resource}"
+    );
+    let authorization = sign(storage_account_name, storage_account_key, &string_to_sign)?;
+
+    let response = http_client
+        .get(format!(
+            "https://{storage_account_name}.blob.core.windows.net/{container}"
+        ))
+        .query(&query)
+        .header("x-ms-date", date)
+        .header("x-ms-version", API_VERSION)
+        .header("Authorization", authorization)
+        .send()
+        .await
+        .with_context(|| "failed to list blobs")?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!(
+            "failed to list blobs: azure responded w/ {}: {}",
+            status,
+            response.text().await?
+        );
+    }
+    parse_blob_names(&response.text().await?)
+}
+
+/// What has to hold for `put_blob_if` to put a blob.
+pub enum PutCondition<'a> {
+    /// The blob doesn't exist (i.e., `If-None-Match: *`).
+    Absent,
+    /// The blob hasn't changed since it had this ETag (i.e., `If-Match`).
+    Matches(&'a str),
+}
+
+/// Gets the content of the blob called `blob` in `container`, and its' ETag —
+/// or `None` if it doesn't exist.
+pub async fn get_blob_with_etag(
+    http_client: &reqwest::Client,
+    storage_account_name: &str,
+    storage_account_key: &str,
+    container: &str,
+    blob: &str,
+) -> Result<Option<(Vec<u8>, String)>> {
+    let url = blob_url(storage_account_name, container, blob)?;
+    let date = httpdate::fmt_http_date(SystemTime::now());
+    let string_to_sign = format!(
+        "GET\n\n\n\n\n\n\n\n\n\n\n\nx-ms-date:{date}\nx-ms-version:{API_VERSION}\n/{storage_account_name}{}",
+        url.path()
+    );
+    let authorization = sign(storage_account_name, storage_account_key, &string_to_sign)?;
+
+    let response = http_client
+        .get(url)
+        .header("x-ms-date", date)
+        .header("x-ms-version", API_VERSION)
+        .header("Authorization", authorization)
+        .send()
+        .await
+        .with_context(|| "failed to get blob")?;
+    let status = response.status();
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        bail!(
+            "failed to get blob: azure responded w/ {}: {}",
+            status,
+            response.text().await?
+        );
+    }
+    let etag = response
+        .headers()
+        .get("ETag")
+        .and_then(|etag| etag.to_str().ok())
+        .with_context(|| "azure responded w/o the blob's ETag")?
+        .to_string();
+    Ok(Some((response.bytes().await?.to_vec(), etag)))
+}
+
+/// Puts `value` into the block blob called `blob` in `container`, if `condition`
+/// holds (which Azure checks atomically w/ the write), and returns whether it did.
+pub async fn put_blob_if(
+    http_client: &reqwest::Client,
+    storage_account_name: &str,
+    storage_account_key: &str,
+    container: &str,
+    blob: &str,
+    value: Vec<u8>,
+    condition: PutCondition<'_>,
+) -> Result<bool> {
+    let url = blob_url(storage_account_name, container, blob)?;
+    let date = httpdate::fmt_http_date(SystemTime::now());
+    let (if_match, if_none_match) = match condition {
+        PutCondition::Absent => ("", "*"),
+        PutCondition::Matches(etag) => (etag, ""),
+    };
+    // the content length is left blank when it is 0
+    let content_length = match value.len() {
+        0 => String::new(),
+        len => len.to_string(),
+    };
+    let string_to_sign = format!(
+        "PUT\n\n\n{content_length}\n\n{CONTENT_TYPE}\n\n\n{if_match}\n{if_none_match}\n\n\nx-ms-blob-type:BlockBlob\nx-ms-date:{date}\nx-ms-version:{API_VERSION}\n/{storage_account_name}{}",
+        url.path()
+    );
+    let authorization = sign(storage_account_name, storage_account_key, &string_to_sign)?;
+
+    let mut request = http_client
+        .put(url)
+        .header("Content-Type", CONTENT_TYPE)
+        .header("x-ms-blob-type", "BlockBlob")
+        .header("x-ms-date", date)
+        .header("x-ms-version", API_VERSION)
+        .header("Authorization", authorization);
+    request = match condition {
+        PutCondition::Absent => request.header("If-None-Match", if_none_match),
+        PutCondition::Matches(_) => request.header("If-Match", if_match),
+    };
+    let response = request
+        .body(value)
+        .send()
+        .await
+        .with_context(|| "failed to put blob")?;
+    let status = response.status();
+    match status {
+        // the blob exists (for `Absent`), or has changed (for `Matches`)
+        reqwest::StatusCode::CONFLICT | reqwest::StatusCode::PRECONDITION_FAILED => Ok(false),
+        status if status.is_success() => Ok(true),
+        status => bail!(
+            "failed to put blob: azure responded w/ {}: {}",
+            status,
+            response.text().await?
+        ),
+    }
+}
+
+/// The url of the blob called `blob` in `container`, w/ each of the segments of
+/// its' path percent-encoded.
+fn blob_url(storage_account_name: &str, container: &str, blob: &str) -> Result<reqwest::Url> {
+    let mut url = reqwest::Url::parse(&format!(
+        "https://{storage_account_name}.blob.core.windows.net"
+    ))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("failed to build blob url"))?
+        .push(container)
+        .extend(blob.split('/'));
+    Ok(url)
+}
+
+/// Signs a request to the blob storage REST API w/ the storage account's shared
+/// key, and returns its' `Authorization` header.
+fn sign(
+    storage_account_name: &str,
+    storage_account_key: &str,
+    string_to_sign: &str,
+) -> Result<String> {
+    let key = STANDARD
+        .decode(storage_account_key)
+        .with_context(|| "storage account key is not valid base64")?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key)
+        .with_context(|| "failed to sign request w/ storage account key")?;
+    mac.update(string_to_sign.as_bytes());
+    let signature = STANDARD.encode(mac.finalize().into_bytes());
+    Ok(format!("SharedKey {storage_account_name}:{signature}"))
+}
+
+/// Pulls the blob names, and the next marker, out of a `List Blobs` response.
+fn parse_blob_names(xml: &str) -> Result<(Vec<String>, Option<String>)> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut names = Vec::new();
+    let mut next_marker = None;
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) => path.push(e.name().as_ref().to_vec()),
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(e) => {
+                let text = e.unescape()?.into_owned();
+                match path.iter().map(Vec::as_slice).collect::<Vec<_>>()[..] {
+                    [.., b"Blob", b"Name"] => names.push(text),
+                    [b"EnumerationResults", b"NextMarker"] if !text.is_empty() => {
+                        next_marker = Some(text)
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
         }
     }
-    Ok(result)
+    Ok((names, next_marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blob_url, parse_blob_names, sign};
+
+    #[test]
+    fn parse_blob_names_test() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?><EnumerationResults ServiceEndpoint="https://account.blob.core.windows.net/" ContainerName="container"><Prefix>page-a</Prefix><MaxResults>1</MaxResults><Blobs><Blob><Name>page-a1 &amp; more</Name><Properties><Content-Length>5</Content-Length></Properties></Blob></Blobs><NextMarker>2!abc</NextMarker></EnumerationResults>"#;
+        assert_eq!(
+            parse_blob_names(xml).unwrap(),
+            (
+                vec!["page-a1 & more".to_string()],
+                Some("2!abc".to_string())
+            )
+        );
+
+        let xml = r#"<EnumerationResults><Blobs><Blob><Name>a</Name></Blob><Blob><Name>b</Name></Blob></Blobs><NextMarker /></EnumerationResults>"#;
+        assert_eq!(
+            parse_blob_names(xml).unwrap(),
+            (vec!["a".to_string(), "b".to_string()], None)
+        );
+    }
+
+    #[test]
+    fn blob_url_test() {
+        let url = blob_url("account", "container", "dir/a b#c?d").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://account.blob.core.windows.net/container/dir/a%20b%23c%3Fd"
+        );
+    }
+
+    #[test]
+    fn sign_test() {
+        assert_eq!(
+            sign("account", "a2V5", "PUT\n/account/container/blob").unwrap(),
+            "SharedKey account:FXq80BPvNxXNMM+3CuICgvUITnpyk3uy+riEcUz3MxU="
+        );
+        assert!(sign("account", "not base64!", "").is_err());
+    }
 }
@@ -0,0 +1,69 @@
+pub use messaging_handler::{Message, MessagingHandlerData};
+
+wit_bindgen_wasmtime::import!({paths: ["../../wit/messaging-handler.wit"], async: *});
+
+/// A Messaging Handler that finds the handler function from the wasm module
+/// and calls it with a received message.
+///
+/// Like the `HttpHandler` in `slight-http-api`, the purpose of this wrapper is
+/// to enable any handler name being bound to a subscription in the slightfile.
+/// The `new` constructor function will take `handler_name` as a parameter, and
+/// then use it to find the handler function, which may differ from the
+/// `handle-message` function name defined in the wit file.
+///
+/// The handler_name must be defined in the wasm module and use the
+/// `register_message_handler` macro to register the handler function.
+pub struct MessagingHandler<T> {
+    inner: messaging_handler::MessagingHandler<T>,
+}
+
+impl<T> AsRef<messaging_handler::MessagingHandler<T>> for MessagingHandler<T> {
+    fn as_ref(&self) -> &messaging_handler::MessagingHandler<T> {
+        &self.inner
+    }
+}
+
+impl<T> AsMut<messaging_handler::MessagingHandler<T>> for MessagingHandler<T> {
+    fn as_mut(&mut self) -> &mut messaging_handler::MessagingHandler<T> {
+        &mut self.inner
+    }
+}
+
+impl<T: Send> MessagingHandler<T> {
+    /// Create a new Messaging Handler.
+    pub fn new(
+        mut store: impl wasmtime::AsContextMut<Data = T>,
+        instance: &wasmtime::Instance,
+        handler_name: &str,
+        get_state: impl Fn(&mut T) -> &mut MessagingHandlerData + Send + Sync + Copy + 'static,
+    ) -> anyhow::Result<Self> {
+        let mut store = store.as_context_mut();
+        let canonical_abi_free =
+            instance.get_typed_func::<(i32, i32, i32), ()>(&mut store, "canonical_abi_free")?;
+        let canonical_abi_realloc = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&mut store, "canonical_abi_realloc")?;
+        let handle_message = instance
+            .get_typed_func::<(i32, i32, i32, i32, i32, i32), (i32,)>(&mut store, handler_name)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow::anyhow!("`memory` export not a memory"))?;
+        let get_state = Box::new(get_state);
+        Ok(Self {
+            inner: messaging_handler::MessagingHandler {
+                get_state,
+                canonical_abi_free,
+                canonical_abi_realloc,
+                handle_message,
+                memory,
+            },
+        })
+    }
+
+    pub async fn handle_message(
+        &self,
+        caller: impl wasmtime::AsContextMut<Data = T>,
+        msg: Message<'_>,
+    ) -> Result<Result<(), String>, anyhow::Error> {
+        self.inner.handle_message(caller, msg).await
+    }
+}
@@ -0,0 +1,482 @@
+//! Wrapping published messages into CloudEvents, and unwrapping received ones.
+
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{Map, Value};
+use slight_common::BasicState;
+use slight_runtime_configs::maybe_get_from_state;
+
+use crate::implementors::ReceivedMessage;
+
+/// The version of the CloudEvents spec that messages are wrapped w/.
+const SPEC_VERSION: &str = "1.0";
+/// The prefix of the headers a guest sets, and gets, an event's attributes
+/// through, whichever the mode, and implementor.
+const ATTRIBUTE_HEADER_PREFIX: &str = "ce-";
+/// The header an event's `datacontenttype` maps to.
+const CONTENT_TYPE_HEADER: &str = "content-type";
+/// The content type of a structured mode event.
+const STRUCTURED_CONTENT_TYPE: &str = "application/cloudevents+json";
+
+/// A message's payload, and headers, to publish.
+type Message = (Vec<u8>, Vec<(String, String)>);
+
+/// How an event is laid out in a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// The whole event (i.e., its' attributes, and data) is the payload, as json.
+    Structured,
+    /// The event's data is the payload, and its' attributes are headers,
+    /// prefixed as per the CloudEvents protocol binding of the implementor (e.g.,
+    /// `ce_` for kafka).
+    Binary { header_prefix: &'static str },
+}
+
+impl Mode {
+    /// Parses `CLOUDEVENTS_MODE`, where `header_prefix` is `None` if the
+    /// implementor of the resource called `name` has no headers, and so only
+    /// supports structured mode.
+    fn parse(
+        mode: Option<&str>,
+        header_prefix: Option<&'static str>,
+        name: &str,
+    ) -> Result<Option<Self>> {
+        match (mode, header_prefix) {
+            (None, _) => Ok(None),
+            (Some("structured"), _) => Ok(Some(Self::Structured)),
+            (Some("binary"), Some(header_prefix)) => Ok(Some(Self::Binary { header_prefix })),
+            (Some("binary"), None) => bail!(
+                "CLOUDEVENTS_MODE 'binary' is not supported by the implementor of {name}, as it doesn't support message headers"
+            ),
+            (Some(mode), _) => {
+                bail!("CLOUDEVENTS_MODE must be either 'structured', or 'binary', but got '{mode}'")
+            }
+        }
+    }
+}
+
+/// Wraps the messages published through a messaging resource into CloudEvents,
+/// and unwraps the ones received through it, if its' `CLOUDEVENTS_MODE` is set
+/// (to "structured", or "binary"). Structured mode events are published w/ a
+/// `content-type` of "application/cloudevents+json", if the implementor
+/// supports headers.
+///
+/// Events get a random `id`, the `source` set by the optional
+/// `CLOUDEVENTS_SOURCE` (or the resource's name), and the `type` set by the
+/// optional `CLOUDEVENTS_TYPE` (or the topic), where `{topic}` is replaced w/
+/// the topic they're published to. A guest can set these, or any other
+/// attribute, per message, w/ `ce-` headers (e.g., `ce-type`), and gets a
+/// received event's attributes as such headers, too. Received messages that
+/// aren't CloudEvents are left as-is.
+#[derive(Debug)]
+pub struct CloudEvents {
+    mode: Mode,
+    source: String,
+    event_type: String,
+    /// Whether the implementor supports headers, which a structured mode event's
+    /// content type is set through.
+    has_headers: bool,
+}
+
+impl CloudEvents {
+    /// `header_prefix` is how attributes' headers are prefixed in binary mode, or
+    /// `None` if the implementor has no headers.
+    pub async fn from_state(
+        slight_state: &BasicState,
+        name: &str,
+        header_prefix: Option<&'static str>,
+    ) -> Result<Option<Self>> {
+        let mode = maybe_get_from_state("CLOUDEVENTS_MODE", slight_state).await?;
+        let mode = match Mode::parse(mode.as_deref(), header_prefix, name)? {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+        let source = maybe_get_from_state("CLOUDEVENTS_SOURCE", slight_state)
+            .await?
+            .unwrap_or_else(|| name.to_string());
+        let event_type = maybe_get_from_state("CLOUDEVENTS_TYPE", slight_state)
+            .await?
+            .unwrap_or_else(|| "{topic}".to_string());
+
+        Ok(Some(Self {
+            mode,
+            source,
+            event_type,
+            has_headers: header_prefix.is_some(),
+        }))
+    }
+
+    /// Wraps a message that is about to be published to `topic` into an event,
+    /// and returns the payload, and headers, to publish instead.
+    pub fn wrap(&self, payload: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<Message> {
+        let mut attributes = Map::new();
+        attributes.insert("specversion".into(), SPEC_VERSION.into());
+        attributes.insert("id".into(), uuid::Uuid::new_v4().to_string().into());
+        attributes.insert("source".into(), self.source.clone().into());
+        attributes.insert(
+            "type".into(),
+            self.event_type.replace("{topic}", topic).into(),
+        );
+        let mut other_headers = Vec::new();
+        for (key, value) in headers {
+            match key.strip_prefix(ATTRIBUTE_HEADER_PREFIX) {
+                Some(attribute) => {
+                    attributes.insert(attribute.to_string(), value.to_string().into());
+                }
+                None => other_headers.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        match self.mode {
+            // the content type is a header of its' own in binary mode, so it's
+            // left as-is
+            Mode::Binary { header_prefix } => {
+                let mut headers: Vec<(String, String)> = attributes
+                    .into_iter()
+                    .map(|(attribute, value)| {
+                        (
+                            format!("{header_prefix}{attribute}"),
+                            to_header_value(value),
+                        )
+                    })
+                    .collect();
+                headers.extend(other_headers);
+                Ok((payload.to_vec(), headers))
+            }
+            Mode::Structured => {
+                let mut event = attributes;
+                let content_type = take_content_type(&mut other_headers);
+                // data w/o a content type is taken to be json, if it parses as such
+                let is_json = content_type.iter().all(|ct| is_json_content_type(ct));
+                match serde_json::from_slice::<Value>(payload) {
+                    Ok(data) if is_json => {
+                        event.insert(
+                            "datacontenttype".into(),
+                            content_type
+                                .unwrap_or_else(|| "application/json".into())
+                                .into(),
+                        );
+                        event.insert("data".into(), data);
+                    }
+                    _ => match std::str::from_utf8(payload) {
+                        Ok(text) => {
+                            event.insert(
+                                "datacontenttype".into(),
+                                content_type.unwrap_or_else(|| "text/plain".into()).into(),
+                            );
+                            event.insert("data".into(), text.into());
+                        }
+                        Err(_) => {
+                            if let Some(content_type) = content_type {
+                                event.insert("datacontenttype".into(), content_type.into());
+                            }
+                            event.insert("data_base64".into(), STANDARD.encode(payload).into());
+                        }
+                    },
+                }
+                if self.has_headers {
+                    other_headers.push((
+                        CONTENT_TYPE_HEADER.to_string(),
+                        STRUCTURED_CONTENT_TYPE.to_string(),
+                    ));
+                }
+                Ok((serde_json::to_vec(&event)?, other_headers))
+            }
+        }
+    }
+
+    /// Unwraps a received event into its' data, and its' attributes into `ce-`
+    /// headers (and a `content-type` one, for its' `datacontenttype`). Json data
+    /// is serialized anew, so its' formatting (e.g., whitespace, or the order of
+    /// keys) isn't kept.
+    pub fn unwrap(&self, mut message: ReceivedMessage) -> ReceivedMessage {
+        match self.mode {
+            Mode::Binary { header_prefix } => {
+                let spec_version_header = format!("{header_prefix}specversion");
+                if !message
+                    .headers
+                    .iter()
+                    .any(|(k, _)| *k == spec_version_header)
+                {
+                    return message;
+                }
+                for (key, _) in &mut message.headers {
+                    if let Some(attribute) = key.strip_prefix(header_prefix) {
+                        *key = format!("{ATTRIBUTE_HEADER_PREFIX}{attribute}");
+                    }
+                }
+                message
+            }
+            Mode::Structured => {
+                let mut event = match serde_json::from_slice::<Value>(&message.payload) {
+                    Ok(Value::Object(event)) if event.contains_key("specversion") => event,
+                    _ => return message,
+                };
+                let content_type = match event.remove("datacontenttype") {
+                    Some(Value::String(content_type)) => Some(content_type),
+                    _ => None,
+                };
+                let payload = match (event.remove("data"), event.remove("data_base64")) {
+                    (_, Some(Value::String(encoded))) => match STANDARD.decode(encoded) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            tracing::warn!("failed to decode cloudevent's data_base64: {e}");
+                            return message;
+                        }
+                    },
+                    (Some(Value::String(text)), _)
+                        if content_type.iter().any(|ct| !is_json_content_type(ct)) =>
+                    {
+                        text.into_bytes()
+                    }
+                    (Some(data), _) => data.to_string().into_bytes(),
+                    (None, _) => Vec::new(),
+                };
+
+                message.payload = payload;
+                // the event's content type (i.e., that of a structured mode event)
+                // is replaced w/ its' data's
+                take_content_type(&mut message.headers);
+                if let Some(content_type) = content_type {
+                    message
+                        .headers
+                        .push((CONTENT_TYPE_HEADER.to_string(), content_type));
+                }
+                message
+                    .headers
+                    .extend(event.into_iter().map(|(attribute, value)| {
+                        (
+                            format!("{ATTRIBUTE_HEADER_PREFIX}{attribute}"),
+                            to_header_value(value),
+                        )
+                    }));
+                message
+            }
+        }
+    }
+}
+
+/// Removes the `content-type` header(s), and returns the (first) value.
+fn take_content_type(headers: &mut Vec<(String, String)>) -> Option<String> {
+    let mut content_type = None;
+    headers.retain(|(key, value)| {
+        if !key.eq_ignore_ascii_case(CONTENT_TYPE_HEADER) {
+            return true;
+        }
+        content_type.get_or_insert_with(|| value.clone());
+        false
+    });
+    content_type
+}
+
+fn is_json_content_type(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json" || media_type == "text/json" || media_type.ends_with("+json")
+}
+
+/// Attributes are strings, for the most part, which are used as-is, whereas
+/// any other json value is used as its' json text.
+fn to_header_value(value: Value) -> String {
+    match value {
+        Value::String(value) => value,
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cloudevents(mode: Mode) -> CloudEvents {
+        CloudEvents {
+            mode,
+            source: "my-events".to_string(),
+            event_type: "com.example.{topic}".to_string(),
+            has_headers: true,
+        }
+    }
+
+    /// Receives what `wrap` returned, as a message published to `orders`.
+    fn received(payload: Vec<u8>, headers: Vec<(String, String)>) -> ReceivedMessage {
+        ReceivedMessage {
+            handle: String::new(),
+            topic: "orders".to_string(),
+            payload,
+            headers,
+            system_properties: Default::default(),
+        }
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], key: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn parse_mode() -> Result<()> {
+        assert_eq!(Mode::parse(None, None, "my-events")?, None);
+        assert_eq!(
+            Mode::parse(Some("structured"), None, "my-events")?,
+            Some(Mode::Structured)
+        );
+        assert_eq!(
+            Mode::parse(Some("binary"), Some("ce_"), "my-events")?,
+            Some(Mode::Binary {
+                header_prefix: "ce_"
+            })
+        );
+        // w/o headers, there's nowhere to put the attributes
+        assert!(Mode::parse(Some("binary"), None, "my-events").is_err());
+        assert!(Mode::parse(Some("batch"), Some("ce-"), "my-events").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn structured_json_round_trip() -> Result<()> {
+        let cloudevents = cloudevents(Mode::Structured);
+        let (payload, headers) = cloudevents.wrap(br#"{"order":42}"#, "orders", &[])?;
+        assert_eq!(
+            header(&headers, "content-type"),
+            Some("application/cloudevents+json")
+        );
+        let event: Value = serde_json::from_slice(&payload)?;
+        assert_eq!(event["specversion"], "1.0");
+        assert_eq!(event["source"], "my-events");
+        assert_eq!(event["type"], "com.example.orders");
+        assert_eq!(event["datacontenttype"], "application/json");
+        assert_eq!(event["data"]["order"], 42);
+
+        let message = cloudevents.unwrap(received(payload, headers));
+        assert_eq!(message.payload, br#"{"order":42}"#);
+        assert_eq!(
+            header(&message.headers, "content-type"),
+            Some("application/json")
+        );
+        assert_eq!(
+            header(&message.headers, "ce-type"),
+            Some("com.example.orders")
+        );
+        assert!(header(&message.headers, "ce-id").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn structured_text_round_trip() -> Result<()> {
+        let cloudevents = cloudevents(Mode::Structured);
+        let (payload, headers) = cloudevents.wrap(
+            b"42",
+            "orders",
+            &[
+                ("content-type", "text/plain"),
+                ("ce-subject", "order"),
+                ("correlation-id", "7"),
+            ],
+        )?;
+        let event: Value = serde_json::from_slice(&payload)?;
+        // text that happens to be json is kept as text
+        assert_eq!(event["data"], "42");
+        assert_eq!(event["subject"], "order");
+        assert_eq!(header(&headers, "correlation-id"), Some("7"));
+
+        let message = cloudevents.unwrap(received(payload, headers));
+        assert_eq!(message.payload, b"42");
+        assert_eq!(header(&message.headers, "content-type"), Some("text/plain"));
+        assert_eq!(header(&message.headers, "ce-subject"), Some("order"));
+        assert_eq!(header(&message.headers, "correlation-id"), Some("7"));
+        Ok(())
+    }
+
+    #[test]
+    fn structured_base64_round_trip() -> Result<()> {
+        let cloudevents = cloudevents(Mode::Structured);
+        let bytes: &[u8] = &[0, 159, 146, 150];
+        let (payload, headers) = cloudevents.wrap(bytes, "orders", &[])?;
+        let event: Value = serde_json::from_slice(&payload)?;
+        assert_eq!(event["data_base64"], STANDARD.encode(bytes));
+        assert!(event.get("data").is_none());
+
+        let message = cloudevents.unwrap(received(payload, headers));
+        assert_eq!(message.payload, bytes);
+        // there's no content type to replace the event's w/
+        assert_eq!(header(&message.headers, "content-type"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn structured_wo_headers() -> Result<()> {
+        let cloudevents = CloudEvents {
+            has_headers: false,
+            ..cloudevents(Mode::Structured)
+        };
+        let (_, headers) = cloudevents.wrap(b"placed", "orders", &[])?;
+        assert!(headers.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn binary_round_trip() -> Result<()> {
+        for header_prefix in ["ce_", "cloudEvents:"] {
+            let cloudevents = cloudevents(Mode::Binary { header_prefix });
+            let (payload, headers) = cloudevents.wrap(
+                b"placed",
+                "orders",
+                &[
+                    ("ce-type", "com.example.order.placed"),
+                    ("correlation-id", "7"),
+                ],
+            )?;
+            assert_eq!(payload, b"placed");
+            assert_eq!(
+                header(&headers, &format!("{header_prefix}specversion")),
+                Some("1.0")
+            );
+            assert_eq!(
+                header(&headers, &format!("{header_prefix}type")),
+                Some("com.example.order.placed")
+            );
+            assert_eq!(
+                header(&headers, &format!("{header_prefix}source")),
+                Some("my-events")
+            );
+            assert_eq!(header(&headers, "ce-type"), None);
+            assert_eq!(header(&headers, "correlation-id"), Some("7"));
+
+            let message = cloudevents.unwrap(received(payload, headers));
+            assert_eq!(message.payload, b"placed");
+            assert_eq!(header(&message.headers, "ce-specversion"), Some("1.0"));
+            assert_eq!(
+                header(&message.headers, "ce-type"),
+                Some("com.example.order.placed")
+            );
+            assert_eq!(header(&message.headers, "correlation-id"), Some("7"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unwrap_leaves_other_messages_as_is() {
+        for mode in [
+            Mode::Structured,
+            Mode::Binary {
+                header_prefix: "ce_",
+            },
+        ] {
+            let message = cloudevents(mode).unwrap(received(
+                br#"{"order":42}"#.to_vec(),
+                vec![("content-type".to_string(), "application/json".to_string())],
+            ));
+            assert_eq!(message.payload, br#"{"order":42}"#);
+            assert_eq!(
+                message.headers,
+                vec![("content-type".to_string(), "application/json".to_string())]
+            );
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use async_trait::async_trait;
+use lapin::acker::Acker;
+use lapin::message::Delivery;
+use lapin::options::{
+    BasicAckOptions, BasicGetOptions, BasicNackOptions, BasicPublishOptions, ConfirmSelectOptions,
+    ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions, QueueDeleteOptions,
+};
+use lapin::types::{AMQPValue, FieldTable, LongString, ShortString};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use slight_common::BasicState;
+use slight_runtime_configs::{get_from_state, maybe_get_from_state};
+
+use crate::wildcard::{self, DEFAULT_SEPARATOR};
+
+use super::{PubImplementor, ReceivedMessage, SubImplementor, SystemProperties};
+
+/// The exchange messages are published to if `AMQP_EXCHANGE` isn't set.
+const DEFAULT_EXCHANGE: &str = "slight";
+/// How often `receive` asks the broker for a message while it waits for one.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// This is the underlying struct behind the `Amqp` variant of the `MessagingImplementors` enum.
+///
+/// It publishes to a single exchange, w/ topics as routing keys, and waits for
+/// the broker to confirm each message. Each subscription reads from a queue
+/// bound to that exchange w/ its' topic — the queue named after its' consumer
+/// group, or an exclusive one if it wasn't given any — through a channel of its'
+/// own.
+///
+/// As per its' usage in `PubInner` and `SubInner`, it must `derive` `Debug`, and `Clone`.
+#[derive(Clone)]
+pub struct AmqpImplementor {
+    // the connection closes its' channels once dropped
+    connection: Arc<Connection>,
+    // the channel messages are published on
+    channel: Channel,
+    exchange: String,
+    durable: bool,
+    subscription_tokens: Arc<Mutex<HashMap<String, AmqpSubscription>>>,
+    unacked_messages: Arc<Mutex<HashMap<String, Acker>>>,
+}
+
+/// The queue a subscription reads from, whether it is the subscription's own
+/// (i.e., exclusive) queue, and the channel it reads on.
+#[derive(Clone)]
+struct AmqpSubscription {
+    queue: String,
+    exclusive: bool,
+    channel: Channel,
+}
+
+impl std::fmt::Debug for AmqpImplementor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AmqpImplementor")
+    }
+}
+
+impl AmqpImplementor {
+    /// Connects to the broker at `AMQP_URI`, and declares the exchange to
+    /// publish to. The exchange is named after the optional `AMQP_EXCHANGE`, and
+    /// is of the optional `AMQP_EXCHANGE_TYPE` (i.e., "direct", "fanout",
+    /// "headers", or "topic" — the default). Setting the optional `AMQP_DURABLE`
+    /// to "true" makes the exchange, and consumer groups' queues, survive a
+    /// broker restart.
+    pub async fn new(slight_state: &BasicState) -> Self {
+        let uri = get_from_state("AMQP_URI", slight_state).await.unwrap();
+        let exchange = maybe_get_from_state("AMQP_EXCHANGE", slight_state)
+            .await
+            .unwrap()
+            .unwrap_or_else(|| DEFAULT_EXCHANGE.to_string());
+        let exchange_kind = match maybe_get_from_state("AMQP_EXCHANGE_TYPE", slight_state)
+            .await
+            .unwrap()
+            .as_deref()
+        {
+            None | Some("topic") => ExchangeKind::Topic,
+            Some("direct") => ExchangeKind::Direct,
+            Some("fanout") => ExchangeKind::Fanout,
+            Some("headers") => ExchangeKind::Headers,
+            Some(kind) => ExchangeKind::Custom(kind.to_string()),
+        };
+        let durable = matches!(
+            maybe_get_from_state("AMQP_DURABLE", slight_state)
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("true")
+        );
+
+        tracing::debug!("Connecting to AMQP broker at {}", uri);
+
+        let connection = Connection::connect(&uri, ConnectionProperties::default())
+            .await
+            .unwrap();
+        let channel = connection.create_channel().await.unwrap();
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await
+            .unwrap();
+        channel
+            .exchange_declare(
+                &exchange,
+                exchange_kind,
+                ExchangeDeclareOptions {
+                    durable,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await
+            .unwrap();
+
+        Self {
+            connection: Arc::new(connection),
+            channel,
+            exchange,
+            durable,
+            subscription_tokens: Arc::new(Mutex::new(HashMap::new())),
+            unacked_messages: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn get_subscription(&self, sub_tok: &str) -> Result<AmqpSubscription> {
+        self.subscription_tokens
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .cloned()
+            .with_context(|| "failed to get queue from subscription token")
+    }
+
+    fn take_unacked_message(&self, handle: &str) -> Result<Acker> {
+        self.unacked_messages
+            .lock()
+            .unwrap()
+            .remove(handle)
+            .with_context(|| "failed to get unacknowledged message from handle")
+    }
+}
+
+/// Gets the next message from `sub`'s queue, if any.
+async fn get(sub: &AmqpSubscription, no_ack: bool) -> Result<Option<Delivery>> {
+    let msg = sub
+        .channel
+        .basic_get(&sub.queue, BasicGetOptions { no_ack })
+        .await
+        .with_context(|| "failed to get message from queue")?;
+    Ok(msg.map(|msg| msg.delivery))
+}
+
+#[async_trait]
+impl PubImplementor for AmqpImplementor {
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        let mut header_table = FieldTable::default();
+        for (key, value) in headers {
+            header_table.insert(
+                ShortString::from(key.to_string()),
+                AMQPValue::LongString(LongString::from(value.to_string())),
+            );
+        }
+        let properties = BasicProperties::default()
+            .with_message_id(ShortString::from(uuid::Uuid::new_v4().to_string()))
+            .with_timestamp(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+            .with_headers(header_table);
+
+        let confirmation = self
+            .channel
+            .basic_publish(
+                &self.exchange,
+                topic,
+                BasicPublishOptions::default(),
+                msg,
+                properties,
+            )
+            .await
+            .with_context(|| "failed to publish message")?
+            .await
+            .with_context(|| "failed to confirm published message")?;
+        if confirmation.is_nack() {
+            bail!("failed to publish message: the broker rejected it");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SubImplementor for AmqpImplementor {
+    /// Subscriptions in a consumer `group` share the queue named after it, whereas
+    /// ones w/o a group each get an exclusive, server-named, queue that is deleted
+    /// along w/ the connection. The queue is bound w/ the `topic` as its' binding
+    /// key, in which a topic exchange's wildcards are `*`, and `#` (which matches
+    /// zero or more levels, so `>` becomes `*.#`).
+    ///
+    /// Each subscription gets a channel of its' own, as the broker only
+    /// redelivers the messages left unacked on a channel once it closes.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        let topic = &wildcard::translate(topic, DEFAULT_SEPARATOR, "*", "#")?;
+        let channel = self
+            .connection
+            .create_channel()
+            .await
+            .with_context(|| "failed to open channel")?;
+        let options = match group {
+            Some(_) => QueueDeclareOptions {
+                durable: self.durable,
+                ..Default::default()
+            },
+            None => QueueDeclareOptions {
+                exclusive: true,
+                auto_delete: true,
+                ..Default::default()
+            },
+        };
+        let queue = channel
+            .queue_declare(group.unwrap_or(""), options, FieldTable::default())
+            .await
+            .with_context(|| "failed to declare queue")?;
+        let queue = queue.name().as_str().to_string();
+        channel
+            .queue_bind(
+                &queue,
+                &self.exchange,
+                topic,
+                QueueBindOptions::default(),
+                FieldTable::default(),
+            )
+            .await
+            .with_context(|| "failed to bind queue to exchange")?;
+
+        let sub_tok = uuid::Uuid::new_v4().to_string();
+        self.subscription_tokens.lock().unwrap().insert(
+            sub_tok.clone(),
+            AmqpSubscription {
+                queue,
+                exclusive: group.is_none(),
+                channel,
+            },
+        );
+
+        Ok(sub_tok)
+    }
+
+    /// A consumer group's queue is shared by its' members, so it outlives them,
+    /// whereas a subscription's own queue is deleted along w/ it. The
+    /// subscription's channel is closed, so the messages it left unacked are
+    /// redelivered (to the group's other members).
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        let sub = self
+            .subscription_tokens
+            .lock()
+            .unwrap()
+            .remove(sub_tok)
+            .with_context(|| "failed to get queue from subscription token")?;
+        if sub.exclusive {
+            sub.channel
+                .queue_delete(&sub.queue, QueueDeleteOptions::default())
+                .await
+                .with_context(|| "failed to delete queue")?;
+        }
+        sub.channel
+            .close(0, "unsubscribed")
+            .await
+            .with_context(|| "failed to close channel")?;
+        Ok(())
+    }
+
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        let sub = self.get_subscription(sub_tok)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(delivery) = get(&sub, true).await? {
+                return Ok(Some(to_received_message(String::new(), &delivery)));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    fn supports_acknowledgement(&self) -> bool {
+        true
+    }
+
+    /// AMQP brokers only redeliver unacknowledged messages once the channel they
+    /// were delivered on closes (i.e., once the subscription is cancelled, or
+    /// slight exits), so the `visibility_timeout` is ignored.
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        if visibility_timeout.is_some() {
+            tracing::debug!("ignoring visibility timeout as amqp doesn't support one");
+        }
+
+        let sub = self.get_subscription(sub_tok)?;
+        let delivery = match get(&sub, false).await? {
+            Some(delivery) => delivery,
+            None => return Ok(None),
+        };
+
+        let handle = uuid::Uuid::new_v4().to_string();
+        let received = to_received_message(handle.clone(), &delivery);
+        self.unacked_messages
+            .lock()
+            .unwrap()
+            .insert(handle, delivery.acker);
+
+        Ok(Some(received))
+    }
+
+    async fn ack(&self, handle: &str) -> Result<()> {
+        self.take_unacked_message(handle)?
+            .ack(BasicAckOptions::default())
+            .await
+            .with_context(|| "failed to ack message")?;
+        Ok(())
+    }
+
+    async fn nack(&self, handle: &str) -> Result<()> {
+        self.take_unacked_message(handle)?
+            .nack(BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            })
+            .await
+            .with_context(|| "failed to nack message")?;
+        Ok(())
+    }
+}
+
+fn to_received_message(handle: String, delivery: &Delivery) -> ReceivedMessage {
+    let properties = &delivery.properties;
+    let mut headers = Vec::new();
+    if let Some(header_table) = properties.headers() {
+        for (key, value) in header_table.inner() {
+            if let AMQPValue::LongString(value) = value {
+                headers.push((
+                    key.as_str().to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                ));
+            }
+        }
+    }
+
+    ReceivedMessage {
+        handle,
+        // messages are published w/ their topic as the routing key
+        topic: delivery.routing_key.as_str().to_string(),
+        payload: delivery.data.clone(),
+        headers,
+        system_properties: SystemProperties {
+            message_id: properties
+                .message_id()
+                .as_ref()
+                .map(|id| id.as_str().to_string()),
+            // amqp timestamps are in seconds
+            timestamp: properties.timestamp().map(|t| t * 1000),
+            // amqp only tells whether a message has been delivered before
+            delivery_count: if delivery.redelivered { None } else { Some(1) },
+        },
+    }
+}
@@ -1,8 +1,8 @@
 use async_trait::async_trait;
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     sync::{Arc, Mutex},
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{Context, Result};
@@ -11,9 +11,11 @@ use slight_common::BasicState;
 use slight_runtime_configs::get_from_state;
 use tokio::{runtime::Handle, task::block_in_place};
 
-use crate::providers::confluent;
+use crate::providers::confluent::{self, MessagePosition, UncommittedMessage};
 
-use super::{PubImplementor, SubImplementor};
+use crate::wildcard::{self, DEFAULT_SEPARATOR};
+
+use super::{PubImplementor, ReceivedMessage, SubImplementor, SystemProperties};
 
 #[derive(Clone)]
 pub struct Pub {
@@ -49,28 +51,69 @@ impl Pub {
 
 #[async_trait]
 impl PubImplementor for Pub {
-    async fn publish(&self, msg_value: &[u8], topic: &str) -> Result<()> {
+    async fn publish(&self, msg_value: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
         tracing::info!("publishing to topic {}", topic);
 
         confluent::publish(
             &self.producer,
-            format!(
-                "{:?}",
-                SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
-            )
-            .as_bytes(), // rand key
+            message_key().as_bytes(),
             msg_value,
             topic,
+            headers,
         )
         .with_context(|| "failed to send message to a topic")
     }
+
+    /// The messages are all queued up in the producer before it is flushed, so
+    /// that it batches them into as few requests as it can.
+    async fn publish_batch(&self, msgs: &[&[u8]], topic: &str) -> Result<()> {
+        tracing::info!("publishing {} messages to topic {}", msgs.len(), topic);
+
+        for msg_value in msgs {
+            confluent::publish(
+                &self.producer,
+                message_key().as_bytes(),
+                msg_value,
+                topic,
+                &[],
+            )
+            .with_context(|| "failed to send message to a topic")?;
+        }
+        block_in_place(|| confluent::flush(&self.producer))
+            .with_context(|| "failed to deliver batch of messages")
+    }
+}
+
+/// The offsets of the messages a subscription has received from a partition,
+/// but hasn't acked yet, and the offset of the next message it will receive
+/// from it.
+#[derive(Debug, Default)]
+struct PartitionOffsets {
+    outstanding: BTreeSet<i64>,
+    next: i64,
+}
+
+impl PartitionOffsets {
+    /// The offset that is safe to commit: that of the lowest un-acked message,
+    /// since committing past it would ack it too.
+    fn committable(&self) -> i64 {
+        self.outstanding.iter().next().copied().unwrap_or(self.next)
+    }
 }
 
+/// Each subscription's offsets, by topic and partition.
+type SubscriptionOffsets = HashMap<String, HashMap<(String, i32), PartitionOffsets>>;
+
+/// A message received w/ `receive_message`, that is waiting to be acked.
+type Uncommitted = (String, Arc<StreamConsumer>, MessagePosition);
+
 #[derive(Clone)]
 pub struct Sub {
     apache_kafka_config: ApacheKafkaConfigs,
     group_id: String,
     consumers: Arc<Mutex<HashMap<String, Arc<StreamConsumer>>>>,
+    uncommitted: Arc<Mutex<HashMap<String, Uncommitted>>>,
+    offsets: Arc<Mutex<SubscriptionOffsets>>,
 }
 
 impl std::fmt::Debug for Sub {
@@ -88,13 +131,94 @@ impl Sub {
             apache_kafka_config: akc,
             group_id,
             consumers: Arc::new(Mutex::new(HashMap::new())),
+            uncommitted: Arc::new(Mutex::new(HashMap::new())),
+            offsets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn take_uncommitted(&self, handle: &str) -> Result<Uncommitted> {
+        self.uncommitted
+            .lock()
+            .unwrap()
+            .remove(handle)
+            .with_context(|| "failed to get uncommitted message from handle")
+    }
+
+    /// Records that a subscription received the messages at `positions`, which
+    /// are left outstanding until they are acked, unless `acked` already. Returns
+    /// the offsets that are now safe to commit.
+    fn received(
+        &self,
+        sub_tok: &str,
+        positions: &[&MessagePosition],
+        acked: bool,
+    ) -> Vec<(String, i32, i64)> {
+        let mut offsets = self.offsets.lock().unwrap();
+        let partitions = offsets.entry(sub_tok.to_string()).or_default();
+        let mut committable = HashMap::new();
+        for position in positions {
+            let key = (position.topic.clone(), position.partition);
+            let partition = partitions.entry(key.clone()).or_default();
+            partition.next = partition.next.max(position.offset + 1);
+            if !acked {
+                partition.outstanding.insert(position.offset);
+            }
+            committable.insert(key, partition.committable());
+        }
+        committable
+            .into_iter()
+            .map(|((topic, partition), offset)| (topic, partition, offset))
+            .collect()
+    }
+
+    /// Records that the message at `position` was acked, and returns the offset
+    /// that is now safe to commit for its' partition.
+    fn acked(&self, sub_tok: &str, position: &MessagePosition) -> Option<i64> {
+        let mut offsets = self.offsets.lock().unwrap();
+        let partition = offsets
+            .get_mut(sub_tok)?
+            .get_mut(&(position.topic.clone(), position.partition))?;
+        partition.outstanding.remove(&position.offset);
+        Some(partition.committable())
+    }
+
+    /// Records that the consumer was sought back to the message at `position`,
+    /// so it (and every message after it in the partition) will be received again.
+    fn rewound(&self, sub_tok: &str, position: &MessagePosition) {
+        let mut offsets = self.offsets.lock().unwrap();
+        if let Some(partition) = offsets
+            .get_mut(sub_tok)
+            .and_then(|p| p.get_mut(&(position.topic.clone(), position.partition)))
+        {
+            partition
+                .outstanding
+                .retain(|offset| *offset < position.offset);
+            partition.next = position.offset;
         }
     }
 }
 
 #[async_trait]
 impl SubImplementor for Sub {
-    async fn subscribe(&self, topic: &str) -> Result<String> {
+    /// Consumers w/ the same `group.id` split a topic's partitions among them, so
+    /// the `group` is used as the `group.id`, if any. W/o a group, the consumer
+    /// gets a `group.id` of its' own (i.e., `CAK_GROUP_ID` suffixed w/ the
+    /// subscription token), so that it gets all of the topic's messages.
+    /// Kafka subscribes to the topics matching a regular expression, if the topic
+    /// starts w/ a `^`, so a `topic` w/ wildcards is translated into one.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        wildcard::validate(topic, DEFAULT_SEPARATOR)?;
+        let topic = &if wildcard::has_wildcards(topic, DEFAULT_SEPARATOR) {
+            wildcard::to_regex(topic, DEFAULT_SEPARATOR)?
+        } else {
+            topic.to_string()
+        };
+        // generate uuid for subscription
+        let sub_tok = uuid::Uuid::new_v4().to_string();
+        let group_id = match group {
+            Some(group) => group.to_string(),
+            None => format!("{}-{sub_tok}", self.group_id),
+        };
         let consumer: StreamConsumer = ClientConfig::new()
             .set(
                 "bootstrap.servers",
@@ -107,7 +231,9 @@ impl SubImplementor for Sub {
             .set("sasl.mechanisms", &self.apache_kafka_config.sasl_mechanisms)
             .set("sasl.username", &self.apache_kafka_config.sasl_username)
             .set("sasl.password", &self.apache_kafka_config.sasl_password)
-            .set("group.id", &self.group_id)
+            .set("group.id", &group_id)
+            // offsets are committed per message, as they are received (or acked)
+            .set("enable.auto.commit", "false")
             .create()
             .with_context(|| "failed to create consumer client")
             .unwrap(); // panic if we fail to create client
@@ -115,9 +241,6 @@ impl SubImplementor for Sub {
         confluent::subscribe(&consumer, vec![topic])
             .with_context(|| "failed to subscribe to topic")?;
 
-        // generate uuid for subscription
-        let sub_tok = uuid::Uuid::new_v4().to_string();
-
         self.consumers
             .lock()
             .unwrap()
@@ -126,20 +249,203 @@ impl SubImplementor for Sub {
         Ok(sub_tok)
     }
 
-    async fn receive(&self, sub_tok: &str) -> Result<Vec<u8>> {
-        block_in_place(|| {
-            Handle::current().block_on(async move {
-                let consumers_lock = self.consumers.lock().unwrap();
+    /// The consumer leaves its' group, so the topic's partitions are split among
+    /// the remaining members.
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        let consumer = self
+            .consumers
+            .lock()
+            .unwrap()
+            .remove(sub_tok)
+            .with_context(|| "failed to get consumer from subscription token")?;
+        confluent::unsubscribe(&consumer);
+        self.offsets.lock().unwrap().remove(sub_tok);
+        Ok(())
+    }
+
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        let accessed_consumer = self
+            .consumers
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .cloned()
+            .with_context(|| "failed to get consumer from subscription token")?;
+
+        let msg = block_in_place(|| {
+            Handle::current().block_on(async {
+                tokio::time::timeout(timeout, confluent::receive_uncommitted(&accessed_consumer))
+                    .await
+            })
+        });
+        let msg = match msg {
+            Ok(msg) => msg.with_context(|| "failed to poll for message")?,
+            Err(_) => return Ok(None),
+        };
+        confluent::commit(
+            &accessed_consumer,
+            &self.received(sub_tok, &[&msg.position], true),
+        )
+        .with_context(|| "failed to commit message")?;
 
-                let accessed_consumer = consumers_lock
-                    .get(sub_tok)
-                    .with_context(|| "failed to get consumer from subscription token")?;
+        Ok(Some(to_received_message(String::new(), msg)))
+    }
+
+    /// The messages already fetched by the consumer are taken w/o waiting, and
+    /// their offsets are committed in one go.
+    async fn receive_batch(
+        &self,
+        sub_tok: &str,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<ReceivedMessage>> {
+        let accessed_consumer = self
+            .consumers
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .cloned()
+            .with_context(|| "failed to get consumer from subscription token")?;
 
-                confluent::receive(accessed_consumer)
+        let msgs = block_in_place(|| {
+            Handle::current().block_on(async {
+                let mut msgs = Vec::new();
+                let mut timeout = timeout;
+                while msgs.len() < max {
+                    match tokio::time::timeout(
+                        timeout,
+                        confluent::receive_uncommitted(&accessed_consumer),
+                    )
                     .await
-                    .with_context(|| "failed to poll for message")
+                    {
+                        Ok(Ok(msg)) => msgs.push(msg),
+                        // the messages polled so far would be lost otherwise, as
+                        // they're committed below
+                        Ok(Err(e)) if !msgs.is_empty() => {
+                            tracing::warn!(
+                                "failed to poll for message, so returning the {} polled so far: {e:?}",
+                                msgs.len()
+                            );
+                            break;
+                        }
+                        Ok(Err(e)) => return Err(e.context("failed to poll for message")),
+                        Err(_) => break,
+                    }
+                    timeout = Duration::ZERO;
+                }
+                Ok::<_, anyhow::Error>(msgs)
             })
-        })
+        })?;
+        if msgs.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let positions: Vec<&MessagePosition> = msgs.iter().map(|m| &m.position).collect();
+        confluent::commit(
+            &accessed_consumer,
+            &self.received(sub_tok, &positions, true),
+        )
+        .with_context(|| "failed to commit batch of messages")?;
+
+        Ok(msgs
+            .into_iter()
+            .map(|msg| to_received_message(String::new(), msg))
+            .collect())
+    }
+
+    fn supports_acknowledgement(&self) -> bool {
+        true
+    }
+
+    /// Kafka only keeps track of a single committed offset per partition, and
+    /// committing an offset acks every message before it. So, the offsets of the
+    /// messages received here are tracked until they are acked, and only the
+    /// offset of the lowest un-acked message in the partition is ever committed.
+    /// That is, acking a message that was received after one that is yet to be
+    /// acked doesn't move the committed offset, and both are redelivered if the
+    /// consumer restarts (or the partition is reassigned) before that one is acked.
+    ///
+    /// A nack seeks the consumer back to the message, so every message after it
+    /// in the partition is redelivered too. Kafka has no per-message visibility,
+    /// so the `visibility_timeout` is ignored.
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        if visibility_timeout.is_some() {
+            tracing::debug!("ignoring visibility timeout, as kafka has no per-message visibility");
+        }
+
+        let accessed_consumer = self
+            .consumers
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .cloned()
+            .with_context(|| "failed to get consumer from subscription token")?;
+
+        let msg = confluent::receive_uncommitted(&accessed_consumer)
+            .await
+            .with_context(|| "failed to poll for message")?;
+
+        self.received(sub_tok, &[&msg.position], false);
+        let handle = uuid::Uuid::new_v4().to_string();
+        self.uncommitted.lock().unwrap().insert(
+            handle.clone(),
+            (sub_tok.to_string(), accessed_consumer, msg.position.clone()),
+        );
+
+        Ok(Some(to_received_message(handle, msg)))
+    }
+
+    async fn ack(&self, handle: &str) -> Result<()> {
+        let (sub_tok, consumer, position) = self.take_uncommitted(handle)?;
+        match self.acked(&sub_tok, &position) {
+            Some(offset) => confluent::commit(
+                &consumer,
+                &[(position.topic.clone(), position.partition, offset)],
+            )
+            .with_context(|| "failed to commit message"),
+            // the subscription is gone
+            None => Ok(()),
+        }
+    }
+
+    async fn nack(&self, handle: &str) -> Result<()> {
+        let (sub_tok, consumer, position) = self.take_uncommitted(handle)?;
+        confluent::rewind(&consumer, &position)
+            .with_context(|| "failed to seek back to message")?;
+        self.rewound(&sub_tok, &position);
+        Ok(())
+    }
+}
+
+/// A random key for a message, so that messages are spread across partitions.
+fn message_key() -> String {
+    format!(
+        "{:?}",
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    )
+}
+
+fn to_received_message(handle: String, msg: UncommittedMessage) -> ReceivedMessage {
+    // kafka has no message ids, or delivery counts, but a message's position
+    // identifies it (incl. when it's redelivered)
+    let position = msg.position;
+    ReceivedMessage {
+        handle,
+        payload: msg.payload,
+        headers: msg.headers,
+        system_properties: SystemProperties {
+            message_id: Some(format!(
+                "{}/{}/{}",
+                position.topic, position.partition, position.offset
+            )),
+            timestamp: msg.timestamp.map(|t| t as u64),
+            delivery_count: None,
+        },
+        topic: position.topic,
     }
 }
 
@@ -1,24 +1,40 @@
 use async_trait::async_trait;
-use azure_messaging_servicebus::service_bus::SubscriptionReceiver;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use tokio::runtime::Handle;
-use tokio::task::block_in_place;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use azure_messaging_servicebus::prelude::TopicClient;
 use slight_common::BasicState;
 use slight_runtime_configs::get_from_state;
 
-use super::{PubImplementor, SubImplementor};
+use crate::providers::servicebus::{self, ServiceBusMessage};
+use crate::wildcard::{self, DEFAULT_SEPARATOR};
 
+use super::{PubImplementor, ReceivedMessage, SubImplementor, SystemProperties};
+
+/// How long a peek-lock waits for a message to arrive.
+const PEEK_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The service bus client only gets a message's body, so messages are sent and
+/// received through service bus' rest api instead, which also carries their
+/// custom properties and broker properties.
 #[derive(Clone)]
 pub struct AzSbusImplementor {
     service_bus_namespace: String,
     policy_name: String,
     policy_key: String,
-    http_client: Arc<dyn azure_core::HttpClient>,
-    subscription_tokens: Arc<Mutex<HashMap<String, SubscriptionReceiver>>>,
+    http_client: reqwest::Client,
+    subscription_tokens: Arc<Mutex<HashMap<String, AzSbusSubscription>>>,
+    // the location of each peek-locked message, by its' handle
+    locked_messages: Arc<Mutex<HashMap<String, String>>>,
+}
+
+/// The topic a subscription receives from, and the service bus subscription
+/// it receives through.
+#[derive(Clone)]
+struct AzSbusSubscription {
+    topic: String,
+    subscription: String,
 }
 
 impl std::fmt::Debug for AzSbusImplementor {
@@ -39,73 +55,231 @@ impl AzSbusImplementor {
             .await
             .unwrap();
 
-        let http_client = azure_core::new_http_client();
-
         Self {
             service_bus_namespace,
             policy_name,
             policy_key,
-            http_client,
+            http_client: reqwest::Client::new(),
             subscription_tokens: Arc::new(Mutex::new(HashMap::new())),
+            locked_messages: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    fn make_topic_client(&self, topic: &str) -> TopicClient {
-        TopicClient::new(
-            self.http_client.clone(),
+    fn get_subscription(&self, sub_tok: &str) -> Result<AzSbusSubscription> {
+        self.subscription_tokens
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .cloned()
+            .with_context(|| "failed to get consumer from subscription token")
+    }
+
+    fn take_locked_message(&self, handle: &str) -> Result<String> {
+        self.locked_messages
+            .lock()
+            .unwrap()
+            .remove(handle)
+            .with_context(|| "failed to get locked message from handle")
+    }
+}
+
+/// Converts a message received from service bus, w/ its' custom properties as
+/// headers.
+fn to_received_message(topic: String, handle: String, msg: ServiceBusMessage) -> ReceivedMessage {
+    ReceivedMessage {
+        handle,
+        topic,
+        payload: msg.body,
+        headers: msg.properties,
+        system_properties: SystemProperties {
+            message_id: msg.message_id,
+            timestamp: msg.enqueued_time,
+            delivery_count: msg.delivery_count,
+        },
+    }
+}
+
+#[async_trait]
+impl PubImplementor for AzSbusImplementor {
+    /// The `headers` are sent as the message's custom properties, so their names
+    /// must be valid http header names (and are received lowercased).
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        servicebus::send(
+            &self.http_client,
             &self.service_bus_namespace,
             topic,
             &self.policy_name,
             &self.policy_key,
+            msg,
+            headers,
         )
-        .unwrap()
+        .await
     }
-}
-
-#[async_trait]
-impl PubImplementor for AzSbusImplementor {
-    async fn publish(&self, msg: &[u8], topic: &str) -> Result<()> {
-        let topic_client = self.make_topic_client(topic);
 
-        topic_client
-            .topic_sender()
-            .send_message(std::str::from_utf8(msg).unwrap())
-            .await?;
+    /// The batch is sent in a single request, so either all of the messages are
+    /// published, or none are.
+    async fn publish_batch(&self, msgs: &[&[u8]], topic: &str) -> Result<()> {
+        let msgs = msgs
+            .iter()
+            .map(|msg| std::str::from_utf8(msg))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| "service bus messages must be valid utf-8")?;
 
-        Ok(())
+        servicebus::send_batch(
+            &self.http_client,
+            &self.service_bus_namespace,
+            topic,
+            &self.policy_name,
+            &self.policy_key,
+            &msgs,
+        )
+        .await
     }
 }
 
 #[async_trait]
 impl SubImplementor for AzSbusImplementor {
-    async fn subscribe(&self, topic: &str) -> Result<String> {
+    /// Receivers of the same service bus subscription compete for its' messages,
+    /// so the `group` names the (existing) subscription to receive from. W/o a
+    /// group, the subscription named after the topic is used, so, unlike w/ other
+    /// implementors, subscriptions w/o a group compete for its' messages too
+    /// (i.e., to each get all of them, give each one a subscription of its' own).
+    /// Service bus subscriptions belong to a single topic, so wildcards aren't
+    /// supported.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        wildcard::reject(topic, DEFAULT_SEPARATOR, "azure service bus")?;
         let sub_tok = uuid::Uuid::new_v4().to_string();
 
-        let topic_client = self.make_topic_client(topic);
+        self.subscription_tokens.lock().unwrap().insert(
+            sub_tok.clone(),
+            AzSbusSubscription {
+                topic: topic.to_string(),
+                subscription: group.unwrap_or(topic).to_string(),
+            },
+        );
 
-        let receiver = topic_client.subscription_receiver(topic);
+        Ok(sub_tok)
+    }
 
+    /// Service bus subscriptions are managed outside of slight, so only the
+    /// receiver goes away.
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
         self.subscription_tokens
             .lock()
             .unwrap()
-            .insert(sub_tok.clone(), receiver);
+            .remove(sub_tok)
+            .with_context(|| "failed to get consumer from subscription token")?;
+        Ok(())
+    }
 
-        Ok(sub_tok)
+    /// The message is peek-locked, and then deleted, so that it isn't lost if
+    /// the request times out, or receiving fails, midway — it's redelivered once
+    /// its' lock expires instead. Service bus waits for messages in whole
+    /// seconds, so the `timeout` is rounded up to a whole number of them.
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        let timeout =
+            Duration::from_secs(timeout.as_secs() + u64::from(timeout.subsec_nanos() > 0));
+        let sub = self.get_subscription(sub_tok)?;
+
+        let msg = servicebus::peek_lock(
+            &self.http_client,
+            &self.service_bus_namespace,
+            &sub.topic,
+            &sub.subscription,
+            &self.policy_name,
+            &self.policy_key,
+            timeout,
+        )
+        .await?;
+        let mut msg = match msg {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+
+        let location = msg
+            .location
+            .take()
+            .with_context(|| "service bus didn't respond w/ the locked message's location")?;
+        servicebus::delete(
+            &self.http_client,
+            &location,
+            &self.policy_name,
+            &self.policy_key,
+        )
+        .await?;
+
+        Ok(Some(to_received_message(sub.topic, String::new(), msg)))
+    }
+
+    fn supports_acknowledgement(&self) -> bool {
+        true
     }
 
-    async fn receive(&self, sub_tok: &str) -> Result<Vec<u8>> {
-        block_in_place(|| {
-            Handle::current().block_on(async move {
-                let sub_toks = self.subscription_tokens.lock().unwrap();
+    /// Messages are received w/ peek-lock, which hides them from other receivers
+    /// until they are deleted (acked), unlocked (nacked), or their lock expires.
+    /// How long the lock lasts is set on the subscription itself, so the
+    /// `visibility_timeout` is ignored.
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        if visibility_timeout.is_some() {
+            tracing::debug!(
+                "ignoring visibility timeout in favour of the subscription's lock duration"
+            );
+        }
+        let sub = self.get_subscription(sub_tok)?;
+
+        let msg = servicebus::peek_lock(
+            &self.http_client,
+            &self.service_bus_namespace,
+            &sub.topic,
+            &sub.subscription,
+            &self.policy_name,
+            &self.policy_key,
+            PEEK_LOCK_TIMEOUT,
+        )
+        .await?;
+        let mut msg = match msg {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+
+        let handle = uuid::Uuid::new_v4().to_string();
+        let location = msg
+            .location
+            .take()
+            .with_context(|| "service bus didn't respond w/ the locked message's location")?;
+        self.locked_messages
+            .lock()
+            .unwrap()
+            .insert(handle.clone(), location);
 
-                let accessed_consumer = sub_toks
-                    .get(sub_tok)
-                    .with_context(|| "failed to get consumer from subscription token")?;
+        Ok(Some(to_received_message(sub.topic, handle, msg)))
+    }
 
-                let msg = accessed_consumer.receive_and_delete_message().await?;
+    async fn ack(&self, handle: &str) -> Result<()> {
+        let location = self.take_locked_message(handle)?;
+        servicebus::delete(
+            &self.http_client,
+            &location,
+            &self.policy_name,
+            &self.policy_key,
+        )
+        .await
+        .with_context(|| "failed to complete message")
+    }
 
-                Ok(msg.as_bytes().to_vec())
-            })
-        })
+    async fn nack(&self, handle: &str) -> Result<()> {
+        let location = self.take_locked_message(handle)?;
+        servicebus::unlock(
+            &self.http_client,
+            &location,
+            &self.policy_name,
+            &self.policy_key,
+        )
+        .await
+        .with_context(|| "failed to abandon message")
     }
 }
@@ -1,10 +1,18 @@
-use crate::providers::fs::Pubsub;
-use anyhow::Result;
+use std::time::Duration;
+
+use crate::providers::fs::{Delivery, Pubsub};
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use slight_common::BasicState;
+use slight_runtime_configs::filesystem_root_from_state;
+use tokio::time::{sleep, Instant};
 
 use crate::PubImplementor;
 
-use super::SubImplementor;
+use super::{ReceivedMessage, SubImplementor, SystemProperties};
+
+/// How often a receive w/ a timeout checks for new messages.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
 
 /// This is the underlying struct behind the `Filesystem` variant of the implementors enum.
 #[derive(Debug, Clone)]
@@ -13,27 +21,93 @@ pub struct FilesystemImplementor {
 }
 
 impl FilesystemImplementor {
-    pub fn new(name: &str) -> Self {
-        Self {
-            pubsub: Pubsub::open(name).unwrap(),
-        }
+    /// Creates a new `FilesystemImplementor` instance.
+    ///
+    /// The broker lives under `<root>/<name>`, where `<root>` comes from the
+    /// optional `FILESYSTEM_ROOT` config (see `filesystem_root_from_state`).
+    pub async fn new(slight_state: &BasicState, name: &str) -> Result<Self> {
+        let root = filesystem_root_from_state(slight_state).await?;
+        let pubsub = Pubsub::open(&root, name).with_context(|| {
+            format!(
+                "failed to open filesystem broker '{name}' under '{}'",
+                root.display()
+            )
+        })?;
+        Ok(Self { pubsub })
     }
 }
 
 #[async_trait]
 impl PubImplementor for FilesystemImplementor {
-    async fn publish(&self, msg: &[u8], topic: &str) -> Result<()> {
-        self.pubsub.publish(msg, topic)
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        self.pubsub.publish(msg, topic, headers)
     }
 }
 
 #[async_trait]
 impl SubImplementor for FilesystemImplementor {
-    async fn subscribe(&self, topic: &str) -> Result<String> {
-        self.pubsub.subscribe(topic)
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        self.pubsub.subscribe(topic, group)
+    }
+
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        self.pubsub.unsubscribe(sub_tok)
     }
 
-    async fn receive(&self, sub_tok: &str) -> Result<Vec<u8>> {
-        self.pubsub.receive(sub_tok)
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(delivery) = self.pubsub.receive(sub_tok)? {
+                return Ok(Some(to_received_message(delivery)));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            sleep(POLL_INTERVAL.min(deadline - now)).await;
+        }
+    }
+
+    fn supports_acknowledgement(&self) -> bool {
+        true
+    }
+
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        Ok(self
+            .pubsub
+            .receive_message(sub_tok, visibility_timeout)?
+            .map(to_received_message))
+    }
+
+    async fn ack(&self, handle: &str) -> Result<()> {
+        self.pubsub.ack(handle)
+    }
+
+    async fn nack(&self, handle: &str) -> Result<()> {
+        self.pubsub.nack(handle)
+    }
+}
+
+fn to_received_message(delivery: Delivery) -> ReceivedMessage {
+    let Delivery {
+        handle,
+        message,
+        delivery_count,
+    } = delivery;
+    ReceivedMessage {
+        handle,
+        topic: message.topic,
+        payload: message.payload,
+        headers: message.headers,
+        system_properties: SystemProperties {
+            // a message's offset is unique within its' topic
+            message_id: Some(message.offset.to_string()),
+            timestamp: Some(message.published_at),
+            delivery_count: Some(delivery_count),
+        },
     }
 }
@@ -1,6 +1,10 @@
-use anyhow::Result;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
 use async_trait::async_trait;
 
+#[cfg(feature = "amqp")]
+pub mod amqp;
 #[cfg(feature = "apache_kafka")]
 pub mod apache_kafka;
 #[cfg(feature = "azsbus")]
@@ -11,10 +15,24 @@ pub mod filesystem;
 pub mod mosquitto;
 #[cfg(feature = "natsio")]
 pub mod natsio;
+#[cfg(feature = "redis")]
+pub mod redis;
 
 #[async_trait]
 pub trait PubImplementor {
-    async fn publish(&self, msg: &[u8], topic: &str) -> Result<()>;
+    /// Publishes `msg` to `topic`, along w/ its' `headers` (i.e., key-value pairs
+    /// like a correlation id, content type, or trace context). Implementors that
+    /// can't send headers fail if there are any.
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()>;
+
+    /// Publishes `msgs` to `topic` in one go. Implementors w/o a way to batch
+    /// messages publish them one after the other, stopping at the first failure.
+    async fn publish_batch(&self, msgs: &[&[u8]], topic: &str) -> Result<()> {
+        for msg in msgs {
+            self.publish(msg, topic, &[]).await?;
+        }
+        Ok(())
+    }
 }
 
 impl std::fmt::Debug for dyn PubImplementor + Send + Sync {
@@ -23,10 +41,97 @@ impl std::fmt::Debug for dyn PubImplementor + Send + Sync {
     }
 }
 
+/// A message received w/ `SubImplementor::receive_message`, the handle to
+/// acknowledge it with, and its' metadata.
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    pub handle: String,
+    /// The topic the message was published to, which, for a subscription w/
+    /// wildcards, is whichever one of its' topics the message came from.
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+    pub system_properties: SystemProperties,
+}
+
+/// Properties set by the messaging system on a received message. Each one is
+/// `None` if the implementor's backend doesn't provide it.
+#[derive(Debug, Clone, Default)]
+pub struct SystemProperties {
+    pub message_id: Option<String>,
+    /// When the message was published, in milliseconds since the unix epoch.
+    pub timestamp: Option<u64>,
+    /// How many times the message has been delivered (incl. this time).
+    pub delivery_count: Option<u32>,
+}
+
 #[async_trait]
 pub trait SubImplementor {
-    async fn subscribe(&self, topic: &str) -> Result<String>;
-    async fn receive(&self, sub_tok: &str) -> Result<Vec<u8>>;
+    /// Subscribes to `topic`. Subscriptions in the same consumer `group` compete
+    /// for the topic's messages (i.e., each message goes to only one of them),
+    /// whereas ones w/o a group each get all of them. Implementors whose backend
+    /// can't give each subscription its' own copy of the messages (i.e.,
+    /// `messaging.azsbus`) say so below, where they differ.
+    ///
+    /// The `topic` can have wildcards in it (see the `wildcard` module), which
+    /// implementors translate into their backend's own, or fail on if it has none.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String>;
+
+    /// Cancels a subscription, and lets go of whatever the implementor holds on
+    /// to for it. Its' token can't be received w/ afterwards.
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()>;
+
+    /// Receives a message, which is acknowledged straight away (so its' `handle`
+    /// is empty). Waits up to `timeout` for one to arrive — a zero `timeout`
+    /// polls w/o waiting — and returns `None` if none did.
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>>;
+
+    /// Receives up to `max` messages, which are acknowledged straight away. Waits
+    /// up to `timeout` for the first one to arrive, and then takes whichever
+    /// others are ready w/o waiting. Implementors w/o a way to batch messages
+    /// `receive` them one after the other.
+    async fn receive_batch(
+        &self,
+        sub_tok: &str,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<ReceivedMessage>> {
+        let mut msgs = Vec::new();
+        let mut timeout = timeout;
+        while msgs.len() < max {
+            match self.receive(sub_tok, timeout).await? {
+                Some(msg) => msgs.push(msg),
+                None => break,
+            }
+            timeout = Duration::ZERO;
+        }
+        Ok(msgs)
+    }
+
+    /// Whether messages can be received w/ `receive_message`, and `ack`ed or
+    /// `nack`ed. Implementors that override those should say so here too.
+    fn supports_acknowledgement(&self) -> bool {
+        false
+    }
+
+    /// Receives a message that is redelivered unless it is `ack`ed. Implementors
+    /// that can't hide a message for a specific `visibility_timeout` fall back to
+    /// their backend's own redelivery policy.
+    async fn receive_message(
+        &self,
+        _sub_tok: &str,
+        _visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        bail!("acknowledging messages is not supported by this messaging implementor")
+    }
+
+    async fn ack(&self, _handle: &str) -> Result<()> {
+        bail!("acknowledging messages is not supported by this messaging implementor")
+    }
+
+    async fn nack(&self, _handle: &str) -> Result<()> {
+        bail!("acknowledging messages is not supported by this messaging implementor")
+    }
 }
 
 impl std::fmt::Debug for dyn SubImplementor + Send + Sync {
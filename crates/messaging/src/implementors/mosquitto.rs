@@ -2,16 +2,22 @@ use async_trait::async_trait;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use async_channel::Receiver;
 use mosquitto_rs::{Client, Message, QoS};
 use slight_common::BasicState;
 use slight_runtime_configs::get_from_state;
 use tokio::{runtime::Handle, task::block_in_place};
 
-use super::{PubImplementor, SubImplementor};
+use crate::wildcard;
+
+use super::{PubImplementor, ReceivedMessage, SubImplementor};
+
+/// What separates an mqtt topic's levels.
+const TOPIC_SEPARATOR: char = '/';
 
 #[derive(Clone)]
 pub struct Pub {
@@ -75,7 +81,12 @@ impl Pub {
 
 #[async_trait]
 impl PubImplementor for Pub {
-    async fn publish(&self, msg_value: &[u8], topic: &str) -> Result<()> {
+    async fn publish(&self, msg_value: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        // mqtt only supports user properties from v5 on
+        if !headers.is_empty() {
+            bail!("message headers are not supported by the mosquitto implementor");
+        }
+
         block_in_place(|| {
             Handle::current().block_on(async move {
                 self.producer
@@ -114,7 +125,17 @@ impl Sub {
 
 #[async_trait]
 impl SubImplementor for Sub {
-    async fn subscribe(&self, topic: &str) -> Result<String> {
+    /// Subscriptions in a consumer `group` are MQTT shared subscriptions (i.e.,
+    /// to `$share/<group>/<topic>`), which the broker round-robins messages across.
+    /// MQTT topics' levels are separated by a `/` (rather than a `.`), and its'
+    /// wildcards are `+`, and `#` (which also matches the level before it, so `>`
+    /// becomes `+/#`).
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        let topic = wildcard::translate(topic, TOPIC_SEPARATOR, "+", "#")?;
+        let topic = &match group {
+            Some(group) => format!("$share/{group}/{topic}"),
+            None => topic.to_string(),
+        };
         let new_consumer = block_in_place(|| {
             Handle::current().block_on(async move {
                 let mut client = Client::with_auto_id().unwrap();
@@ -151,29 +172,51 @@ impl SubImplementor for Sub {
         Ok(k)
     }
 
-    async fn receive(&self, sub_tok: &str) -> Result<Vec<u8>> {
-        let mut res: Vec<u8> = vec![];
+    /// Each subscription has its' own client, which disconnects once dropped.
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        self.consumers
+            .lock()
+            .unwrap()
+            .remove(sub_tok)
+            .with_context(|| "failed to get consumer from subscription token")?;
+        Ok(())
+    }
+
+    /// Mqtt (before v5) carries no headers, message ids, timestamps, or delivery
+    /// counts, so only the payload is received.
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        let subscriptions = self
+            .consumers
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .with_context(|| "failed to get consumer from subscription token")?
+            .subscriptions
+            .clone();
+        let receiver = subscriptions
+            .lock()
+            .unwrap()
+            .clone()
+            .with_context(|| "failed to get receiver from subscription")?;
 
         block_in_place(|| {
-            res = Handle::current().block_on(async move {
-                self.consumers
-                    .lock()
-                    .unwrap()
-                    .get(sub_tok)
-                    .with_context(|| "failed to get consumer from subscription token")
-                    .unwrap()
-                    .subscriptions
-                    .lock()
-                    .unwrap()
-                    .as_mut()
-                    .unwrap()
-                    .recv()
-                    .await
-                    .unwrap()
-                    .payload
+            Handle::current().block_on(async move {
+                // the channel is checked before the timeout is, so a zero timeout
+                // still gets a message that has already arrived
+                match tokio::time::timeout(timeout, receiver.recv()).await {
+                    Ok(message) => {
+                        let message = message?;
+                        Ok(Some(ReceivedMessage {
+                            handle: String::new(),
+                            topic: message.topic,
+                            payload: message.payload,
+                            headers: Vec::new(),
+                            system_properties: Default::default(),
+                        }))
+                    }
+                    Err(_) => Ok(None),
+                }
             })
-        });
-
-        Ok(res)
+        })
     }
 }
@@ -1,21 +1,73 @@
 use async_trait::async_trait;
-use nats::{Connection, Subscription};
+use nats::jetstream::{AckKind, JetStream, PushSubscription};
+use nats::{header::HeaderMap, Connection, Message, Subscription};
 use std::collections::HashMap;
+use std::io::ErrorKind;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::runtime::Handle;
 use tokio::task::block_in_place;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use slight_common::BasicState;
-use slight_runtime_configs::get_from_state;
+use slight_runtime_configs::{get_from_state, maybe_get_from_state};
 
-use super::{PubImplementor, SubImplementor};
+use crate::wildcard::{self, DEFAULT_SEPARATOR};
 
-#[derive(Clone, Debug)]
+use super::{PubImplementor, ReceivedMessage, SubImplementor, SystemProperties};
+
+/// How long `receive_message` waits for a message to arrive.
+const RECEIVE_MESSAGE_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
 pub struct NatsIoImplementor {
     connection: Connection,
-    subscription_tokens: Arc<Mutex<HashMap<String, Subscription>>>,
+    jetstream: Option<JetStream>,
+    subscription_tokens: Arc<Mutex<HashMap<String, NatsSubscription>>>,
+    unacked_messages: Arc<Mutex<HashMap<String, Message>>>,
+}
+
+impl std::fmt::Debug for NatsIoImplementor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NatsIoImplementor")
+    }
+}
+
+/// Core NATS subscriptions are fire-and-forget, while JetStream ones must be
+/// acknowledged (or are redelivered once the consumer's ack wait passes).
+enum NatsSubscription {
+    Core(Subscription),
+    JetStream(PushSubscription),
+}
+
+impl NatsSubscription {
+    /// Waits up to `timeout` for the next message, or returns `None` if none arrived.
+    fn next_timeout(&self, timeout: Duration) -> std::io::Result<Option<Message>> {
+        let next = match self {
+            Self::Core(sub) => sub.next_timeout(timeout),
+            Self::JetStream(sub) => sub.next_timeout(timeout),
+        };
+        match next {
+            Ok(msg) => Ok(Some(msg)),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Takes the next message if one has already arrived, w/o waiting.
+    fn try_next(&self) -> Option<Message> {
+        match self {
+            Self::Core(sub) => sub.try_next(),
+            Self::JetStream(sub) => sub.try_next(),
+        }
+    }
+
+    fn unsubscribe(self) -> std::io::Result<()> {
+        match self {
+            Self::Core(sub) => sub.unsubscribe(),
+            Self::JetStream(sub) => sub.unsubscribe(),
+        }
+    }
 }
 
 impl NatsIoImplementor {
@@ -28,25 +80,87 @@ impl NatsIoImplementor {
             .unwrap();
         let subscription_tokens = Arc::new(Mutex::new(HashMap::new()));
 
+        // subscriptions only go through JetStream (and, so, can be acknowledged) if
+        // the optional `NATS_JETSTREAM` config is set
+        let jetstream = match maybe_get_from_state("NATS_JETSTREAM", slight_state)
+            .await
+            .unwrap()
+        {
+            Some(enabled) if enabled == "true" => Some(nats::jetstream::new(connection.clone())),
+            _ => None,
+        };
+
         Self {
             connection,
+            jetstream,
             subscription_tokens,
+            unacked_messages: Arc::new(Mutex::new(HashMap::new())),
         }
     }
+
+    fn take_unacked_message(&self, handle: &str) -> Result<Message> {
+        self.unacked_messages
+            .lock()
+            .unwrap()
+            .remove(handle)
+            .with_context(|| "failed to get unacknowledged message from handle")
+    }
 }
 
 #[async_trait]
 impl PubImplementor for NatsIoImplementor {
-    async fn publish(&self, msg: &[u8], topic: &str) -> Result<()> {
-        self.connection.publish(topic, msg).unwrap();
-        Ok(())
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        if headers.is_empty() {
+            self.connection.publish(topic, msg).unwrap();
+            return Ok(());
+        }
+
+        let mut header_map = HeaderMap::new();
+        for (key, value) in headers {
+            header_map.insert(*key, *value);
+        }
+        self.connection
+            .publish_with_reply_or_headers(topic, None, Some(&header_map), msg)
+            .with_context(|| "failed to publish message w/ headers")
+    }
+
+    /// Publishes are buffered by the connection, which is flushed once they're
+    /// all in, rather than per message.
+    async fn publish_batch(&self, msgs: &[&[u8]], topic: &str) -> Result<()> {
+        for msg in msgs {
+            self.connection
+                .publish(topic, msg)
+                .with_context(|| "failed to publish message")?;
+        }
+        block_in_place(|| self.connection.flush())
+            .with_context(|| "failed to flush batch of messages")
     }
 }
 
 #[async_trait]
 impl SubImplementor for NatsIoImplementor {
-    async fn subscribe(&self, topic: &str) -> Result<String> {
-        let sub = self.connection.subscribe(topic).unwrap();
+    /// Subscriptions in a consumer `group` join the NATS queue group w/ its' name.
+    /// NATS' wildcards are the same as slight's, so the `topic` is used as-is.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        wildcard::validate(topic, DEFAULT_SEPARATOR)?;
+        let sub = match (&self.jetstream, group) {
+            (Some(jetstream), None) => NatsSubscription::JetStream(
+                jetstream
+                    .subscribe(topic)
+                    .with_context(|| "failed to subscribe to topic through jetstream")?,
+            ),
+            (Some(jetstream), Some(group)) => NatsSubscription::JetStream(
+                jetstream
+                    .queue_subscribe(topic, group)
+                    .with_context(|| "failed to join queue group through jetstream")?,
+            ),
+            (None, None) => NatsSubscription::Core(self.connection.subscribe(topic).unwrap()),
+            (None, Some(group)) => NatsSubscription::Core(
+                self.connection
+                    .queue_subscribe(topic, group)
+                    .with_context(|| "failed to join queue group")?,
+            ),
+        };
 
         let sub_tok = uuid::Uuid::new_v4().to_string();
         self.subscription_tokens
@@ -57,7 +171,47 @@ impl SubImplementor for NatsIoImplementor {
         Ok(sub_tok)
     }
 
-    async fn receive(&self, sub_tok: &str) -> Result<Vec<u8>> {
+    /// JetStream subscriptions delete their (ephemeral) consumer as well.
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        let sub = self
+            .subscription_tokens
+            .lock()
+            .unwrap()
+            .remove(sub_tok)
+            .with_context(|| "failed to get consumer from subscription token")?;
+        block_in_place(|| sub.unsubscribe()).with_context(|| "failed to unsubscribe from topic")
+    }
+
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        block_in_place(|| {
+            Handle::current().block_on(async move {
+                let sub_toks = self.subscription_tokens.lock().unwrap();
+
+                let accessed_consumer = sub_toks
+                    .get(sub_tok)
+                    .with_context(|| "failed to get consumer from subscription token")?;
+
+                let msg = match accessed_consumer.next_timeout(timeout)? {
+                    Some(msg) => msg,
+                    None => return Ok(None),
+                };
+                if let NatsSubscription::JetStream(_) = accessed_consumer {
+                    msg.ack()?;
+                }
+
+                Ok(Some(to_received_message(String::new(), &msg)))
+            })
+        })
+    }
+
+    /// Messages are pushed to the subscription as they're published, so, once the
+    /// first one arrives, the rest are taken from those already pushed to it.
+    async fn receive_batch(
+        &self,
+        sub_tok: &str,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<ReceivedMessage>> {
         block_in_place(|| {
             Handle::current().block_on(async move {
                 let sub_toks = self.subscription_tokens.lock().unwrap();
@@ -66,10 +220,112 @@ impl SubImplementor for NatsIoImplementor {
                     .get(sub_tok)
                     .with_context(|| "failed to get consumer from subscription token")?;
 
-                let msg = accessed_consumer.next_timeout(Duration::from_millis(500))?;
+                let mut msgs = Vec::new();
+                if max == 0 {
+                    return Ok(msgs);
+                }
+                if let Some(msg) = accessed_consumer.next_timeout(timeout)? {
+                    msgs.push(msg);
+                    while msgs.len() < max {
+                        match accessed_consumer.try_next() {
+                            Some(msg) => msgs.push(msg),
+                            None => break,
+                        }
+                    }
+                }
+                if let NatsSubscription::JetStream(_) = accessed_consumer {
+                    for msg in &msgs {
+                        msg.ack()?;
+                    }
+                }
 
-                Ok(msg.data)
+                Ok(msgs
+                    .iter()
+                    .map(|msg| to_received_message(String::new(), msg))
+                    .collect())
             })
         })
     }
+
+    fn supports_acknowledgement(&self) -> bool {
+        self.jetstream.is_some()
+    }
+
+    /// Only JetStream subscriptions (i.e., if `NATS_JETSTREAM` is set) can be
+    /// acknowledged. How long JetStream waits for an ack is set on its' consumer,
+    /// so the `visibility_timeout` is ignored.
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        if visibility_timeout.is_some() {
+            tracing::debug!("ignoring visibility timeout in favour of the consumer's ack wait");
+        }
+
+        let msg = block_in_place(|| {
+            Handle::current().block_on(async move {
+                let sub_toks = self.subscription_tokens.lock().unwrap();
+
+                let accessed_consumer = sub_toks
+                    .get(sub_tok)
+                    .with_context(|| "failed to get consumer from subscription token")?;
+                if let NatsSubscription::Core(_) = accessed_consumer {
+                    bail!("acknowledging messages requires a jetstream subscription (i.e., setting `NATS_JETSTREAM` to \"true\")");
+                }
+
+                Ok(accessed_consumer.next_timeout(RECEIVE_MESSAGE_TIMEOUT)?)
+            })
+        })?;
+        let msg = match msg {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+
+        let handle = uuid::Uuid::new_v4().to_string();
+        let received = to_received_message(handle.clone(), &msg);
+        self.unacked_messages.lock().unwrap().insert(handle, msg);
+
+        Ok(Some(received))
+    }
+
+    async fn ack(&self, handle: &str) -> Result<()> {
+        self.take_unacked_message(handle)?
+            .ack()
+            .with_context(|| "failed to ack message")
+    }
+
+    async fn nack(&self, handle: &str) -> Result<()> {
+        self.take_unacked_message(handle)?
+            .ack_kind(AckKind::Nak)
+            .with_context(|| "failed to nak message")
+    }
+}
+
+fn to_received_message(handle: String, msg: &Message) -> ReceivedMessage {
+    let mut headers = Vec::new();
+    if let Some(header_map) = &msg.headers {
+        for (key, values) in header_map.iter() {
+            for value in values {
+                headers.push((key.to_string(), value.to_string()));
+            }
+        }
+    }
+    let system_properties = match msg.jetstream_message_info() {
+        Some(info) => SystemProperties {
+            // a message's sequence number is unique within its' stream
+            message_id: Some(info.stream_seq.to_string()),
+            timestamp: Some((info.published.unix_timestamp_nanos() / 1_000_000) as u64),
+            delivery_count: Some(info.delivered as u32),
+        },
+        None => SystemProperties::default(),
+    };
+
+    ReceivedMessage {
+        handle,
+        topic: msg.subject.clone(),
+        payload: msg.data.clone(),
+        headers,
+        system_properties,
+    }
 }
@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use redis::{
+    streams::{
+        StreamClaimOptions, StreamClaimReply, StreamId, StreamMaxlen, StreamPendingCountReply,
+        StreamRangeReply, StreamReadOptions, StreamReadReply,
+    },
+    Client, Commands, Connection, Value,
+};
+use slight_common::BasicState;
+use slight_runtime_configs::{get_from_state, maybe_get_from_state};
+use tokio::task::block_in_place;
+
+use crate::wildcard::{self, DEFAULT_SEPARATOR};
+
+use super::{PubImplementor, ReceivedMessage, SubImplementor, SystemProperties};
+
+/// The stream entry field a message's payload is stored under.
+const PAYLOAD_FIELD: &str = "payload";
+/// The prefix of the stream entry fields a message's headers are stored under.
+const HEADER_FIELD_PREFIX: &str = "header:";
+/// How long a message received w/o a visibility timeout stays pending before
+/// it is redelivered.
+const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a nacked message is made to look pending for, so that it is
+/// redelivered regardless of the visibility timeout it is next received w/.
+const NACKED_IDLE_TIME: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// This is the underlying struct behind the `Redis` variant of the `MessagingImplementors` enum.
+///
+/// Topics are Redis Streams, which are trimmed to about `REDIS_STREAM_MAX_LEN`
+/// messages, if it is set, as messages are published (i.e., w/ `MAXLEN ~`).
+/// A subscription in a consumer group reads its' stream through that group, and
+/// is the consumer named after its' subscription token. A subscription w/o a
+/// group reads the stream by itself, and keeps track of where it's at (and of
+/// the messages waiting to be acked) in memory, so that it leaves nothing
+/// behind in redis once it's gone.
+///
+/// As per its' usage in `PubInner` and `SubInner`, it must `derive` `Debug`, and `Clone`.
+#[derive(Debug, Clone)]
+pub struct RedisImplementor {
+    client: Client,
+    stream_max_len: Option<usize>,
+    subscriptions: Arc<Mutex<HashMap<String, RedisSubscription>>>,
+}
+
+#[derive(Debug, Clone)]
+struct RedisSubscription {
+    topic: String,
+    /// The consumer group the subscription reads through, if it was given one.
+    group: Option<String>,
+    /// Where a subscription w/o a group is at in its' stream.
+    reader: Arc<Mutex<StreamReader>>,
+}
+
+/// Where a subscription w/o a consumer group is at in its' stream.
+#[derive(Debug, Default)]
+struct StreamReader {
+    /// The id of the last entry read.
+    last_id: String,
+    /// The messages received w/ `receive_message` that are waiting to be acked,
+    /// per stream entry id.
+    pending: HashMap<String, PendingEntry>,
+}
+
+#[derive(Debug)]
+struct PendingEntry {
+    entry: StreamId,
+    delivery_count: u32,
+    /// When the message is redelivered, unless it is acked before then.
+    redeliver_at: Instant,
+}
+
+impl RedisImplementor {
+    pub async fn new(slight_state: &BasicState) -> Self {
+        let connection_string = get_from_state("REDIS_ADDRESS", slight_state).await.unwrap();
+        let client = redis::Client::open(connection_string).unwrap();
+        let stream_max_len = maybe_get_from_state("REDIS_STREAM_MAX_LEN", slight_state)
+            .await
+            .unwrap()
+            .map(|max_len| {
+                max_len
+                    .parse::<usize>()
+                    .expect("REDIS_STREAM_MAX_LEN must be a positive integer")
+            });
+        Self {
+            client,
+            stream_max_len,
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn get_subscription(&self, sub_tok: &str) -> Result<RedisSubscription> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .get(sub_tok)
+            .cloned()
+            .with_context(|| "failed to get consumer group from subscription token")
+    }
+
+    /// Splits a message handle into the subscription it was received through,
+    /// and the message's stream entry id.
+    fn resolve_handle(&self, handle: &str) -> Result<(String, RedisSubscription, String)> {
+        let (sub_tok, id) = handle
+            .split_once('/')
+            .with_context(|| "failed to get stream entry id from handle")?;
+        Ok((
+            sub_tok.to_string(),
+            self.get_subscription(sub_tok)?,
+            id.to_string(),
+        ))
+    }
+}
+
+#[async_trait]
+impl PubImplementor for RedisImplementor {
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        let mut con = self.client.get_connection()?;
+        let mut fields: Vec<(String, &[u8])> = vec![(PAYLOAD_FIELD.to_string(), msg)];
+        for (key, value) in headers {
+            fields.push((format!("{HEADER_FIELD_PREFIX}{key}"), value.as_bytes()));
+        }
+        let _: String = match self.stream_max_len {
+            Some(max_len) => con.xadd_maxlen(topic, StreamMaxlen::Approx(max_len), "*", &fields),
+            None => con.xadd(topic, "*", &fields),
+        }
+        .with_context(|| "failed to add message to stream")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SubImplementor for RedisImplementor {
+    /// Consumer groups are created at the end of the stream (i.e., they only get
+    /// messages published after they were created), along w/ the stream itself
+    /// if it doesn't exist yet. A subscription w/o a group starts at the end of
+    /// the stream too. A consumer group reads from a single stream, so wildcards
+    /// aren't supported.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        wildcard::reject(topic, DEFAULT_SEPARATOR, "redis")?;
+        let sub_tok = uuid::Uuid::new_v4().to_string();
+
+        let mut con = self.client.get_connection()?;
+        let mut reader = StreamReader::default();
+        match group {
+            Some(group) => {
+                let created: redis::RedisResult<()> = con.xgroup_create_mkstream(topic, group, "$");
+                match created {
+                    Ok(()) => (),
+                    // other subscriptions have already created the group
+                    Err(e) if e.code() == Some("BUSYGROUP") => (),
+                    Err(e) => return Err(e).with_context(|| "failed to create consumer group"),
+                }
+            }
+            None => {
+                let last: StreamRangeReply = con
+                    .xrevrange_count(topic, "+", "-", 1)
+                    .with_context(|| "failed to get the end of the stream")?;
+                reader.last_id = match last.ids.first() {
+                    Some(entry) => entry.id.clone(),
+                    None => "0-0".to_string(),
+                };
+            }
+        }
+
+        self.subscriptions.lock().unwrap().insert(
+            sub_tok.clone(),
+            RedisSubscription {
+                topic: topic.to_string(),
+                group: group.map(|group| group.to_string()),
+                reader: Arc::new(Mutex::new(reader)),
+            },
+        );
+
+        Ok(sub_tok)
+    }
+
+    /// A consumer group is left as-is, so that the messages the subscription left
+    /// pending can still be redelivered to the group's other members.
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .remove(sub_tok)
+            .with_context(|| "failed to get consumer group from subscription token")?;
+        Ok(())
+    }
+
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        let sub = self.get_subscription(sub_tok)?;
+        let mut con = self.client.get_connection()?;
+
+        // a zero `BLOCK` waits forever, so polling must leave it out
+        let mut opts = StreamReadOptions::default().count(1);
+        if !timeout.is_zero() {
+            opts = opts.block(timeout.as_millis() as usize);
+        }
+        let entry = match &sub.group {
+            Some(group) => {
+                let opts = opts.group(group, sub_tok);
+                let entry = block_in_place(|| read_new_entry(&mut con, &sub.topic, ">", &opts))?;
+                if let Some(entry) = &entry {
+                    let _: usize = con
+                        .xack(&sub.topic, group, &[&entry.id])
+                        .with_context(|| "failed to ack message")?;
+                }
+                entry
+            }
+            None => block_in_place(|| read_next_entry(&mut con, &sub, &opts))?,
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        Ok(Some(to_received_message(
+            String::new(),
+            &sub.topic,
+            &entry,
+            1,
+        )))
+    }
+
+    fn supports_acknowledgement(&self) -> bool {
+        true
+    }
+
+    /// Redis only tracks how long a message has been pending for, so a message
+    /// is redelivered once it has been pending for longer than the
+    /// `visibility_timeout` of the `receive_message` that would redeliver it.
+    /// Pending messages are redelivered before any new ones.
+    ///
+    /// W/o a consumer group, pending messages are only kept in memory, so they
+    /// aren't redelivered if slight restarts before they are acked.
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        let sub = self.get_subscription(sub_tok)?;
+        let mut con = self.client.get_connection()?;
+        let visibility_timeout = visibility_timeout.unwrap_or(DEFAULT_VISIBILITY_TIMEOUT);
+        let group = match &sub.group {
+            Some(group) => group,
+            None => return receive_pending_entry(&mut con, sub_tok, &sub, visibility_timeout),
+        };
+        let min_idle_time = visibility_timeout.as_millis() as usize;
+
+        // take over the oldest message that has been pending for too long, if any
+        let pending: StreamPendingCountReply = redis::cmd("XPENDING")
+            .arg(&sub.topic)
+            .arg(group)
+            .arg("IDLE")
+            .arg(min_idle_time)
+            .arg("-")
+            .arg("+")
+            .arg(1)
+            .query(&mut con)
+            .with_context(|| "failed to get pending messages")?;
+        if let Some(pending) = pending.ids.first() {
+            let claimed: StreamClaimReply = con
+                .xclaim_options(
+                    &sub.topic,
+                    group,
+                    sub_tok,
+                    min_idle_time,
+                    &[&pending.id],
+                    StreamClaimOptions::default(),
+                )
+                .with_context(|| "failed to claim pending message")?;
+            // another consumer in the group might have claimed it first
+            if let Some(entry) = claimed.ids.first() {
+                let handle = format!("{sub_tok}/{}", &entry.id);
+                let delivery_count = pending.times_delivered as u32 + 1;
+                return Ok(Some(to_received_message(
+                    handle,
+                    &sub.topic,
+                    entry,
+                    delivery_count,
+                )));
+            }
+        }
+
+        let opts = StreamReadOptions::default().group(group, sub_tok).count(1);
+        Ok(
+            read_new_entry(&mut con, &sub.topic, ">", &opts)?.map(|entry| {
+                let handle = format!("{sub_tok}/{}", &entry.id);
+                to_received_message(handle, &sub.topic, &entry, 1)
+            }),
+        )
+    }
+
+    async fn ack(&self, handle: &str) -> Result<()> {
+        let (_, sub, id) = self.resolve_handle(handle)?;
+        match &sub.group {
+            Some(group) => {
+                let mut con = self.client.get_connection()?;
+                let _: usize = con
+                    .xack(&sub.topic, group, &[&id])
+                    .with_context(|| "failed to ack message")?;
+            }
+            None => {
+                if sub.reader.lock().unwrap().pending.remove(&id).is_none() {
+                    bail!("no pending message found per given handle (it may have been acknowledged already)");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn nack(&self, handle: &str) -> Result<()> {
+        let (sub_tok, sub, id) = self.resolve_handle(handle)?;
+        let group = match &sub.group {
+            Some(group) => group,
+            None => {
+                let mut reader = sub.reader.lock().unwrap();
+                let pending = reader.pending.get_mut(&id).with_context(|| {
+                    "no pending message found per given handle (it may have been acknowledged already)"
+                })?;
+                pending.redeliver_at = Instant::now();
+                return Ok(());
+            }
+        };
+        let mut con = self.client.get_connection()?;
+        let _: Value = con
+            .xclaim_options(
+                &sub.topic,
+                group,
+                &sub_tok,
+                0,
+                &[&id],
+                StreamClaimOptions::default()
+                    .idle(NACKED_IDLE_TIME.as_millis() as usize)
+                    .with_justid(),
+            )
+            .with_context(|| "failed to nack message")?;
+        Ok(())
+    }
+}
+
+/// Receives a message for a subscription w/o a consumer group, redelivering
+/// the one that has been pending for the longest, if any is due, before reading
+/// a new one.
+fn receive_pending_entry(
+    con: &mut Connection,
+    sub_tok: &str,
+    sub: &RedisSubscription,
+    visibility_timeout: Duration,
+) -> Result<Option<ReceivedMessage>> {
+    let now = Instant::now();
+    let mut reader = sub.reader.lock().unwrap();
+    let due = reader
+        .pending
+        .iter()
+        .filter(|(_, pending)| pending.redeliver_at <= now)
+        .min_by_key(|(_, pending)| pending.redeliver_at)
+        .map(|(id, _)| id.clone());
+    let pending = match due {
+        Some(id) => {
+            let pending = reader.pending.get_mut(&id).unwrap();
+            pending.delivery_count += 1;
+            pending
+        }
+        None => {
+            let opts = StreamReadOptions::default().count(1);
+            let entry = match read_new_entry(con, &sub.topic, &reader.last_id, &opts)? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            reader.last_id = entry.id.clone();
+            reader
+                .pending
+                .entry(entry.id.clone())
+                .or_insert(PendingEntry {
+                    entry,
+                    delivery_count: 1,
+                    redeliver_at: now,
+                })
+        }
+    };
+    pending.redeliver_at = now + visibility_timeout;
+
+    let handle = format!("{sub_tok}/{}", &pending.entry.id);
+    Ok(Some(to_received_message(
+        handle,
+        &sub.topic,
+        &pending.entry,
+        pending.delivery_count,
+    )))
+}
+
+/// Reads the next message in the stream of `sub`, which mustn't have a consumer
+/// group, and moves it past that message.
+fn read_next_entry(
+    con: &mut Connection,
+    sub: &RedisSubscription,
+    opts: &StreamReadOptions,
+) -> Result<Option<StreamId>> {
+    let mut reader = sub.reader.lock().unwrap();
+    let entry = read_new_entry(con, &sub.topic, &reader.last_id, opts)?;
+    if let Some(entry) = &entry {
+        reader.last_id = entry.id.clone();
+    }
+    Ok(entry)
+}
+
+/// Reads the next message in `topic`'s stream after `id` — or, for `>`, the
+/// next one that hasn't been delivered to the consumer group in `opts` yet.
+fn read_new_entry(
+    con: &mut Connection,
+    topic: &str,
+    id: &str,
+    opts: &StreamReadOptions,
+) -> Result<Option<StreamId>> {
+    let reply: Option<StreamReadReply> = con
+        .xread_options(&[topic], &[id], opts)
+        .with_context(|| "failed to read from stream")?;
+    Ok(reply
+        .and_then(|reply| reply.keys.into_iter().next())
+        .and_then(|key| key.ids.into_iter().next()))
+}
+
+fn to_received_message(
+    handle: String,
+    topic: &str,
+    entry: &StreamId,
+    delivery_count: u32,
+) -> ReceivedMessage {
+    let mut headers = Vec::new();
+    for (field, value) in &entry.map {
+        if let Some(key) = field.strip_prefix(HEADER_FIELD_PREFIX) {
+            if let Ok(value) = redis::from_redis_value::<String>(value) {
+                headers.push((key.to_string(), value));
+            }
+        }
+    }
+    headers.sort();
+
+    // stream entry ids are `<milliseconds since the unix epoch>-<sequence number>`
+    let timestamp = entry
+        .id
+        .split_once('-')
+        .and_then(|(ms, _)| ms.parse::<u64>().ok());
+
+    ReceivedMessage {
+        handle,
+        topic: topic.to_string(),
+        payload: entry.get(PAYLOAD_FIELD).unwrap_or_default(),
+        headers,
+        system_properties: SystemProperties {
+            message_id: Some(entry.id.clone()),
+            timestamp,
+            delivery_count: Some(delivery_count),
+        },
+    }
+}
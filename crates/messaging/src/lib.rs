@@ -1,15 +1,25 @@
+mod cloudevents;
 mod implementors;
 pub mod providers;
-use std::sync::Arc;
+mod wildcard;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
+use tokio::{sync::watch, task::JoinHandle};
 
+use cloudevents::CloudEvents;
 use implementors::{PubImplementor, SubImplementor, *};
-use slight_common::{impl_resource, BasicState};
+use slight_common::{impl_resource, BasicState, Builder, Ctx, WasmtimeBuildable};
 use slight_file::capability_store::CapabilityStore;
 use slight_file::resource::MessagingResource::*;
-use slight_file::Resource;
+use slight_file::{Resource, Subscription};
+use slight_messaging_api::MessagingHandler;
+use slight_runtime_configs::maybe_get_from_state;
 
 /// It is mandatory to `use <interface>::*` due to `impl_resource!`.
 /// That is because `impl_resource!` accesses the `crate`'s
@@ -34,14 +44,70 @@ wit_error_rs::impl_from!(
 ///     - the `slight_state` (of type `BasicState`) that contains common
 ///     things received from the slight binary (i.e., the `config_type`
 ///     and the `config_toml_file_path`).
-#[derive(Clone, Default)]
+#[derive(Default)]
 pub struct Messaging {
     store: CapabilityStore<MessagingState>,
+    guest_subscriptions: GuestSubscriptions,
+    handlers: Vec<HandlerTask>,
+}
+
+/// Every guest instance (e.g., per http request, or per message delivered to a
+/// handler) gets its' own clone, so a clone starts out w/o any of the guest
+/// subscriptions, or handlers, of the `Messaging` it was cloned from.
+impl Clone for Messaging {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The subscriptions a guest instance made, and hasn't cancelled, which are
+/// cancelled once the instance is done w/ them (i.e., when its' store is dropped,
+/// or when slight shuts down).
+#[derive(Default)]
+struct GuestSubscriptions(Vec<(SubInner, String)>);
+
+impl Drop for GuestSubscriptions {
+    fn drop(&mut self) {
+        let subscriptions = std::mem::take(&mut self.0);
+        if subscriptions.is_empty() {
+            return;
+        }
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(unsubscribe_all(subscriptions));
+            }
+            Err(_) => tracing::warn!(
+                "failed to cancel {} guest subscriptions, as there is no runtime to do it on",
+                subscriptions.len()
+            ),
+        }
+    }
+}
+
+async fn unsubscribe_all(subscriptions: Vec<(SubInner, String)>) {
+    for (sub, sub_tok) in subscriptions {
+        if let Err(e) = sub.unsubscribe(&sub_tok).await {
+            tracing::warn!("failed to cancel subscription {sub_tok}: {e}");
+        }
+    }
+}
+
+/// The background task polling a subscription bound to a messaging handler, and
+/// what it takes to stop it.
+struct HandlerTask {
+    sub: SubInner,
+    sub_tok: String,
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
 }
 
 #[derive(Clone, Debug)]
 pub struct PubInner {
     pub_implementor: Arc<dyn PubImplementor + Send + Sync>,
+    cloudevents: Option<Arc<CloudEvents>>,
 }
 
 impl PubInner {
@@ -50,11 +116,18 @@ impl PubInner {
         slight_state: &BasicState,
         name: &str,
     ) -> Result<Self> {
+        let cloudevents = CloudEvents::from_state(
+            slight_state,
+            name,
+            messaging_implementor.cloudevents_header_prefix(),
+        )
+        .await?
+        .map(Arc::new);
         Ok(Self {
             pub_implementor: match messaging_implementor {
                 #[cfg(feature = "filesystem")]
                 MessagingImplementors::Filesystem => {
-                    Arc::new(filesystem::FilesystemImplementor::new(name))
+                    Arc::new(filesystem::FilesystemImplementor::new(slight_state, name).await?)
                 }
                 #[cfg(feature = "mosquitto")]
                 MessagingImplementors::Mosquitto => {
@@ -72,48 +145,470 @@ impl PubInner {
                 MessagingImplementors::Nats => {
                     Arc::new(natsio::NatsIoImplementor::new(slight_state).await)
                 }
+                #[cfg(feature = "redis")]
+                MessagingImplementors::Redis => {
+                    Arc::new(redis::RedisImplementor::new(slight_state).await)
+                }
+                #[cfg(feature = "amqp")]
+                MessagingImplementors::Amqp => {
+                    Arc::new(amqp::AmqpImplementor::new(slight_state).await)
+                }
             },
+            cloudevents,
         })
     }
+
+    /// Publishes a message, wrapped into a CloudEvent if the resource is set up to.
+    async fn publish(&self, msg: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        let cloudevents = match &self.cloudevents {
+            Some(cloudevents) => cloudevents,
+            None => return self.pub_implementor.publish(msg, topic, headers).await,
+        };
+        let (msg, headers) = cloudevents.wrap(msg, topic, headers)?;
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        self.pub_implementor.publish(&msg, topic, &headers).await
+    }
+
+    async fn publish_batch(&self, msgs: &[&[u8]], topic: &str) -> Result<()> {
+        let cloudevents = match &self.cloudevents {
+            Some(cloudevents) => cloudevents,
+            None => return self.pub_implementor.publish_batch(msgs, topic).await,
+        };
+        let events = msgs
+            .iter()
+            .map(|msg| cloudevents.wrap(msg, topic, &[]))
+            .collect::<Result<Vec<_>>>()?;
+        // batches can't carry headers, so events w/ headers (i.e., their
+        // attributes, in binary mode, or their content type, in structured mode)
+        // are published one after the other
+        if events.iter().any(|(_, headers)| !headers.is_empty()) {
+            for (msg, headers) in &events {
+                let headers: Vec<(&str, &str)> = headers
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                self.pub_implementor.publish(msg, topic, &headers).await?;
+            }
+            return Ok(());
+        }
+        let msgs: Vec<&[u8]> = events.iter().map(|(msg, _)| msg.as_slice()).collect();
+        self.pub_implementor.publish_batch(&msgs, topic).await
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct SubInner {
     sub_implementor: Arc<dyn SubImplementor + Send + Sync>,
+    /// The topic, and group, of each subscription that hasn't been cancelled,
+    /// per token.
+    subscriptions: Arc<Mutex<BTreeMap<String, (String, Option<String>)>>>,
+    /// The resource's `MAX_DELIVERY_COUNT`, and `DEAD_LETTER_TOPIC`, which
+    /// subscriptions w/o settings of their own go by.
+    dead_letter_settings: Option<DeadLetterSettings>,
+    /// The dead-letter policy of each subscription that has one, per token.
+    dead_letter_policies: Arc<Mutex<HashMap<String, Arc<DeadLetterPolicy>>>>,
+    /// What dead-lettered messages are published w/.
+    dead_letter_publisher: Arc<dyn PubImplementor + Send + Sync>,
+    cloudevents: Option<Arc<CloudEvents>>,
+}
+
+/// How many times a message can be delivered before it is taken out of
+/// circulation, and where it goes then, for a resource (i.e., its'
+/// `MAX_DELIVERY_COUNT`, and `DEAD_LETTER_TOPIC`) or a subscription.
+#[derive(Clone, Debug)]
+struct DeadLetterSettings {
+    max_delivery_count: u32,
+    /// Where `{topic}` is replaced w/ the topic the message was published to, so
+    /// that each one can have its' own.
+    topic: Option<String>,
+}
+
+impl DeadLetterSettings {
+    async fn from_state(slight_state: &BasicState) -> Result<Option<Self>> {
+        let max_delivery_count = maybe_get_from_state("MAX_DELIVERY_COUNT", slight_state).await?;
+        let topic = maybe_get_from_state("DEAD_LETTER_TOPIC", slight_state).await?;
+        let max_delivery_count = match (max_delivery_count, &topic) {
+            (Some(max), _) => match max.parse::<u32>() {
+                Ok(max) if max > 0 => max,
+                _ => bail!("MAX_DELIVERY_COUNT must be a positive integer, but got '{max}'"),
+            },
+            (None, Some(_)) => bail!("DEAD_LETTER_TOPIC requires MAX_DELIVERY_COUNT to be set"),
+            (None, None) => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            max_delivery_count,
+            topic,
+        }))
+    }
+
+    /// The settings of `subscription`, which go by the resource's (i.e.,
+    /// `default`) where they aren't set.
+    fn for_subscription(
+        subscription: &Subscription,
+        default: Option<&Self>,
+    ) -> Result<Option<Self>> {
+        let max_delivery_count = subscription
+            .max_delivery_count
+            .or_else(|| default.map(|default| default.max_delivery_count));
+        let topic = subscription
+            .dead_letter_topic
+            .clone()
+            .or_else(|| default.and_then(|default| default.topic.clone()));
+        match (max_delivery_count, topic) {
+            (Some(0), _) => bail!(
+                "the max_delivery_count of the subscription to {} must be positive",
+                subscription.topic
+            ),
+            (Some(max_delivery_count), topic) => Ok(Some(Self {
+                max_delivery_count,
+                topic,
+            })),
+            (None, Some(_)) => bail!(
+                "the dead_letter_topic of the subscription to {} requires max_delivery_count, or MAX_DELIVERY_COUNT, to be set",
+                subscription.topic
+            ),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Takes messages that keep failing to be processed (i.e., that have been
+/// delivered more than `max_delivery_count` times) out of a subscription's
+/// circulation, by publishing them to the dead-letter `topic` — or dropping
+/// them, if there isn't one — and acking them.
+///
+/// This is enforced here, rather than by each implementor, so that it works
+/// the same across backends, incl. those w/o dead-letter queues of their own.
+#[derive(Debug)]
+struct DeadLetterPolicy {
+    settings: DeadLetterSettings,
+    delivery_counts: Mutex<DeliveryCounts>,
+}
+
+/// How many messages a `DeadLetterPolicy` counts the deliveries of, at most. Past
+/// that, the ones that were first delivered longest ago (e.g., b/c they were never
+/// settled) stop being counted, and start over if they are redelivered.
+const MAX_COUNTED_MESSAGES: usize = 10_000;
+
+/// How many times each message has been delivered per topic, and message id
+/// (as a subscription w/ wildcards spans several topics), for implementors
+/// that don't keep count themselves.
+#[derive(Debug, Default)]
+struct DeliveryCounts {
+    /// The count of each message, along w/ when it was first counted (as per
+    /// `next_seq`).
+    counts: HashMap<(String, String), (u32, u64)>,
+    /// The topic, and message id, of each message counted above that is waiting
+    /// to be acknowledged, per handle.
+    in_flight: HashMap<String, (String, String)>,
+    /// The messages counted above (and ones that have since been forgotten), in
+    /// the order they were first counted, so the oldest can be let go of.
+    order: VecDeque<(u64, (String, String))>,
+    next_seq: u64,
+}
+
+impl DeadLetterPolicy {
+    fn new(settings: DeadLetterSettings) -> Self {
+        Self {
+            settings,
+            delivery_counts: Mutex::new(DeliveryCounts::default()),
+        }
+    }
+
+    /// Counts a delivery of `message`, and returns how many times it has been
+    /// delivered — or `None` if that can't be told (i.e., the implementor
+    /// provides neither a delivery count, nor a message id).
+    fn count_delivery(&self, message: &ReceivedMessage) -> Option<u32> {
+        if let Some(delivery_count) = message.system_properties.delivery_count {
+            return Some(delivery_count);
+        }
+        let key = (
+            message.topic.clone(),
+            message.system_properties.message_id.clone()?,
+        );
+        let mut delivery_counts = self.delivery_counts.lock().unwrap();
+        Some(delivery_counts.count(key, &message.handle))
+    }
+
+    /// Stops counting the deliveries of the message w/ `handle`, as it won't be
+    /// redelivered.
+    fn forget(&self, handle: &str) {
+        self.delivery_counts.lock().unwrap().forget(handle);
+    }
+
+    /// Stops waiting for the message w/ `handle` to be acknowledged, as it will be
+    /// redelivered (w/ its' deliveries still counted, but not by this handle).
+    fn release(&self, handle: &str) {
+        self.delivery_counts
+            .lock()
+            .unwrap()
+            .in_flight
+            .remove(handle);
+    }
+}
+
+impl DeliveryCounts {
+    fn count(&mut self, key: (String, String), handle: &str) -> u32 {
+        let next_seq = self.next_seq;
+        let (delivery_count, _) = self.counts.entry(key.clone()).or_insert_with(|| {
+            self.order.push_back((next_seq, key.clone()));
+            (0, next_seq)
+        });
+        *delivery_count += 1;
+        let delivery_count = *delivery_count;
+        self.next_seq += 1;
+        self.in_flight.insert(handle.to_string(), key);
+
+        while self.order.len() > MAX_COUNTED_MESSAGES {
+            let (seq, oldest) = self.order.pop_front().unwrap();
+            // the message might have been forgotten, and counted anew, since
+            if matches!(self.counts.get(&oldest), Some((_, first)) if *first == seq) {
+                self.remove(&oldest);
+            }
+        }
+        delivery_count
+    }
+
+    fn forget(&mut self, handle: &str) {
+        if let Some(key) = self.in_flight.remove(handle) {
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &(String, String)) {
+        self.counts.remove(key);
+        // earlier deliveries of the message might not have been acknowledged
+        self.in_flight.retain(|_, k| k != key);
+    }
 }
 
 impl SubInner {
+    /// Dead-lettered messages are published w/ the resource's `publisher`.
     async fn new(
         messaging_implementor: MessagingImplementors,
         slight_state: &BasicState,
         name: &str,
+        publisher: &PubInner,
     ) -> Result<Self> {
-        let sub_implementor = Self {
-            sub_implementor: match messaging_implementor {
-                #[cfg(feature = "filesystem")]
-                MessagingImplementors::Filesystem => {
-                    Arc::new(filesystem::FilesystemImplementor::new(name))
-                }
-                #[cfg(feature = "mosquitto")]
-                MessagingImplementors::Mosquitto => {
-                    Arc::new(mosquitto::Sub::new(slight_state).await)
-                }
-                #[cfg(feature = "apache_kafka")]
-                MessagingImplementors::ConfluentApacheKafka => {
-                    Arc::new(apache_kafka::Sub::new(slight_state).await)
+        let cloudevents = CloudEvents::from_state(
+            slight_state,
+            name,
+            messaging_implementor.cloudevents_header_prefix(),
+        )
+        .await?
+        .map(Arc::new);
+        let sub_implementor: Arc<dyn SubImplementor + Send + Sync> = match messaging_implementor {
+            #[cfg(feature = "filesystem")]
+            MessagingImplementors::Filesystem => {
+                Arc::new(filesystem::FilesystemImplementor::new(slight_state, name).await?)
+            }
+            #[cfg(feature = "mosquitto")]
+            MessagingImplementors::Mosquitto => Arc::new(mosquitto::Sub::new(slight_state).await),
+            #[cfg(feature = "apache_kafka")]
+            MessagingImplementors::ConfluentApacheKafka => {
+                Arc::new(apache_kafka::Sub::new(slight_state).await)
+            }
+            #[cfg(feature = "azsbus")]
+            MessagingImplementors::AzSbus => {
+                Arc::new(azsbus::AzSbusImplementor::new(slight_state).await)
+            }
+            #[cfg(feature = "natsio")]
+            MessagingImplementors::Nats => {
+                Arc::new(natsio::NatsIoImplementor::new(slight_state).await)
+            }
+            #[cfg(feature = "redis")]
+            MessagingImplementors::Redis => {
+                Arc::new(redis::RedisImplementor::new(slight_state).await)
+            }
+            #[cfg(feature = "amqp")]
+            MessagingImplementors::Amqp => Arc::new(amqp::AmqpImplementor::new(slight_state).await),
+        };
+
+        // a message's deliveries can only be counted if it is redelivered until
+        // it is acknowledged
+        let dead_letter_settings = DeadLetterSettings::from_state(slight_state).await?;
+        if dead_letter_settings.is_some() && !sub_implementor.supports_acknowledgement() {
+            bail!("MAX_DELIVERY_COUNT is set for {name}, but its' implementor can't acknowledge messages, so none would be dead-lettered");
+        }
+
+        Ok(Self {
+            sub_implementor,
+            subscriptions: Arc::new(Mutex::new(BTreeMap::new())),
+            dead_letter_settings,
+            dead_letter_policies: Arc::new(Mutex::new(HashMap::new())),
+            dead_letter_publisher: publisher.pub_implementor.clone(),
+            cloudevents,
+        })
+    }
+
+    /// Subscribes to `topic` w/ the resource's dead-letter settings, if any.
+    async fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        self.subscribe_with_dead_letter(topic, group, self.dead_letter_settings.clone())
+            .await
+    }
+
+    async fn subscribe_with_dead_letter(
+        &self,
+        topic: &str,
+        group: Option<&str>,
+        dead_letter_settings: Option<DeadLetterSettings>,
+    ) -> Result<String> {
+        let sub_tok = self.sub_implementor.subscribe(topic, group).await?;
+        self.subscriptions.lock().unwrap().insert(
+            sub_tok.clone(),
+            (topic.to_string(), group.map(|g| g.to_string())),
+        );
+        if let Some(settings) = dead_letter_settings {
+            self.dead_letter_policies
+                .lock()
+                .unwrap()
+                .insert(sub_tok.clone(), Arc::new(DeadLetterPolicy::new(settings)));
+        }
+        Ok(sub_tok)
+    }
+
+    async fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        self.sub_implementor.unsubscribe(sub_tok).await?;
+        self.subscriptions.lock().unwrap().remove(sub_tok);
+        self.dead_letter_policies.lock().unwrap().remove(sub_tok);
+        Ok(())
+    }
+
+    /// The dead-letter policies of all subscriptions, as a message handle
+    /// doesn't tell which one it was counted by.
+    fn dead_letter_policies(&self) -> Vec<Arc<DeadLetterPolicy>> {
+        self.dead_letter_policies
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Unwraps a received CloudEvent, if the resource is set up to.
+    fn unwrap(&self, message: ReceivedMessage) -> ReceivedMessage {
+        match &self.cloudevents {
+            Some(cloudevents) => cloudevents.unwrap(message),
+            None => message,
+        }
+    }
+
+    async fn receive(&self, sub_tok: &str, timeout: Duration) -> Result<Option<ReceivedMessage>> {
+        Ok(self
+            .sub_implementor
+            .receive(sub_tok, timeout)
+            .await?
+            .map(|message| self.unwrap(message)))
+    }
+
+    async fn receive_batch(
+        &self,
+        sub_tok: &str,
+        max: usize,
+        timeout: Duration,
+    ) -> Result<Vec<ReceivedMessage>> {
+        Ok(self
+            .sub_implementor
+            .receive_batch(sub_tok, max, timeout)
+            .await?
+            .into_iter()
+            .map(|message| self.unwrap(message))
+            .collect())
+    }
+
+    /// Receives a message that is redelivered unless it is `ack`ed, dead-lettering
+    /// (rather than returning) the ones that have been delivered too many times,
+    /// as per the subscription's dead-letter policy. Dead-lettered messages are published as they were received (i.e., w/o
+    /// unwrapping them).
+    async fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<ReceivedMessage>> {
+        loop {
+            let message = match self
+                .sub_implementor
+                .receive_message(sub_tok, visibility_timeout)
+                .await?
+            {
+                Some(message) => message,
+                None => return Ok(None),
+            };
+            let policy = self
+                .dead_letter_policies
+                .lock()
+                .unwrap()
+                .get(sub_tok)
+                .cloned();
+            let policy = match policy {
+                Some(policy) => policy,
+                None => return Ok(Some(self.unwrap(message))),
+            };
+            match policy.count_delivery(&message) {
+                Some(delivery_count) if delivery_count > policy.settings.max_delivery_count => {
+                    self.dead_letter(&policy, message).await?
                 }
-                #[cfg(feature = "azsbus")]
-                MessagingImplementors::AzSbus => {
-                    Arc::new(azsbus::AzSbusImplementor::new(slight_state).await)
+                Some(_) => return Ok(Some(self.unwrap(message))),
+                None => {
+                    tracing::warn!(
+                        "can't tell how many times a message from topic {} was delivered, as it has neither a delivery count, nor a message id, so it won't be dead-lettered",
+                        message.topic
+                    );
+                    return Ok(Some(self.unwrap(message)));
                 }
-                #[cfg(feature = "natsio")]
-                MessagingImplementors::Nats => {
-                    Arc::new(natsio::NatsIoImplementor::new(slight_state).await)
-                }
-            },
-        };
+            }
+        }
+    }
 
-        Ok(sub_implementor)
+    async fn dead_letter(&self, policy: &DeadLetterPolicy, message: ReceivedMessage) -> Result<()> {
+        let topic = &message.topic;
+        match &policy.settings.topic {
+            Some(dead_letter_topic) => {
+                let dead_letter_topic = dead_letter_topic.replace("{topic}", topic);
+                tracing::warn!(
+                    "dead-lettering message {:?} from topic {} to {}",
+                    message.system_properties.message_id,
+                    topic,
+                    dead_letter_topic
+                );
+                let headers: Vec<(&str, &str)> = message
+                    .headers
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                self.dead_letter_publisher
+                    .publish(&message.payload, &dead_letter_topic, &headers)
+                    .await
+                    .with_context(|| "failed to publish message to dead-letter topic")?;
+            }
+            None => tracing::warn!(
+                "dropping message {:?} from topic {}, as it was delivered more than {} times",
+                message.system_properties.message_id,
+                topic,
+                policy.settings.max_delivery_count
+            ),
+        }
+        self.ack(&message.handle).await
+    }
+
+    async fn ack(&self, handle: &str) -> Result<()> {
+        self.sub_implementor.ack(handle).await?;
+        for policy in self.dead_letter_policies() {
+            policy.forget(handle);
+        }
+        Ok(())
+    }
+
+    async fn nack(&self, handle: &str) -> Result<()> {
+        self.sub_implementor.nack(handle).await?;
+        for policy in self.dead_letter_policies() {
+            policy.release(handle);
+        }
+        Ok(())
     }
 }
 
@@ -124,35 +619,189 @@ struct MessagingState {
 }
 
 impl Messaging {
-    pub async fn new(name: &str, capability_store: CapabilityStore<BasicState>) -> Result<Self> {
-        let state = capability_store.get(name, "messaging").unwrap().clone();
-
-        tracing::log::info!("Opening implementor {}", &state.implementor);
+    /// Opens an implementor for each named messaging resource in the
+    /// `capability_store`, so that resources w/ different implementors (e.g.,
+    /// `messaging.nats`, and `messaging.azsbus`) can be used side by side.
+    pub async fn new(capability_store: CapabilityStore<BasicState>) -> Result<Self> {
+        let mut messaging_store: CapabilityStore<MessagingState> = CapabilityStore::new();
+        if let Some(resources) = capability_store.as_ref().get("messaging") {
+            for (resource_name, state) in resources {
+                tracing::log::info!(
+                    "Opening implementor {} for {}",
+                    &state.implementor,
+                    &state.name
+                );
 
-        let p = PubInner::new(state.implementor.into(), &state, name).await?;
-        let s = SubInner::new(state.implementor.into(), &state, name).await?;
+                let p = PubInner::new(state.implementor.into(), state, &state.name).await?;
+                let s = SubInner::new(state.implementor.into(), state, &state.name, &p).await?;
 
-        let mut messaging_store: CapabilityStore<MessagingState> = CapabilityStore::new();
-        capability_store
-            .as_ref()
-            .get("messaging")
-            .unwrap()
-            .iter()
-            .for_each(|c| {
                 messaging_store.insert(
-                    c.0.clone(),
+                    resource_name.clone(),
                     "",
                     MessagingState {
-                        pub_implementor: p.clone(),
-                        sub_implementor: s.clone(),
+                        pub_implementor: p,
+                        sub_implementor: s,
                     },
                 );
-            });
+            }
+        }
 
         Ok(Self {
             store: messaging_store,
+            ..Default::default()
         })
     }
+
+    /// Stops the handlers' background tasks (once they are done w/ the message
+    /// they are on, if any), and cancels their subscriptions, along w/ the ones
+    /// the guest made, and hasn't cancelled itself. This is for when slight is
+    /// done running the guest, or shutting down.
+    pub async fn shutdown(&mut self) {
+        for handler in std::mem::take(&mut self.handlers) {
+            // the task may be gone already, if it panicked
+            let _ = handler.stop.send(true);
+            if let Err(e) = handler.task.await {
+                tracing::warn!("messaging handler task failed: {e}");
+            }
+            if let Err(e) = handler.sub.unsubscribe(&handler.sub_tok).await {
+                tracing::warn!("failed to cancel subscription {}: {e}", handler.sub_tok);
+            }
+        }
+        unsubscribe_all(std::mem::take(&mut self.guest_subscriptions.0)).await;
+    }
+
+    /// Binds each subscription of the named messaging resource to an exported
+    /// guest handler.
+    ///
+    /// A background task is spawned per subscription. For every received
+    /// message, a fresh guest is instantiated from `builder` — like the http
+    /// server does per request — and the handler is invoked with it. The
+    /// message is acked if the handler returns `Ok`, and nacked otherwise so
+    /// that it can be redelivered. Hence, the implementor must support
+    /// acknowledging messages, or else no subscription is bound. Messages that keep failing are dead-lettered as
+    /// per the subscription's `max_delivery_count`, and `dead_letter_topic`, or
+    /// else the resource's `MAX_DELIVERY_COUNT`, and `DEAD_LETTER_TOPIC`. The tasks
+    /// run until `shutdown`.
+    pub async fn serve_handlers<T: WasmtimeBuildable + Send + Sync + 'static>(
+        &mut self,
+        name: &str,
+        subscriptions: Vec<Subscription>,
+        builder: Builder<T>,
+    ) -> Result<()> {
+        let sub = self
+            .store
+            .get(name, "")
+            .with_context(|| format!("No messaging implementor found for {name}"))?
+            .sub_implementor
+            .clone();
+        if !sub.sub_implementor.supports_acknowledgement() {
+            bail!("messaging handlers are not supported by the implementor of {name}, as it can't acknowledge messages");
+        }
+
+        for subscription in subscriptions {
+            let dead_letter_settings = DeadLetterSettings::for_subscription(
+                &subscription,
+                sub.dead_letter_settings.as_ref(),
+            )?;
+            let sub_tok = sub
+                .subscribe_with_dead_letter(
+                    &subscription.topic,
+                    subscription.group.as_deref(),
+                    dead_letter_settings,
+                )
+                .await?;
+            info!(
+                "binding topic {} to handler {}",
+                &subscription.topic, &subscription.handler
+            );
+            let builder = builder.clone();
+            let (stop, mut stopped) = watch::channel(false);
+            let (task_sub, task_sub_tok) = (sub.clone(), sub_tok.clone());
+            let task = tokio::spawn(async move {
+                let (sub, sub_tok) = (task_sub, task_sub_tok);
+                // the task also stops if its' `Messaging` is gone w/o telling it to
+                while !*stopped.borrow() && stopped.has_changed().is_ok() {
+                    match sub.receive_message(&sub_tok, None).await {
+                        Ok(Some(message)) => {
+                            let handled =
+                                invoke_handler(builder.clone(), &subscription, &message).await;
+                            let res = match handled {
+                                Ok(()) => sub.ack(&message.handle).await,
+                                Err(e) => {
+                                    tracing::error!(
+                                        "handler {} failed on topic {}: {e}",
+                                        &subscription.handler,
+                                        &subscription.topic
+                                    );
+                                    sub.nack(&message.handle).await
+                                }
+                            };
+                            if let Err(e) = res {
+                                tracing::error!("failed to settle message: {e}");
+                            }
+                        }
+                        Ok(None) => wait_unless_stopped(HANDLER_POLL_INTERVAL, &mut stopped).await,
+                        Err(e) => {
+                            tracing::error!(
+                                "failed to receive message on topic {}: {e}",
+                                &subscription.topic
+                            );
+                            wait_unless_stopped(HANDLER_ERROR_BACKOFF, &mut stopped).await;
+                        }
+                    }
+                }
+            });
+            self.handlers.push(HandlerTask {
+                sub: sub.clone(),
+                sub_tok,
+                stop,
+                task,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Waits for `duration`, or until the handler is told to stop, whichever comes first.
+async fn wait_unless_stopped(duration: Duration, stopped: &mut watch::Receiver<bool>) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = stopped.changed() => {}
+    }
+}
+
+const HANDLER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const HANDLER_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Instantiates the guest and invokes the handler bound to `subscription`
+/// with the received message.
+async fn invoke_handler<T: WasmtimeBuildable + Send + Sync + 'static>(
+    builder: Builder<T>,
+    subscription: &Subscription,
+    message: &ReceivedMessage,
+) -> Result<()> {
+    let (mut store, instance) = builder.owned_inner().build().await;
+    let handler_name = &subscription.handler.replace('_', "-");
+    let handler = MessagingHandler::new(&mut store, &instance, handler_name, |ctx| {
+        ctx.get_messaging_handler_mut()
+    })?;
+
+    let headers: Vec<(&str, &str)> = message
+        .headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    let msg = slight_messaging_api::Message {
+        topic: &message.topic,
+        payload: &message.payload,
+        headers: &headers,
+    };
+
+    tracing::debug!("invoking handler: {}", handler_name);
+    handler
+        .handle_message(&mut store, msg)
+        .await?
+        .map_err(|e| anyhow::anyhow!(e))
 }
 
 impl_resource!(
@@ -182,7 +831,28 @@ impl messaging::Messaging for Messaging {
         message: &[u8],
         topic: &str,
     ) -> Result<(), MessagingError> {
-        self_.pub_implementor.publish(message, topic).await?;
+        self_.publish(message, topic, &[]).await?;
+        Ok(())
+    }
+
+    async fn pub_publish_with_headers(
+        &mut self,
+        self_: &Self::Pub,
+        message: &[u8],
+        topic: &str,
+        headers: Vec<(&str, &str)>,
+    ) -> Result<(), MessagingError> {
+        self_.publish(message, topic, &headers).await?;
+        Ok(())
+    }
+
+    async fn pub_publish_batch(
+        &mut self,
+        self_: &Self::Pub,
+        messages: Vec<&[u8]>,
+        topic: &str,
+    ) -> Result<(), MessagingError> {
+        self_.publish_batch(&messages, topic).await?;
         Ok(())
     }
 
@@ -200,16 +870,125 @@ impl messaging::Messaging for Messaging {
         self_: &Self::Sub,
         topic: &str,
     ) -> Result<String, MessagingError> {
-        Ok(self_.sub_implementor.subscribe(topic).await?)
+        let sub_tok = self_.subscribe(topic, None).await?;
+        self.guest_subscriptions
+            .0
+            .push((self_.clone(), sub_tok.clone()));
+        Ok(sub_tok)
+    }
+
+    async fn sub_subscribe_with_group(
+        &mut self,
+        self_: &Self::Sub,
+        topic: &str,
+        group: &str,
+    ) -> Result<String, MessagingError> {
+        let sub_tok = self_.subscribe(topic, Some(group)).await?;
+        self.guest_subscriptions
+            .0
+            .push((self_.clone(), sub_tok.clone()));
+        Ok(sub_tok)
+    }
+
+    async fn sub_unsubscribe(
+        &mut self,
+        self_: &Self::Sub,
+        sub_tok: SubscriptionTokenParam<'_>,
+    ) -> Result<(), MessagingError> {
+        self_.unsubscribe(sub_tok).await?;
+        self.guest_subscriptions.0.retain(|(_, t)| t != sub_tok);
+        Ok(())
+    }
+
+    async fn sub_list_subscriptions(
+        &mut self,
+        self_: &Self::Sub,
+    ) -> Result<Vec<SubscriptionInfo>, MessagingError> {
+        Ok(self_
+            .subscriptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(token, (topic, group))| SubscriptionInfo {
+                token: token.clone(),
+                topic: topic.clone(),
+                group: group.clone(),
+            })
+            .collect())
     }
 
     async fn sub_receive(
         &mut self,
         self_: &Self::Sub,
         sub_tok: SubscriptionTokenParam<'_>,
-    ) -> Result<Vec<u8>, MessagingError> {
+        timeout_ms: u32,
+    ) -> Result<Option<Message>, MessagingError> {
         info!("token: {:?}", sub_tok);
-        Ok(self_.sub_implementor.receive(sub_tok).await?)
+        let timeout = Duration::from_millis(timeout_ms.into());
+        Ok(self_.receive(sub_tok, timeout).await?.map(Message::from))
+    }
+
+    async fn sub_receive_batch(
+        &mut self,
+        self_: &Self::Sub,
+        sub_tok: SubscriptionTokenParam<'_>,
+        max: u32,
+        timeout_ms: u32,
+    ) -> Result<Vec<Message>, MessagingError> {
+        let timeout = Duration::from_millis(timeout_ms.into());
+        Ok(self_
+            .receive_batch(sub_tok, max as usize, timeout)
+            .await?
+            .into_iter()
+            .map(Message::from)
+            .collect())
+    }
+
+    async fn sub_receive_message(
+        &mut self,
+        self_: &Self::Sub,
+        sub_tok: SubscriptionTokenParam<'_>,
+        visibility_timeout_seconds: Option<u32>,
+    ) -> Result<Option<Message>, MessagingError> {
+        let visibility_timeout = visibility_timeout_seconds.map(|s| Duration::from_secs(s.into()));
+        Ok(self_
+            .receive_message(sub_tok, visibility_timeout)
+            .await?
+            .map(Message::from))
+    }
+
+    async fn sub_ack(
+        &mut self,
+        self_: &Self::Sub,
+        handle: MessageHandleParam<'_>,
+    ) -> Result<(), MessagingError> {
+        self_.ack(handle).await?;
+        Ok(())
+    }
+
+    async fn sub_nack(
+        &mut self,
+        self_: &Self::Sub,
+        handle: MessageHandleParam<'_>,
+    ) -> Result<(), MessagingError> {
+        self_.nack(handle).await?;
+        Ok(())
+    }
+}
+
+impl From<ReceivedMessage> for Message {
+    fn from(m: ReceivedMessage) -> Self {
+        Self {
+            handle: m.handle,
+            topic: m.topic,
+            payload: m.payload,
+            headers: m.headers,
+            system_properties: messaging::SystemProperties {
+                message_id: m.system_properties.message_id,
+                timestamp: m.system_properties.timestamp,
+                delivery_count: m.system_properties.delivery_count,
+            },
+        }
     }
 }
 
@@ -228,6 +1007,27 @@ pub enum MessagingImplementors {
     AzSbus,
     #[cfg(feature = "natsio")]
     Nats,
+    #[cfg(feature = "redis")]
+    Redis,
+    #[cfg(feature = "amqp")]
+    Amqp,
+}
+
+impl MessagingImplementors {
+    /// How a CloudEvent's attributes are prefixed, as headers, in binary mode,
+    /// as per the CloudEvents protocol binding of the implementor's backend — or
+    /// `None` if it doesn't support headers, so binary mode can't be used.
+    fn cloudevents_header_prefix(&self) -> Option<&'static str> {
+        match self {
+            #[cfg(feature = "apache_kafka")]
+            Self::ConfluentApacheKafka => Some("ce_"),
+            #[cfg(feature = "amqp")]
+            Self::Amqp => Some("cloudEvents:"),
+            #[cfg(feature = "mosquitto")]
+            Self::Mosquitto => None,
+            _ => Some("ce-"),
+        }
+    }
 }
 
 impl From<Resource> for MessagingImplementors {
@@ -243,6 +1043,10 @@ impl From<Resource> for MessagingImplementors {
             Resource::Messaging(Azsbus) | Resource::Messaging(V1Azsbus) => Self::AzSbus,
             #[cfg(feature = "natsio")]
             Resource::Messaging(Nats) => Self::Nats,
+            #[cfg(feature = "redis")]
+            Resource::Messaging(Redis) => Self::Redis,
+            #[cfg(feature = "amqp")]
+            Resource::Messaging(Amqp) => Self::Amqp,
             p => panic!(
                 "failed to match provided name (i.e., '{p}') to any known host implementations"
             ),
@@ -1,31 +1,127 @@
+use std::time::Duration;
+
 use anyhow::{bail, Result};
 #[cfg(feature = "apache_kafka")]
 use rdkafka::{
-    consumer::{Consumer, StreamConsumer},
-    producer::{BaseProducer, BaseRecord},
-    Message,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    message::{Header, Headers, OwnedHeaders},
+    producer::{BaseProducer, BaseRecord, Producer},
+    Message, Offset, TopicPartitionList,
 };
 
+/// How long to wait for a consumer to seek back to an uncommitted message.
+const SEEK_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for a producer to deliver the messages queued up in it.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a message was read from, so that it can be committed (or sought back to) later.
+#[derive(Debug, Clone)]
+pub struct MessagePosition {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+/// A message received w/o committing it, w/ its' metadata.
+#[derive(Debug, Clone)]
+pub struct UncommittedMessage {
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+    /// When the message was created, in milliseconds since the unix epoch.
+    pub timestamp: Option<i64>,
+    pub position: MessagePosition,
+}
+
 pub fn publish(
     producer: &BaseProducer,
     msg_key: &[u8],
     msg_value: &[u8],
     topic: &str,
+    headers: &[(&str, &str)],
 ) -> Result<()> {
+    let mut record = BaseRecord::to(topic).key(msg_key).payload(msg_value);
+    if !headers.is_empty() {
+        record = record.headers(headers.iter().fold(
+            OwnedHeaders::new(),
+            |owned, &(key, value)| {
+                owned.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            },
+        ));
+    }
     producer
-        .send(BaseRecord::to(topic).key(msg_key).payload(msg_value))
+        .send(record)
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
     Ok(())
 }
 
+/// Waits for the messages queued up in the producer to be delivered, which it
+/// sends in as few requests as it can.
+pub fn flush(producer: &BaseProducer) -> Result<()> {
+    producer.flush(FLUSH_TIMEOUT)?;
+    Ok(())
+}
+
 pub fn subscribe(consumer: &StreamConsumer, topic: Vec<&str>) -> Result<()> {
     consumer.subscribe(&topic)?;
     Ok(())
 }
 
-pub async fn receive(consumer: &StreamConsumer) -> Result<Vec<u8>> {
+pub fn unsubscribe(consumer: &StreamConsumer) {
+    consumer.unsubscribe();
+}
+
+/// Receives a message w/o committing it, so it is redelivered (e.g., to another
+/// consumer in the group, after a rebalance) unless it is `commit`ed.
+pub async fn receive_uncommitted(consumer: &StreamConsumer) -> Result<UncommittedMessage> {
     match consumer.recv().await {
         Err(e) => bail!(e),
-        Ok(m) => Ok(m.payload().unwrap().to_vec()),
+        Ok(m) => Ok(UncommittedMessage {
+            payload: m.payload().unwrap_or_default().to_vec(),
+            headers: match m.headers() {
+                Some(headers) => headers
+                    .iter()
+                    .map(|h| {
+                        (
+                            h.key.to_string(),
+                            String::from_utf8_lossy(h.value.unwrap_or_default()).into_owned(),
+                        )
+                    })
+                    .collect(),
+                None => Vec::new(),
+            },
+            timestamp: m.timestamp().to_millis(),
+            position: MessagePosition {
+                topic: m.topic().to_string(),
+                partition: m.partition(),
+                offset: m.offset(),
+            },
+        }),
+    }
+}
+
+/// Commits the given offsets, each of which is that of the next message to
+/// consume from its' topic and partition.
+pub fn commit(consumer: &StreamConsumer, offsets: &[(String, i32, i64)]) -> Result<()> {
+    let mut tpl = TopicPartitionList::new();
+    for (topic, partition, offset) in offsets {
+        tpl.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
     }
+    consumer.commit(&tpl, CommitMode::Async)?;
+    Ok(())
+}
+
+/// Seeks the consumer back to an uncommitted message, so it is redelivered.
+/// Kafka has no per-message redelivery, so every message after it in the same
+/// partition is redelivered too.
+pub fn rewind(consumer: &StreamConsumer, position: &MessagePosition) -> Result<()> {
+    consumer.seek(
+        &position.topic,
+        position.partition,
+        Offset::Offset(position.offset),
+        SEEK_TIMEOUT,
+    )?;
+    Ok(())
 }
@@ -1,170 +1,901 @@
-use std::fs::{read_dir, OpenOptions};
-use std::io::{Read, Write};
-use std::path::PathBuf;
-use std::{env, fs};
-
-use anyhow::{Context, Result};
-
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use fs2::FileExt;
+
+use crate::wildcard::{self, DEFAULT_SEPARATOR};
+
+/// Name of the directory holding one append-only log per topic.
+const TOPICS_DIR: &str = "topics";
+/// Name of the directory holding one offset file per subscription.
+const SUBSCRIPTIONS_DIR: &str = "subscriptions";
+/// Name of the directory holding one offset file per consumer group and topic.
+const GROUPS_DIR: &str = "groups";
+/// Length, in bytes, of the big-endian length that prefixes every message in a log
+/// (and every header key and value in a message).
+const LEN_PREFIX: usize = 4;
+/// How long a message received w/o a visibility timeout is hidden from other
+/// receives, while waiting to be acknowledged.
+const DEFAULT_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a consumer group's member can go w/o trying to receive before the
+/// group's other members take its' turns (e.g., b/c its' process went away w/o
+/// unsubscribing).
+const TURN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A filesystem-backed broker.
+///
+/// Each topic is an append-only log of length-prefixed messages (see
+/// `encode_message` for how they are laid out), and each
+/// subscription is an offset into its' topic's log — or into each of its'
+/// topics' logs, for one w/ wildcards (see the `wildcard` module), which
+/// receives their messages in the order they were published. Both live under
+/// `<root>/<name>`, and outlive the process, so subscriptions pick up where
+/// they left off after a restart.
+///
+/// Messages received w/ `receive_message` are also tracked per subscription
+/// until they are acknowledged, and are redelivered if that doesn't happen
+/// within their visibility timeout.
+///
+/// Subscriptions in a consumer group share a single offset (and pending
+/// messages), kept under `groups/<topic>/<group>`, so each message is delivered
+/// to only one of the group's members. The members take turns receiving, in the
+/// order they joined the group, which is kept next to it (see `Turns`). A member
+/// that hasn't tried to receive for a while (see `TURN_TIMEOUT`) is skipped, so a
+/// group doesn't stall on one that went away.
 #[derive(Debug, Clone)]
 pub struct Pubsub {
-    locale: LocaleDir,
+    root: PathBuf,
 }
 
-#[derive(Debug, Clone)]
-struct LocaleDir {
-    inner: PathBuf,
+/// A message read from a topic's log.
+#[derive(Debug)]
+pub struct LoggedMessage {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub headers: Vec<(String, String)>,
+    /// When the message was published, in milliseconds since the unix epoch.
+    pub published_at: u64,
+    /// Where the message is in its' topic's log, which doubles as its' id.
+    pub offset: u64,
 }
 
-impl LocaleDir {
-    fn new(name: &str) -> Self {
-        let locale = env::temp_dir().join(name);
-        let _ = std::fs::create_dir_all(&locale);
-
-        Self { inner: locale }
-    }
+/// A received message, the handle to acknowledge it with (which is empty for one
+/// received w/ `receive`), and how many times it has been delivered (incl. this time).
+#[derive(Debug)]
+pub struct Delivery {
+    pub handle: String,
+    pub message: LoggedMessage,
+    pub delivery_count: u32,
 }
 
-impl AsRef<PathBuf> for LocaleDir {
-    fn as_ref(&self) -> &PathBuf {
-        &self.inner
-    }
+/// A message that is waiting to be acknowledged.
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    /// When the message may be redelivered, in milliseconds since the unix epoch.
+    visible_at: u64,
+    delivery_count: u32,
 }
 
-impl AsMut<PathBuf> for LocaleDir {
-    fn as_mut(&mut self) -> &mut PathBuf {
-        &mut self.inner
-    }
+/// A subscription's topic (which may have wildcards), and the offset of the
+/// next message it will receive from each of the topics it matches.
+struct Subscription {
+    topic: String,
+    offsets: BTreeMap<String, u64>,
 }
 
-impl Drop for LocaleDir {
-    fn drop(&mut self) {
-        let _ = std::fs::remove_dir_all(&self.inner);
-    }
+/// The order in which a consumer group's members take turns receiving (w/ the
+/// one whose turn it is first), along w/ when each of them last tried to, in
+/// milliseconds since the unix epoch.
+struct Turns {
+    members: Vec<(u64, String)>,
 }
 
 impl Pubsub {
-    pub fn open(name: &str) -> Result<Pubsub> {
-        let locale = LocaleDir::new(name);
-        Ok(Pubsub { locale })
+    /// Opens (or creates) the broker named `name` under `root`.
+    pub fn open(root: &Path, name: &str) -> Result<Pubsub> {
+        let root = root.join(name);
+        fs::create_dir_all(root.join(TOPICS_DIR))
+            .with_context(|| "failed to create topics directory")?;
+        fs::create_dir_all(root.join(SUBSCRIPTIONS_DIR))
+            .with_context(|| "failed to create subscriptions directory")?;
+        fs::create_dir_all(root.join(GROUPS_DIR))
+            .with_context(|| "failed to create groups directory")?;
+        Ok(Pubsub { root })
     }
 
-    pub fn publish(&self, message: &[u8], topic: &str) -> Result<()> {
-        let fixed_topic = topic.replace('-', "_");
-        let topic_path = self.locale.as_ref().join(fixed_topic.clone());
+    pub fn publish(&self, message: &[u8], topic: &str, headers: &[(&str, &str)]) -> Result<()> {
+        let message = encode_message(message, headers, now_millis())?;
+        let len = u32::try_from(message.len())
+            .with_context(|| "message is too large for the filesystem broker")?;
+        let mut record = Vec::with_capacity(LEN_PREFIX + message.len());
+        record.extend(len.to_be_bytes());
+        record.extend(message);
 
-        // If the topic file doesn't exist, create it
-        if !topic_path.exists() {
-            fs::File::create(&topic_path)?;
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.topic_path(topic))
+            .with_context(|| format!("failed to open log for topic '{topic}'"))?;
+        // the lock keeps receivers from reading a half-written message
+        log.lock_exclusive()?;
+        log.write_all(&record)?;
+        log.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Receives the next message for a subscription, w/o keeping track of it (so
+    /// its' handle is empty). Messages received w/ `receive_message` that are due
+    /// to be redelivered are received before any new ones, and stop being tracked.
+    ///
+    /// Returns `None` if there are no messages to receive.
+    pub fn receive(&self, sub_tok: &str) -> Result<Option<Delivery>> {
+        let (sub_path, in_group) = self.resolve_subscription(sub_tok)?;
+        let _lock = lock_subscription(&sub_path)?;
+        let now = now_millis();
+        let mut turns = in_group
+            .then(|| Turns::read(&sub_path, sub_tok, now))
+            .transpose()?;
+        if !is_turn(&sub_path, turns.as_ref(), sub_tok, now)? {
+            return Ok(None);
         }
 
-        // Open the topic file for writing and append the message
-        let mut topic_file = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .open(&topic_path)?;
+        let mut pending = read_pending(&sub_path)?;
+        if let Some((topic, offset, delivery_count)) = next_redelivery(&pending, now) {
+            tracing::debug!(
+                "redelivering message at offset {} of topic '{}'",
+                offset,
+                topic
+            );
+            let (message, _) = self
+                .read_message(&topic, offset)?
+                .with_context(|| format!("log for topic '{topic}' is corrupted"))?;
+            pending.remove(&(topic, offset));
+            write_pending(&sub_path, &pending)?;
+            pass_turn(&sub_path, turns.as_mut(), sub_tok)?;
+            return Ok(Some(Delivery {
+                handle: String::new(),
+                message,
+                delivery_count: delivery_count + 1,
+            }));
+        }
 
-        // Add a newline character at the end of the message
-        let mut message_to_write = Vec::from(message);
-        message_to_write.push(b'\n');
+        let mut sub = read_subscription(&sub_path)?;
+        tracing::debug!("receiving from topic '{}'", sub.topic);
+        match self.next_message(&sub)? {
+            Some((message, next_offset)) => {
+                sub.offsets.insert(message.topic.clone(), next_offset);
+                write_subscription(&sub_path, &sub)?;
+                pass_turn(&sub_path, turns.as_mut(), sub_tok)?;
+                Ok(Some(Delivery {
+                    handle: String::new(),
+                    message,
+                    delivery_count: 1,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Receives the next message for a subscription, and keeps track of it until
+    /// it is acknowledged. Messages that were `nack`ed, or that weren't acknowledged
+    /// within their visibility timeout, are redelivered before any new ones.
+    ///
+    /// Returns `None` if there are no messages to receive.
+    pub fn receive_message(
+        &self,
+        sub_tok: &str,
+        visibility_timeout: Option<Duration>,
+    ) -> Result<Option<Delivery>> {
+        let (sub_path, in_group) = self.resolve_subscription(sub_tok)?;
+        let _lock = lock_subscription(&sub_path)?;
+        let now = now_millis();
+        let mut turns = in_group
+            .then(|| Turns::read(&sub_path, sub_tok, now))
+            .transpose()?;
+        if !is_turn(&sub_path, turns.as_ref(), sub_tok, now)? {
+            return Ok(None);
+        }
+
+        let mut sub = read_subscription(&sub_path)?;
+        let mut pending = read_pending(&sub_path)?;
+        let visible_at = now
+            + visibility_timeout
+                .unwrap_or(DEFAULT_VISIBILITY_TIMEOUT)
+                .as_millis() as u64;
+
+        if let Some((topic, offset, delivery_count)) = next_redelivery(&pending, now) {
+            tracing::debug!(
+                "redelivering message at offset {} of topic '{}'",
+                offset,
+                topic
+            );
+            let (message, _) = self
+                .read_message(&topic, offset)?
+                .with_context(|| format!("log for topic '{topic}' is corrupted"))?;
+            let pending_message = Pending {
+                visible_at,
+                delivery_count: delivery_count + 1,
+            };
+            let handle = message_handle(sub_tok, &topic, offset);
+            pending.insert((topic, offset), pending_message);
+            write_pending(&sub_path, &pending)?;
+            pass_turn(&sub_path, turns.as_mut(), sub_tok)?;
+            return Ok(Some(Delivery {
+                handle,
+                message,
+                delivery_count: pending_message.delivery_count,
+            }));
+        }
 
-        topic_file.write_all(&message_to_write)?;
+        match self.next_message(&sub)? {
+            Some((message, next_offset)) => {
+                // the message is marked as pending before the offset moves past it,
+                // so a crash in between can only lead to it being delivered twice
+                pending.insert(
+                    (message.topic.clone(), message.offset),
+                    Pending {
+                        visible_at,
+                        delivery_count: 1,
+                    },
+                );
+                write_pending(&sub_path, &pending)?;
+                let handle = message_handle(sub_tok, &message.topic, message.offset);
+                sub.offsets.insert(message.topic.clone(), next_offset);
+                write_subscription(&sub_path, &sub)?;
+                pass_turn(&sub_path, turns.as_mut(), sub_tok)?;
+                Ok(Some(Delivery {
+                    handle,
+                    message,
+                    delivery_count: 1,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
 
-        // Relay the message to each subscriber
-        for entry in fs::read_dir(self.locale.as_ref())? {
-            let entry = entry?;
-            let path = entry.path();
-            let file_name = path.file_name().unwrap().to_str().unwrap();
+    /// Acknowledges a message received w/ `receive_message`, so it is never redelivered.
+    pub fn ack(&self, handle: &str) -> Result<()> {
+        self.update_pending(handle, |pending, key| {
+            pending.remove(&key);
+        })
+    }
 
-            // If the file is a subscriber file (i.e., "<topic-name>-<subscription-token>")
-            if file_name.starts_with(&fixed_topic) && file_name.contains('+') {
-                let mut sub_file = OpenOptions::new().write(true).append(true).open(&path)?;
+    /// Gives up on a message received w/ `receive_message`, so it is redelivered
+    /// straight away.
+    pub fn nack(&self, handle: &str) -> Result<()> {
+        self.update_pending(handle, |pending, key| {
+            if let Some(p) = pending.get_mut(&key) {
+                p.visible_at = 0;
+            }
+        })
+    }
 
-                // Add a newline character at the end of the message
-                let mut message_to_write = Vec::from(message);
-                message_to_write.push(b'\n');
+    fn update_pending(
+        &self,
+        handle: &str,
+        update: impl FnOnce(&mut BTreeMap<(String, u64), Pending>, (String, u64)),
+    ) -> Result<()> {
+        let (sub_tok, key) = parse_message_handle(handle)
+            .with_context(|| format!("invalid message handle '{handle}'"))?;
+        let (sub_path, _) = self.resolve_subscription(sub_tok)?;
+        let _lock = lock_subscription(&sub_path)?;
+
+        let mut pending = read_pending(&sub_path)?;
+        if !pending.contains_key(&key) {
+            bail!(
+                "no pending message found per given handle (it may have been acknowledged already)"
+            );
+        }
+        update(&mut pending, key);
+        write_pending(&sub_path, &pending)
+    }
 
-                sub_file.write_all(&message_to_write)?;
+    /// Subscribes to `topic`, which can have wildcards in it. Subscriptions w/ the
+    /// same `group` take turns receiving the topic's messages, rather than each one
+    /// getting all of them.
+    pub fn subscribe(&self, topic: &str, group: Option<&str>) -> Result<String> {
+        wildcard::validate(topic, DEFAULT_SEPARATOR)?;
+        let topics = if wildcard::has_wildcards(topic, DEFAULT_SEPARATOR) {
+            self.matching_topics(topic)?
+        } else {
+            vec![topic.to_string()]
+        };
+
+        // like with a real broker, a new subscription only gets the messages
+        // published after it was created (and topics created after it are read
+        // from the start)
+        let mut offsets = BTreeMap::new();
+        for topic in topics {
+            let log = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.topic_path(&topic))
+                .with_context(|| format!("failed to open log for topic '{topic}'"))?;
+            log.lock_shared()?;
+            offsets.insert(topic, log.metadata()?.len());
+        }
+        let sub = Subscription {
+            topic: topic.to_string(),
+            offsets,
+        };
+        let sub_tok = uuid::Uuid::new_v4().to_string();
+        let sub_path = self.subscription_path(&sub_tok)?;
+
+        match group {
+            Some(group) => {
+                let group_path = self.group_path(topic, group);
+                fs::create_dir_all(group_path.parent().unwrap())
+                    .with_context(|| "failed to create group directory")?;
+                // only the group's first member sets where it starts from
+                let _lock = lock_subscription(&group_path)?;
+                if !group_path.exists() {
+                    write_subscription(&group_path, &sub)?;
+                }
+                // a new member gets its' first turn after the existing ones
+                Turns::read(&group_path, &sub_tok, now_millis())?.write(&group_path)?;
+                write_atomically(
+                    &sub_path.with_extension("group"),
+                    format!("{}\n{}", encode_name(topic), encode_name(group)).as_bytes(),
+                )
+                .with_context(|| "failed to save subscription")?;
             }
+            None => write_subscription(&sub_path, &sub)?,
+        }
+
+        tracing::debug!(
+            "created subscription '{}' to topic '{}' (group: {:?})",
+            sub_tok,
+            topic,
+            group
+        );
+        Ok(sub_tok)
+    }
+
+    /// Deletes a subscription, along w/ its' pending messages. A consumer group's
+    /// offset (and pending messages) is shared by its' members, so it outlives them,
+    /// and a member just stops being one (and taking turns).
+    pub fn unsubscribe(&self, sub_tok: &str) -> Result<()> {
+        let (sub_path, in_group) = self.resolve_subscription(sub_tok)?;
+        if in_group {
+            let _lock = lock_subscription(&sub_path)?;
+            let mut turns = Turns::read(&sub_path, sub_tok, now_millis())?;
+            turns.members.retain(|(_, member)| member != sub_tok);
+            turns.write(&sub_path)?;
+            fs::remove_file(self.subscription_path(sub_tok)?.with_extension("group"))
+                .with_context(|| "failed to delete subscription")?;
+            tracing::debug!("removed subscription '{}' from its' group", sub_tok);
+            return Ok(());
         }
 
+        // receives that are waiting on the lock fail once they get it, as the
+        // subscription is gone by then
+        let lock = lock_subscription(&sub_path)?;
+        remove_if_exists(&sub_path.with_extension("pending"))?;
+        fs::remove_file(&sub_path).with_context(|| "failed to delete subscription")?;
+        drop(lock);
+        remove_if_exists(&sub_path.with_extension("lock"))?;
+
+        tracing::debug!("deleted subscription '{}'", sub_tok);
         Ok(())
     }
 
-    pub fn receive(&self, sub_tok: &str) -> Result<Vec<u8>> {
-        // Find the subscription file that matches the given subscription token
-        let folder = self.locale.as_ref();
-        let files = read_dir(folder).expect("Unable to read topics in pubsub");
-        tracing::debug!("looking for subscription under: {:?}", files);
-        let mut sub_file_name = "".to_string();
+    fn topic_path(&self, topic: &str) -> PathBuf {
+        self.root
+            .join(TOPICS_DIR)
+            .join(format!("{}.log", encode_name(topic)))
+    }
 
-        for file in files {
-            let path = file.expect("Unable to topic").path();
-            let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
-            if file_name.contains(sub_tok) {
-                sub_file_name = file_name;
-                break;
+    fn group_path(&self, topic: &str, group: &str) -> PathBuf {
+        self.root
+            .join(GROUPS_DIR)
+            .join(encode_name(topic))
+            .join(encode_name(group))
+    }
+
+    /// Lists the topics that have a log, and that `pattern` matches.
+    fn matching_topics(&self, pattern: &str) -> Result<Vec<String>> {
+        let mut topics = Vec::new();
+        for entry in
+            fs::read_dir(self.root.join(TOPICS_DIR)).with_context(|| "failed to list topics")?
+        {
+            let file_name = entry?.file_name();
+            let topic = file_name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".log"))
+                .and_then(decode_name);
+            if let Some(topic) = topic {
+                if wildcard::matches(pattern, &topic, DEFAULT_SEPARATOR) {
+                    topics.push(topic);
+                }
             }
         }
-        let sub_file_path = self.locale.as_ref().join(sub_file_name);
-        tracing::debug!("found subscription file: {:?}", sub_file_path);
+        Ok(topics)
+    }
 
-        // Read the entire contents of the subscription file
-        let mut sub_file = fs::File::open(&sub_file_path)
-            .with_context(|| "no subscription found per given token")?;
-        let mut sub_file_contents = Vec::new();
-        sub_file.read_to_end(&mut sub_file_contents)?;
+    /// Reads the next message from each of the topics `sub` matches, and returns
+    /// the one that was published first, along w/ the offset of the one after it
+    /// in its' topic — or `None` if there are no messages to receive.
+    fn next_message(&self, sub: &Subscription) -> Result<Option<(LoggedMessage, u64)>> {
+        let topics = if wildcard::has_wildcards(&sub.topic, DEFAULT_SEPARATOR) {
+            self.matching_topics(&sub.topic)?
+        } else {
+            vec![sub.topic.clone()]
+        };
+
+        let mut next: Option<(LoggedMessage, u64)> = None;
+        for topic in topics {
+            // topics w/o an offset were created after the subscription, so all of
+            // their messages are new to it
+            let offset = sub.offsets.get(&topic).copied().unwrap_or(0);
+            if let Some((message, next_offset)) = self.read_message(&topic, offset)? {
+                let is_earlier = match &next {
+                    Some((earliest, _)) => message.published_at < earliest.published_at,
+                    None => true,
+                };
+                if is_earlier {
+                    next = Some((message, next_offset));
+                }
+            }
+        }
+        Ok(next)
+    }
 
-        // remove \n (i.e., last element of sub_file_contents)
-        sub_file_contents.pop();
+    /// Finds where a subscription's offset (and pending messages) live, which is
+    /// its' group's for a subscription in a consumer group, and whether it is in one.
+    fn resolve_subscription(&self, sub_tok: &str) -> Result<(PathBuf, bool)> {
+        let sub_path = self.subscription_path(sub_tok)?;
+        if sub_path.exists() {
+            return Ok((sub_path, false));
+        }
+        match fs::read_to_string(sub_path.with_extension("group")) {
+            Ok(contents) => {
+                let (topic, group) = contents.split_once('\n').with_context(|| {
+                    format!("subscription file '{}' is corrupted", sub_path.display())
+                })?;
+                Ok((self.root.join(GROUPS_DIR).join(topic).join(group), true))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                bail!("no subscription found per given token")
+            }
+            Err(e) => Err(e).with_context(|| "failed to read subscription"),
+        }
+    }
+
+    fn subscription_path(&self, sub_tok: &str) -> Result<PathBuf> {
+        // tokens are UUIDs, so anything else can't be a subscription (and must
+        // not be used to build a path)
+        if sub_tok.is_empty() || !sub_tok.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            bail!("no subscription found per given token");
+        }
+        Ok(self.root.join(SUBSCRIPTIONS_DIR).join(sub_tok))
+    }
 
-        tracing::debug!("subscription file contents: {:?}", sub_file_contents);
+    /// Reads the message at `offset` in `topic`'s log. Returns the message and the
+    /// offset of the one after it, or `None` if there are no messages at `offset` yet.
+    fn read_message(&self, topic: &str, offset: u64) -> Result<Option<(LoggedMessage, u64)>> {
+        let mut log = match File::open(self.topic_path(topic)) {
+            Ok(log) => log,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to open log for topic '{topic}'"))
+            }
+        };
+        log.lock_shared()?;
+        if log.metadata()?.len() < offset + LEN_PREFIX as u64 {
+            return Ok(None);
+        }
 
-        // Read the last message from the subscription file
-        let last_message = sub_file_contents.split(|b| *b == b'\n').last();
+        log.seek(SeekFrom::Start(offset))?;
+        let mut len = [0; LEN_PREFIX];
+        log.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        let mut message = vec![0; len];
+        log.read_exact(&mut message)
+            .with_context(|| format!("log for topic '{topic}' is corrupted"))?;
 
-        // Check if there is a last message
-        if last_message.is_none() {
-            return Ok(Vec::new());
+        let message = decode_message(&message, topic, offset)
+            .with_context(|| format!("log for topic '{topic}' is corrupted"))?;
+
+        Ok(Some((message, offset + (LEN_PREFIX + len) as u64)))
+    }
+}
+
+/// Subscription files hold the (encoded) topic on the first line, followed by
+/// a line per topic it matches, w/ the offset of the next message to receive
+/// from it, and the (encoded) topic.
+fn read_subscription(path: &Path) -> Result<Subscription> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!("no subscription found per given token")
         }
+        Err(e) => return Err(e).with_context(|| "failed to read subscription"),
+    };
+    let mut lines = contents.lines();
+    let topic = lines.next().and_then(decode_name);
+    let offsets = lines
+        .map(|line| {
+            let (offset, topic) = line.split_once(' ')?;
+            Some((decode_name(topic)?, offset.parse().ok()?))
+        })
+        .collect::<Option<_>>();
+    match (topic, offsets) {
+        (Some(topic), Some(offsets)) => Ok(Subscription { topic, offsets }),
+        _ => bail!("subscription file '{}' is corrupted", path.display()),
+    }
+}
+
+fn write_subscription(path: &Path, sub: &Subscription) -> Result<()> {
+    let mut contents = format!("{}\n", encode_name(&sub.topic));
+    for (topic, offset) in &sub.offsets {
+        contents.push_str(&format!("{} {}\n", offset, encode_name(topic)));
+    }
+    write_atomically(path, contents.as_bytes()).with_context(|| "failed to save subscription")
+}
 
-        let mut last_message = last_message.unwrap().to_vec();
-        tracing::debug!("last message: {:?}", last_message);
+/// Pending files hold one line per message that is waiting to be acknowledged,
+/// w/ its' offset, when it may be redelivered, how many times it has been
+/// delivered, and its' (encoded) topic.
+fn read_pending(sub_path: &Path) -> Result<BTreeMap<(String, u64), Pending>> {
+    let path = sub_path.with_extension("pending");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| "failed to read pending messages"),
+    };
+    contents
+        .lines()
+        .map(|line| {
+            let mut fields = line.split(' ');
+            let mut number = || fields.next()?.parse::<u64>().ok();
+            let offset = number()?;
+            let visible_at = number()?;
+            let delivery_count = u32::try_from(number()?).ok()?;
+            let topic = decode_name(fields.next()?)?;
+            Some((
+                (topic, offset),
+                Pending {
+                    visible_at,
+                    delivery_count,
+                },
+            ))
+        })
+        .collect::<Option<_>>()
+        .with_context(|| format!("pending file '{}' is corrupted", path.display()))
+}
 
-        sub_file_contents.push(b'\n');
-        last_message.push(b'\n');
+/// Finds the first pending message that is due to be redelivered, if any, along
+/// w/ how many times it has been delivered so far. Pending messages are ordered
+/// by topic, and offset, so the oldest one in a topic is redelivered first.
+fn next_redelivery(
+    pending: &BTreeMap<(String, u64), Pending>,
+    now: u64,
+) -> Option<(String, u64, u32)> {
+    pending
+        .iter()
+        .find(|(_, p)| p.visible_at <= now)
+        .map(|((topic, offset), p)| (topic.clone(), *offset, p.delivery_count))
+}
 
-        // Truncate the subscription file to remove the last message
-        let sub_file = fs::OpenOptions::new().write(true).open(&sub_file_path)?;
-        sub_file.set_len((sub_file_contents.len() - last_message.len()) as u64)?;
-        tracing::debug!("truncated subscription file");
+fn write_pending(sub_path: &Path, pending: &BTreeMap<(String, u64), Pending>) -> Result<()> {
+    let contents: String = pending
+        .iter()
+        .map(|((topic, offset), p)| {
+            format!(
+                "{} {} {} {}\n",
+                offset,
+                p.visible_at,
+                p.delivery_count,
+                encode_name(topic)
+            )
+        })
+        .collect();
+    write_atomically(&sub_path.with_extension("pending"), contents.as_bytes())
+        .with_context(|| "failed to save pending messages")
+}
 
-        Ok(last_message)
+impl Turns {
+    /// Turns files hold one line per member of a consumer group, in the order they
+    /// take turns, w/ when it last tried to receive, and its' token. `sub_tok` is
+    /// noted as trying to receive at `now` (and added as the last member, if it
+    /// isn't one yet).
+    fn read(group_path: &Path, sub_tok: &str, now: u64) -> Result<Turns> {
+        let path = group_path.with_extension("turns");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| "failed to read group's turns"),
+        };
+        let mut members: Vec<(u64, String)> = contents
+            .lines()
+            .map(|line| {
+                let (tried_at, member) = line.split_once(' ')?;
+                Some((tried_at.parse().ok()?, member.to_string()))
+            })
+            .collect::<Option<_>>()
+            .with_context(|| format!("turns file '{}' is corrupted", path.display()))?;
+        match members.iter_mut().find(|(_, member)| member == sub_tok) {
+            Some(member) => member.0 = now,
+            None => members.push((now, sub_tok.to_string())),
+        }
+        Ok(Turns { members })
     }
 
-    pub fn subscribe(&self, topic: &str) -> Result<String> {
-        let fixed_topic = topic.replace('-', "_");
+    fn write(&self, group_path: &Path) -> Result<()> {
+        let contents: String = self
+            .members
+            .iter()
+            .map(|(tried_at, member)| format!("{tried_at} {member}\n"))
+            .collect();
+        write_atomically(&group_path.with_extension("turns"), contents.as_bytes())
+            .with_context(|| "failed to save group's turns")
+    }
 
-        // Generate a random UUID to use as the subscription token
-        let sub_token = uuid::Uuid::new_v4().to_string();
+    /// Whether it is `sub_tok`'s turn, i.e., whether every member before it has
+    /// gone `TURN_TIMEOUT` w/o trying to receive.
+    fn is_turn(&self, sub_tok: &str, now: u64) -> bool {
+        let timeout = TURN_TIMEOUT.as_millis() as u64;
+        self.members
+            .iter()
+            .take_while(|(_, member)| member != sub_tok)
+            .all(|(tried_at, _)| tried_at + timeout <= now)
+    }
 
-        // Create the path for the subscription file
-        let sub_file_path = self
-            .locale
-            .as_ref()
-            .join(format!("{fixed_topic}+{sub_token}"));
+    /// Moves `sub_tok` to the back of the line, after it has received a message.
+    fn pass(&mut self, sub_tok: &str) {
+        if let Some(position) = self.members.iter().position(|(_, m)| m == sub_tok) {
+            let member = self.members.remove(position);
+            self.members.push(member);
+        }
+    }
+}
 
-        let topic_path = self.locale.as_ref().join(fixed_topic);
+/// Whether a subscription may receive now, which it always may unless it is in
+/// a consumer group (i.e., has `turns`), and it isn't its' turn. Either way, the
+/// group's turns are saved, so other members know it tried to.
+fn is_turn(sub_path: &Path, turns: Option<&Turns>, sub_tok: &str, now: u64) -> Result<bool> {
+    match turns {
+        Some(turns) => {
+            turns.write(sub_path)?;
+            Ok(turns.is_turn(sub_tok, now))
+        }
+        None => Ok(true),
+    }
+}
 
-        // If the topic file doesn't exist, create it
-        if !topic_path.exists() {
-            fs::File::create(&topic_path)?;
+/// Passes the turn on from a subscription in a consumer group (i.e., that has
+/// `turns`), after it has received a message.
+fn pass_turn(sub_path: &Path, turns: Option<&mut Turns>, sub_tok: &str) -> Result<()> {
+    match turns {
+        Some(turns) => {
+            turns.pass(sub_tok);
+            turns.write(sub_path)
         }
+        None => Ok(()),
+    }
+}
+
+/// Writes to a temporary file first, and then renames it over `path`, so a
+/// crash can't leave a half-written file behind.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_data()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("failed to delete '{}'", path.display())),
+    }
+}
 
-        // Clone the topic file to the subscription file
-        std::fs::copy(topic_path, &sub_file_path)?;
+/// Locks a subscription, so concurrent receives (and acknowledgements) on it
+/// can't step on each other. The lock is released when the returned file is dropped.
+fn lock_subscription(sub_path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(sub_path.with_extension("lock"))?;
+    lock.lock_exclusive()?;
+    Ok(lock)
+}
 
-        tracing::debug!("created subscription file: {:?}", sub_file_path);
+/// Lays a message out as:
+///     - when it was published (a big-endian `u64`),
+///     - how many headers it has (a big-endian `u16`),
+///     - each header's key, and value (each one length-prefixed), and
+///     - its' payload (i.e., the rest of the message).
+fn encode_message(payload: &[u8], headers: &[(&str, &str)], published_at: u64) -> Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(payload.len());
+    message.extend(published_at.to_be_bytes());
+    let count = u16::try_from(headers.len()).with_context(|| "message has too many headers")?;
+    message.extend(count.to_be_bytes());
+    for field in headers.iter().flat_map(|(key, value)| [key, value]) {
+        let len = u32::try_from(field.len()).with_context(|| "message header is too large")?;
+        message.extend(len.to_be_bytes());
+        message.extend(field.as_bytes());
+    }
+    message.extend(payload);
+    Ok(message)
+}
+
+/// Reverses `encode_message`.
+fn decode_message(mut message: &[u8], topic: &str, offset: u64) -> Result<LoggedMessage> {
+    let mut take = |n: usize| -> Result<&[u8]> {
+        if message.len() < n {
+            bail!("message is truncated");
+        }
+        let (taken, rest) = message.split_at(n);
+        message = rest;
+        Ok(taken)
+    };
+
+    let published_at = u64::from_be_bytes(take(8)?.try_into()?);
+    let count = u16::from_be_bytes(take(2)?.try_into()?);
+    let mut headers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut field = || -> Result<String> {
+            let len = u32::from_be_bytes(take(LEN_PREFIX)?.try_into()?) as usize;
+            Ok(String::from_utf8(take(len)?.to_vec())?)
+        };
+        headers.push((field()?, field()?));
+    }
+
+    Ok(LoggedMessage {
+        topic: topic.to_string(),
+        payload: message.to_vec(),
+        headers,
+        published_at,
+        offset,
+    })
+}
+
+/// A message's handle is its' subscription's token, its' (encoded) topic, and
+/// its' offset, separated by a `:` (which an encoded topic can't have).
+fn message_handle(sub_tok: &str, topic: &str, offset: u64) -> String {
+    format!("{}:{}:{}", sub_tok, encode_name(topic), offset)
+}
+
+/// Reverses `message_handle`, into the subscription's token, and the message's
+/// topic, and offset.
+fn parse_message_handle(handle: &str) -> Option<(&str, (String, u64))> {
+    let mut parts = handle.splitn(3, ':');
+    let sub_tok = parts.next()?;
+    let topic = decode_name(parts.next()?)?;
+    let offset = parts.next()?.parse().ok()?;
+    Some((sub_tok, (topic, offset)))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+/// Encodes a topic (or group) into a single, safe file name.
+///
+/// Alphanumerics, `-` and `_` are kept as-is; every other byte is percent-encoded.
+fn encode_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for b in name.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{b:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Reverses `encode_name`, or returns `None` if `encoded` isn't an encoded name.
+fn decode_name(encoded: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            _ => decoded.push(b),
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use anyhow::Result;
+
+    use super::{Pubsub, Turns, TURN_TIMEOUT};
+
+    #[test]
+    fn group_turns_test() -> Result<()> {
+        let pubsub = Pubsub::open(&env::temp_dir(), &uuid::Uuid::new_v4().to_string())?;
+        let worker_a = pubsub.subscribe("work", Some("workers"))?;
+        let worker_b = pubsub.subscribe("work", Some("workers"))?;
+        let worker_c = pubsub.subscribe("work", Some("workers"))?;
+        for job in ["job 1", "job 2", "job 3", "job 4"] {
+            pubsub.publish(job.as_bytes(), "work", &[])?;
+        }
+
+        // members that try to receive out of turn get nothing
+        assert!(pubsub.receive(&worker_b)?.is_none());
+        assert_eq!(
+            pubsub.receive(&worker_a)?.unwrap().message.payload,
+            b"job 1"
+        );
+        assert!(pubsub.receive(&worker_a)?.is_none());
+        assert!(pubsub.receive(&worker_c)?.is_none());
+        assert_eq!(
+            pubsub.receive(&worker_b)?.unwrap().message.payload,
+            b"job 2"
+        );
+        let delivery = pubsub.receive_message(&worker_c, None)?.unwrap();
+        assert_eq!(delivery.message.payload, b"job 3");
+        pubsub.ack(&delivery.handle)?;
+
+        // members that leave the group stop taking turns
+        pubsub.unsubscribe(&worker_a)?;
+        assert_eq!(
+            pubsub.receive(&worker_b)?.unwrap().message.payload,
+            b"job 4"
+        );
+        assert!(pubsub.unsubscribe(&worker_a).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn receive_redelivery_test() -> Result<()> {
+        let pubsub = Pubsub::open(&env::temp_dir(), &uuid::Uuid::new_v4().to_string())?;
+        let sub_tok = pubsub.subscribe("orders", None)?;
+        pubsub.publish(b"placed", "orders", &[])?;
+        pubsub.publish(b"paid", "orders", &[])?;
+
+        let delivery = pubsub.receive_message(&sub_tok, None)?.unwrap();
+        assert_eq!(delivery.delivery_count, 1);
+        pubsub.nack(&delivery.handle)?;
+
+        // the nacked message comes first, and is acknowledged by being received
+        let redelivery = pubsub.receive(&sub_tok)?.unwrap();
+        assert_eq!(redelivery.message.payload, b"placed");
+        assert_eq!(redelivery.delivery_count, 2);
+        assert!(redelivery.handle.is_empty());
+        assert!(pubsub.ack(&delivery.handle).is_err());
+        let delivery = pubsub.receive(&sub_tok)?.unwrap();
+        assert_eq!(delivery.message.payload, b"paid");
+        assert_eq!(delivery.delivery_count, 1);
+        assert!(pubsub.receive(&sub_tok)?.is_none());
+        Ok(())
+    }
 
-        Ok(sub_token)
+    #[test]
+    fn turns_timeout_test() {
+        let timeout = TURN_TIMEOUT.as_millis() as u64;
+        let mut turns = Turns {
+            members: vec![(1000, "a".to_string()), (1000, "b".to_string())],
+        };
+        assert!(turns.is_turn("a", 1000));
+        assert!(!turns.is_turn("b", 1000));
+        // a member that stopped trying to receive is skipped
+        assert!(turns.is_turn("b", 1000 + timeout));
+
+        turns.pass("a");
+        assert!(turns.is_turn("b", 1000));
+        assert!(!turns.is_turn("a", 1000));
     }
 }
@@ -2,3 +2,5 @@
 pub mod confluent;
 #[cfg(feature = "filesystem")]
 pub mod fs;
+#[cfg(feature = "azsbus")]
+pub mod servicebus;
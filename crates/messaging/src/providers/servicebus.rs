@@ -0,0 +1,245 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use sha2::Sha256;
+use url::form_urlencoded::byte_serialize;
+
+/// How long the shared access signature of a request is valid for.
+const SIGNATURE_TTL: Duration = Duration::from_secs(3600);
+
+/// Sends `msgs` to `topic` in a single request, w/ service bus' batch format
+/// (i.e., a json array of messages, each w/ its' `Body`), which the service bus
+/// client doesn't support.
+pub async fn send_batch(
+    http_client: &reqwest::Client,
+    service_bus_namespace: &str,
+    topic: &str,
+    policy_name: &str,
+    policy_key: &str,
+    msgs: &[&str],
+) -> Result<()> {
+    let url = format!("https://{service_bus_namespace}.servicebus.windows.net/{topic}/messages");
+    let body = serde_json::to_string(
+        &msgs
+            .iter()
+            .map(|msg| serde_json::json!({ "Body": msg }))
+            .collect::<Vec<_>>(),
+    )?;
+
+    let response = http_client
+        .post(&url)
+        .header(
+            "Authorization",
+            generate_signature(policy_name, policy_key, &url)?,
+        )
+        .header("Content-Type", "application/vnd.microsoft.servicebus.json")
+        .body(body)
+        .send()
+        .await
+        .with_context(|| "failed to send batch of messages")?;
+    if !response.status().is_success() {
+        bail!(
+            "failed to send batch of messages: service bus responded w/ {}",
+            response.status()
+        );
+    }
+    Ok(())
+}
+
+/// Sends `msg` to `topic`, w/ each of the `headers` as a custom property of the
+/// message. Service bus takes custom properties as http headers, w/ their values
+/// quoted as json strings (unquoted values are taken as numbers, or dates).
+pub async fn send(
+    http_client: &reqwest::Client,
+    service_bus_namespace: &str,
+    topic: &str,
+    policy_name: &str,
+    policy_key: &str,
+    msg: &[u8],
+    headers: &[(&str, &str)],
+) -> Result<()> {
+    let url = format!("https://{service_bus_namespace}.servicebus.windows.net/{topic}/messages");
+    let mut request = http_client
+        .post(&url)
+        .header(
+            "Authorization",
+            generate_signature(policy_name, policy_key, &url)?,
+        )
+        .body(msg.to_vec());
+    for (key, value) in headers {
+        request = request.header(*key, serde_json::to_string(value)?);
+    }
+
+    let response = request
+        .send()
+        .await
+        .with_context(|| "failed to send message")?;
+    if !response.status().is_success() {
+        bail!(
+            "failed to send message: service bus responded w/ {}",
+            response.status()
+        );
+    }
+    Ok(())
+}
+
+/// A message received from a service bus subscription.
+pub struct ServiceBusMessage {
+    pub body: Vec<u8>,
+    /// The message's custom properties.
+    pub properties: Vec<(String, String)>,
+    pub message_id: Option<String>,
+    /// When the message was enqueued, in milliseconds since the unix epoch.
+    pub enqueued_time: Option<u64>,
+    pub delivery_count: Option<u32>,
+    /// The url of a peek-locked message, to delete or unlock it w/.
+    pub location: Option<String>,
+}
+
+/// Receives the next message of `subscription`, and locks it, so that it's
+/// hidden from other receivers until it's deleted, unlocked, or its' lock
+/// expires. Service bus waits up to `timeout` (in whole seconds) for a message
+/// to arrive, and responds w/ `None` if none does.
+pub async fn peek_lock(
+    http_client: &reqwest::Client,
+    service_bus_namespace: &str,
+    topic: &str,
+    subscription: &str,
+    policy_name: &str,
+    policy_key: &str,
+    timeout: Duration,
+) -> Result<Option<ServiceBusMessage>> {
+    let url = format!(
+        "https://{service_bus_namespace}.servicebus.windows.net/{topic}/subscriptions/{subscription}/messages/head"
+    );
+    let response = http_client
+        .post(&url)
+        .query(&[("timeout", timeout.as_secs())])
+        .header(
+            "Authorization",
+            generate_signature(policy_name, policy_key, &url)?,
+        )
+        .header("Content-Length", "0")
+        .send()
+        .await
+        .with_context(|| "failed to peek-lock message")?;
+    read_message(response).await
+}
+
+/// Deletes the peek-locked message at `location`, so it's not received again.
+pub async fn delete(
+    http_client: &reqwest::Client,
+    location: &str,
+    policy_name: &str,
+    policy_key: &str,
+) -> Result<()> {
+    let response = http_client
+        .delete(location)
+        .header(
+            "Authorization",
+            generate_signature(policy_name, policy_key, location)?,
+        )
+        .send()
+        .await
+        .with_context(|| "failed to delete message")?;
+    if !response.status().is_success() {
+        bail!(
+            "failed to delete message: service bus responded w/ {}",
+            response.status()
+        );
+    }
+    Ok(())
+}
+
+/// Unlocks the peek-locked message at `location`, so it's received again.
+pub async fn unlock(
+    http_client: &reqwest::Client,
+    location: &str,
+    policy_name: &str,
+    policy_key: &str,
+) -> Result<()> {
+    let response = http_client
+        .put(location)
+        .header(
+            "Authorization",
+            generate_signature(policy_name, policy_key, location)?,
+        )
+        .header("Content-Length", "0")
+        .send()
+        .await
+        .with_context(|| "failed to unlock message")?;
+    if !response.status().is_success() {
+        bail!(
+            "failed to unlock message: service bus responded w/ {}",
+            response.status()
+        );
+    }
+    Ok(())
+}
+
+/// Reads a received message from `response`, which is `None` if service bus had
+/// no message to give (i.e., responded w/ no content).
+async fn read_message(response: reqwest::Response) -> Result<Option<ServiceBusMessage>> {
+    let status = response.status();
+    if status == StatusCode::NO_CONTENT {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        bail!("failed to receive message: service bus responded w/ {status}");
+    }
+
+    let headers = response.headers();
+    let broker_properties: serde_json::Value = match headers.get("BrokerProperties") {
+        Some(value) => serde_json::from_slice(value.as_bytes())
+            .with_context(|| "failed to parse the message's broker properties")?,
+        None => serde_json::Value::Null,
+    };
+    let location = headers
+        .get("Location")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    // custom properties are the only headers w/ json strings as values (http
+    // lowercases their names, though)
+    let properties = headers
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = serde_json::from_slice::<String>(value.as_bytes()).ok()?;
+            Some((key.to_string(), value))
+        })
+        .collect();
+    let body = response.bytes().await?.to_vec();
+
+    Ok(Some(ServiceBusMessage {
+        body,
+        properties,
+        message_id: broker_properties["MessageId"].as_str().map(str::to_string),
+        enqueued_time: broker_properties["EnqueuedTimeUtc"]
+            .as_str()
+            .and_then(|time| httpdate::parse_http_date(time).ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_millis() as u64),
+        delivery_count: broker_properties["DeliveryCount"]
+            .as_u64()
+            .map(|count| count as u32),
+        location,
+    }))
+}
+
+/// Generates a shared access signature for `url`, signed w/ the policy's key.
+fn generate_signature(policy_name: &str, policy_key: &str, url: &str) -> Result<String> {
+    let expiry = (SystemTime::now().duration_since(UNIX_EPOCH)? + SIGNATURE_TTL).as_secs();
+    let encoded_url: String = byte_serialize(url.as_bytes()).collect();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(policy_key.as_bytes())
+        .with_context(|| "failed to sign request w/ policy key")?;
+    mac.update(format!("{encoded_url}\n{expiry}").as_bytes());
+    let signature = STANDARD.encode(mac.finalize().into_bytes());
+    let encoded_signature: String = byte_serialize(signature.as_bytes()).collect();
+
+    Ok(format!(
+        "SharedAccessSignature sr={encoded_url}&sig={encoded_signature}&se={expiry}&skn={policy_name}"
+    ))
+}
@@ -0,0 +1,206 @@
+//! Slight's wildcard syntax, for subscribing to several topics at once.
+//!
+//! A topic is made up of levels separated by a `.` (or by a `/`, for mqtt
+//! brokers), and any level of a subscription's topic can be a wildcard:
+//!     - `*` matches exactly one level (e.g., `orders.*` matches `orders.eu`,
+//!       but neither `orders`, nor `orders.eu.paid`), and
+//!     - `>` matches one or more levels, so it can only be the last one (e.g.,
+//!       `orders.>` matches `orders.eu`, and `orders.eu.paid`, but not `orders`).
+//!
+//! A wildcard has to make up a whole level, so a topic that is split w/ the
+//! wrong separator (e.g., `orders.*`, for mqtt brokers) is rejected, rather
+//! than subscribed to as-is.
+//!
+//! Each implementor translates these into its' backend's own wildcards, or
+//! emulates them.
+
+use anyhow::{bail, Result};
+
+/// Matches exactly one level.
+pub const SINGLE_LEVEL: &str = "*";
+/// Matches one or more levels, at the end of a topic.
+pub const MULTI_LEVEL: &str = ">";
+/// What separates a topic's levels, for backends w/o a separator of their own.
+pub const DEFAULT_SEPARATOR: char = '.';
+
+/// A level of a subscription's topic.
+enum Level<'a> {
+    Literal(&'a str),
+    Single,
+    Multi,
+}
+
+/// Splits `topic` into its' levels, and checks that wildcards make up whole
+/// levels, and that `>` is only used as the last one.
+fn parse(topic: &str, separator: char) -> Result<Vec<Level<'_>>> {
+    let mut levels = Vec::new();
+    for level in topic.split(separator) {
+        levels.push(match level {
+            SINGLE_LEVEL => Level::Single,
+            MULTI_LEVEL => Level::Multi,
+            literal if literal.contains(SINGLE_LEVEL) || literal.contains(MULTI_LEVEL) => bail!(
+                "wildcards can only make up a whole level of a topic (w/ levels separated by '{separator}'), but got '{topic}'"
+            ),
+            literal => Level::Literal(literal),
+        });
+    }
+    if let Some(position) = levels.iter().position(|l| matches!(l, Level::Multi)) {
+        if position != levels.len() - 1 {
+            bail!("'{MULTI_LEVEL}' can only be the last level of a topic, but got '{topic}'");
+        }
+    }
+    Ok(levels)
+}
+
+/// Fails if `topic`'s wildcards aren't used as they should be.
+pub fn validate(topic: &str, separator: char) -> Result<()> {
+    parse(topic, separator).map(|_| ())
+}
+
+/// Whether `topic` has any wildcards in it.
+pub fn has_wildcards(topic: &str, separator: char) -> bool {
+    topic
+        .split(separator)
+        .any(|level| level == SINGLE_LEVEL || level == MULTI_LEVEL)
+}
+
+/// Fails if `topic` has any wildcards in it, for implementors whose backends
+/// can't subscribe to several topics at once.
+pub fn reject(topic: &str, separator: char, implementor: &str) -> Result<()> {
+    if parse(topic, separator)?
+        .iter()
+        .any(|level| !matches!(level, Level::Literal(_)))
+    {
+        bail!("wildcard subscriptions are not supported by the {implementor} implementor");
+    }
+    Ok(())
+}
+
+/// Rewrites `topic`'s wildcards into a backend's `single`, and `multi`, level
+/// ones — where `multi` matches zero or more levels (as in mqtt, and amqp), so
+/// `>` becomes `single` followed by `multi`. A level w/ the backend's own
+/// wildcards in it is rejected, as they don't mean the same as slight's.
+pub fn translate(topic: &str, separator: char, single: &str, multi: &str) -> Result<String> {
+    let levels = parse(topic, separator)?;
+    let mut translated = Vec::with_capacity(levels.len() + 1);
+    for level in levels {
+        match level {
+            Level::Literal(literal) if literal.contains(single) || literal.contains(multi) => bail!(
+                "'{single}', and '{multi}', aren't wildcards in slight (use '{SINGLE_LEVEL}', and '{MULTI_LEVEL}', instead), but got '{topic}'"
+            ),
+            Level::Literal(literal) => translated.push(literal),
+            Level::Single => translated.push(single),
+            Level::Multi => {
+                translated.push(single);
+                translated.push(multi);
+            }
+        }
+    }
+    Ok(translated.join(&separator.to_string()))
+}
+
+/// Builds a regular expression that matches the same topics as `topic`.
+pub fn to_regex(topic: &str, separator: char) -> Result<String> {
+    let levels = parse(topic, separator)?;
+    let separator = escape_regex(&separator.to_string());
+    let levels: Vec<String> = levels
+        .iter()
+        .map(|level| match level {
+            Level::Literal(literal) => escape_regex(literal),
+            Level::Single => format!("[^{separator}]+"),
+            Level::Multi => ".+".to_string(),
+        })
+        .collect();
+    Ok(format!("^{}$", levels.join(&separator)))
+}
+
+/// Whether `topic` (w/o wildcards) is one of the topics `pattern` matches.
+pub fn matches(pattern: &str, topic: &str, separator: char) -> bool {
+    let levels = match parse(pattern, separator) {
+        Ok(levels) => levels,
+        Err(_) => return false,
+    };
+    let mut topic_levels = topic.split(separator);
+    for level in levels {
+        match (level, topic_levels.next()) {
+            (Level::Multi, Some(_)) => return true,
+            (Level::Single, Some(_)) => (),
+            (Level::Literal(literal), Some(topic_level)) if literal == topic_level => (),
+            _ => return false,
+        }
+    }
+    topic_levels.next().is_none()
+}
+
+fn escape_regex(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if "\\.^$|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translate_wildcards() -> Result<()> {
+        assert_eq!(translate("orders", '/', "+", "#")?, "orders");
+        assert_eq!(translate("orders/*/paid", '/', "+", "#")?, "orders/+/paid");
+        assert_eq!(translate("orders/>", '/', "+", "#")?, "orders/+/#");
+        assert_eq!(translate("orders.*.>", '.', "*", "#")?, "orders.*.*.#");
+        Ok(())
+    }
+
+    #[test]
+    fn translate_rejects_misused_wildcards() {
+        assert!(translate("orders/>/paid", '/', "+", "#").is_err());
+        // split w/ the wrong separator
+        assert!(translate("orders.*", '/', "+", "#").is_err());
+        assert!(translate("orders/>", '.', "*", "#").is_err());
+        // the backend's own wildcards
+        assert!(translate("orders/#", '/', "+", "#").is_err());
+        assert!(translate("orders/+", '/', "+", "#").is_err());
+    }
+
+    #[test]
+    fn to_regex_matches_the_same_topics() -> Result<()> {
+        assert_eq!(to_regex("orders", '.')?, "^orders$");
+        assert_eq!(to_regex("orders.*", '.')?, "^orders\\.[^\\.]+$");
+        assert_eq!(to_regex("orders.>", '.')?, "^orders\\..+$");
+        assert_eq!(to_regex("a+b.*", '.')?, "^a\\+b\\.[^\\.]+$");
+        assert!(to_regex("orders.>.paid", '.').is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn matches_topics() {
+        assert!(matches("orders", "orders", '.'));
+        assert!(!matches("orders", "orders.eu", '.'));
+
+        assert!(matches("orders.*", "orders.eu", '.'));
+        assert!(!matches("orders.*", "orders", '.'));
+        assert!(!matches("orders.*", "orders.eu.paid", '.'));
+
+        assert!(matches("orders.>", "orders.eu", '.'));
+        assert!(matches("orders.>", "orders.eu.paid", '.'));
+        assert!(!matches("orders.>", "orders", '.'));
+
+        assert!(matches("*.eu.>", "orders.eu.paid", '.'));
+        assert!(!matches("*.eu.>", "orders.us.paid", '.'));
+
+        assert!(!matches("orders.>.paid", "orders.eu.paid", '.'));
+    }
+
+    #[test]
+    fn reject_wildcards() {
+        assert!(reject("orders", '.', "redis").is_ok());
+        assert!(reject("orders.*", '.', "redis").is_err());
+        assert!(reject("orders.>", '.', "redis").is_err());
+        assert!(reject("orders/*", '.', "redis").is_err());
+    }
+}
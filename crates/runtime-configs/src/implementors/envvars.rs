@@ -2,11 +2,17 @@ use std::env;
 
 use anyhow::Result;
 
+use crate::ConfigNotFound;
+
 pub struct EnvVars;
 
 impl EnvVars {
     pub fn get(key: &str) -> Result<Vec<u8>> {
-        Ok(env::var(key).map(|thing| thing.as_bytes().to_vec())?)
+        match env::var(key) {
+            Ok(thing) => Ok(thing.as_bytes().to_vec()),
+            Err(env::VarError::NotPresent) => Err(ConfigNotFound(key.to_string()).into()),
+            Err(e) => Err(e.into()),
+        }
     }
 
     pub fn set(key: &str, value: &[u8]) -> Result<()> {
@@ -20,6 +26,7 @@ mod unittests {
     use anyhow::Result;
 
     use super::EnvVars;
+    use crate::ConfigNotFound;
 
     #[test]
     fn set_then_get_test() -> Result<()> {
@@ -28,6 +35,12 @@ mod unittests {
         Ok(())
     }
 
+    #[test]
+    fn get_missing_env_var_test() {
+        let err = EnvVars::get("SLIGHT_MISSING_ENV_VAR").unwrap_err();
+        assert!(err.downcast_ref::<ConfigNotFound>().is_some());
+    }
+
     #[test]
     fn check_path_env_var_test() -> Result<()> {
         assert!(!EnvVars::get("PATH")?.is_empty());
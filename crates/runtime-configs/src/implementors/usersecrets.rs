@@ -1,10 +1,12 @@
 use std::{fs::OpenOptions, path::Path};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use short_crypt::ShortCrypt;
 use slight_core::secret::{create_secret, get_key};
 use slight_file::SlightFileBuilder;
 
+use crate::ConfigNotFound;
+
 pub struct UserSecrets;
 
 impl UserSecrets {
@@ -20,7 +22,8 @@ impl UserSecrets {
         let toml_file_path = toml_file_path;
         let toml = SlightFileBuilder::new().path(toml_file_path)?.build()?;
         if toml.as_ref().secret_settings.is_none() {
-            bail!("failed because toml file has no secrets");
+            return Err(ConfigNotFound(key.to_string()))
+                .context("failed because toml file has no secrets");
         }
 
         // get env var encryption key
@@ -37,7 +40,8 @@ impl UserSecrets {
             // ^^^ note: the unwrap cannot fail
         } else {
             // if it isn't, we will just create new
-            bail!("failed because this secret isn't encrypted in the toml file")
+            return Err(ConfigNotFound(key.to_string()))
+                .context("failed because this secret isn't encrypted in the toml file");
         };
 
         // decrypt key and return value
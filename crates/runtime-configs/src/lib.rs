@@ -1,6 +1,9 @@
 pub mod implementors;
 
-use std::path::Path;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
@@ -202,6 +205,19 @@ impl From<ConfigsImplementor> for String {
     }
 }
 
+/// The error a configs implementor returns when a config doesn't exist, so
+/// that it can be told apart from a failure to get it (e.g., a network error).
+#[derive(Debug)]
+pub struct ConfigNotFound(pub String);
+
+impl std::fmt::Display for ConfigNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no config named '{}' found", self.0)
+    }
+}
+
+impl std::error::Error for ConfigNotFound {}
+
 /// SDK-ish bit
 pub async fn get(
     config_type: ConfigsImplementor,
@@ -264,6 +280,60 @@ pub async fn get_from_state(config_name: &str, state: &BasicState) -> Result<Str
     }
 }
 
+/// Like `get_from_state`, but for optional configs: returns `None` instead
+/// of panicking/erroring when the capability does not define `config_name`.
+///
+/// W/ a secret store, only a `ConfigNotFound` error means the config isn't set,
+/// and any other error (e.g., an auth, or network, one) is returned. The azapp
+/// store can't tell a missing config apart from a failure, so optional configs
+/// must be set in it.
+pub async fn maybe_get_from_state(config_name: &str, state: &BasicState) -> Result<Option<String>> {
+    if state.secret_store.is_some() {
+        return match get_from_state(config_name, state).await {
+            Ok(config) => Ok(Some(config)),
+            Err(e) if e.downcast_ref::<ConfigNotFound>().is_some() => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
+
+    match state.configs_map.as_ref() {
+        Some(configs) if configs.contains_key(config_name) => {
+            Ok(Some(get_from_state(config_name, state).await?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Resolves the directory filesystem-backed implementors (e.g., `keyvalue.filesystem`,
+/// and `messaging.filesystem`) keep their data under.
+///
+/// It is taken from the optional `FILESYSTEM_ROOT` config and defaults to the
+/// system's temporary directory. A relative `FILESYSTEM_ROOT` is resolved against
+/// the directory of the slightfile.
+pub async fn filesystem_root_from_state(state: &BasicState) -> Result<PathBuf> {
+    let root = maybe_get_from_state("FILESYSTEM_ROOT", state)
+        .await
+        .with_context(|| "failed to get 'FILESYSTEM_ROOT' config")?;
+    Ok(resolve_filesystem_root(root, &state.slightfile_path))
+}
+
+fn resolve_filesystem_root(root: Option<String>, slightfile_path: &Path) -> PathBuf {
+    match root {
+        Some(root) => {
+            let root = PathBuf::from(root);
+            if root.is_relative() {
+                slightfile_path
+                    .parent()
+                    .map(|p| p.join(&root))
+                    .unwrap_or(root)
+            } else {
+                root
+            }
+        }
+        None => env::temp_dir(),
+    }
+}
+
 fn maybe_get_config_store_and_value(c: &str) -> Result<(String, String)> {
     let mut regex_match = Regex::new(r"^\$\{(.+)\}$")?;
     if let Some(prelim_cap) = regex_match.captures(c) {
@@ -280,10 +350,12 @@ fn maybe_get_config_store_and_value(c: &str) -> Result<(String, String)> {
 
 #[cfg(test)]
 mod unittests {
+    use std::{env, path::Path};
+
     use anyhow::Result;
     use slight_file::SlightFile;
 
-    use crate::maybe_get_config_store_and_value;
+    use crate::{maybe_get_config_store_and_value, resolve_filesystem_root};
 
     #[test]
     fn parse_this_dot_that() -> Result<()> {
@@ -359,4 +431,21 @@ mod unittests {
 
         Ok(())
     }
+
+    #[test]
+    fn resolve_filesystem_root_test() {
+        let slightfile_path = Path::new("/app/slightfile.toml");
+        assert_eq!(
+            resolve_filesystem_root(Some("data".to_string()), slightfile_path),
+            Path::new("/app/data")
+        );
+        assert_eq!(
+            resolve_filesystem_root(Some("/var/data".to_string()), slightfile_path),
+            Path::new("/var/data")
+        );
+        assert_eq!(
+            resolve_filesystem_root(None, slightfile_path),
+            env::temp_dir()
+        );
+    }
 }
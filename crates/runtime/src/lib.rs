@@ -9,7 +9,7 @@ use std::{
 use anyhow::Result;
 use async_trait::async_trait;
 use ctx::SlightCtxBuilder;
-use resource::{get_host_state, HttpData, HttpServerExportData};
+use resource::{get_host_state, HttpData, HttpServerExportData, MessagingHandlerData};
 use slight_common::{CapabilityBuilder, WasmtimeBuildable, WasmtimeLinkable};
 use tracing::info;
 use wasi_cap_std_sync::{ambient_authority, Dir, WasiCtxBuilder};
@@ -27,6 +27,7 @@ pub type Ctx = RuntimeContext;
 ///    - `wasi`: a wasi context
 ///    - `slight`: a slight context
 ///    - `http_state`: http handler's data
+///    - `messaging_handler_state`: messaging handler's data
 ///
 /// The runtime context will be used inside of the `Builder`
 /// to build a `Store` and `Instance` for the wasm module.
@@ -36,6 +37,7 @@ pub struct RuntimeContext {
     pub slight: SlightCtx,
     pub http_state: HttpData,
     pub http_server_state: HttpServerExportData,
+    pub messaging_handler_state: MessagingHandlerData,
 }
 
 impl slight_common::Ctx for RuntimeContext {
@@ -53,6 +55,10 @@ impl slight_common::Ctx for RuntimeContext {
     fn get_http_server_mut(&mut self) -> &mut slight_http_api::HttpServerExportData {
         &mut self.http_server_state
     }
+
+    fn get_messaging_handler_mut(&mut self) -> &mut MessagingHandlerData {
+        &mut self.messaging_handler_state
+    }
 }
 
 /// Input and output redirects to be used for the running module
@@ -154,6 +160,7 @@ impl WasmtimeBuildable for Builder {
             slight: self.state_builder.build(),
             http_state: HttpData::default(),
             http_server_state: HttpServerExportData::default(),
+            messaging_handler_state: MessagingHandlerData::default(),
         };
 
         let mut store = Store::new(&self.engine, ctx);
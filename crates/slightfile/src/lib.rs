@@ -62,6 +62,12 @@ impl Capability {
             Capability::V2(c) => c.configs.clone(),
         }
     }
+    pub fn subscriptions(&self) -> Option<Vec<Subscription>> {
+        match self {
+            Capability::V1(_) => None,
+            Capability::V2(c) => c.subscriptions.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,6 +80,25 @@ pub struct CapabilityV2 {
     pub resource: Resource,
     pub name: ResourceName,
     pub configs: Option<HashMap<String, String>>,
+    pub subscriptions: Option<Vec<Subscription>>,
+}
+
+/// A subscription binding a messaging topic to an exported guest handler.
+///
+/// For every message received on `topic`, slight instantiates the guest
+/// and invokes the function named `handler`. Replicas subscribed w/ the
+/// same consumer `group` compete for messages, rather than each one
+/// handling all of them.
+///
+/// `max_delivery_count`, and `dead_letter_topic`, override the resource's
+/// `MAX_DELIVERY_COUNT`, and `DEAD_LETTER_TOPIC`, for this subscription.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub topic: String,
+    pub handler: String,
+    pub group: Option<String>,
+    pub max_delivery_count: Option<u32>,
+    pub dead_letter_topic: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -152,6 +177,7 @@ impl SlightFileBuilder {
         let mut slight_file = SlightFileInner::from_toml_string(&self.file_content)?;
         slight_file.check_version()?;
         slight_file.validate_namespace()?;
+        slight_file.validate_subscriptions()?;
         Ok(slight_file)
     }
 }
@@ -250,4 +276,31 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn deserialize_subscriptions() -> Result<()> {
+        let path = format!(
+            "{}/tests/good/msg-subscriptions.toml",
+            env!("CARGO_MANIFEST_DIR")
+        );
+
+        let toml_file = SlightFileBuilder::new().path(path)?.build()?;
+        assert!(toml_file.has_messaging_handlers());
+        let capability = &toml_file.as_ref().capability.as_ref().unwrap()[0];
+        let subscriptions = capability.subscriptions().unwrap();
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(subscriptions[0].topic, "orders");
+        assert_eq!(subscriptions[0].handler, "handle_order");
+        assert_eq!(subscriptions[0].group.as_deref(), Some("order-workers"));
+        assert_eq!(subscriptions[0].max_delivery_count, Some(5));
+        assert_eq!(
+            subscriptions[0].dead_letter_topic.as_deref(),
+            Some("orders-dead-letters")
+        );
+        assert!(subscriptions[1].group.is_none());
+        assert!(subscriptions[1].max_delivery_count.is_none());
+        assert!(subscriptions[1].dead_letter_topic.is_none());
+
+        Ok(())
+    }
 }
@@ -39,6 +39,10 @@ pub enum KeyvalueResource {
     V1Redis,
     #[serde(rename = "keyvalue.dapr")]
     Dapr,
+    #[serde(rename = "keyvalue.sqlite")]
+    Sqlite,
+    #[serde(rename = "keyvalue.memory")]
+    Memory,
 }
 
 impl Display for KeyvalueResource {
@@ -53,12 +57,16 @@ impl Display for KeyvalueResource {
             KeyvalueResource::V1Filesystem => write!(f, "kv.filesystem"),
             KeyvalueResource::V1Redis => write!(f, "kv.redis"),
             KeyvalueResource::Dapr => write!(f, "keyvalue.dapr"),
+            KeyvalueResource::Sqlite => write!(f, "keyvalue.sqlite"),
+            KeyvalueResource::Memory => write!(f, "keyvalue.memory"),
         }
     }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, Hash)]
 pub enum MessagingResource {
+    #[serde(rename = "messaging.amqp")]
+    Amqp,
     #[serde(rename = "messaging.azsbus")]
     Azsbus,
     #[serde(rename = "messaging.confluent_apache_kafka")]
@@ -69,6 +77,8 @@ pub enum MessagingResource {
     Mosquitto,
     #[serde(rename = "messaging.nats")]
     Nats,
+    #[serde(rename = "messaging.redis")]
+    Redis,
     #[serde(rename = "mq.azsbus")]
     V1Azsbus,
     #[serde(rename = "mq.filesystem")]
@@ -78,6 +88,7 @@ pub enum MessagingResource {
 impl Display for MessagingResource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            MessagingResource::Amqp => write!(f, "messaging.amqp"),
             MessagingResource::Azsbus => write!(f, "messaging.azsbus"),
             MessagingResource::ConfluentApacheKafka => {
                 write!(f, "messaging.confluent_apache_kafka")
@@ -85,6 +96,7 @@ impl Display for MessagingResource {
             MessagingResource::Filesystem => write!(f, "messaging.filesystem"),
             MessagingResource::Mosquitto => write!(f, "messaging.mosquitto"),
             MessagingResource::Nats => write!(f, "messaging.nats"),
+            MessagingResource::Redis => write!(f, "messaging.redis"),
             MessagingResource::V1Azsbus => write!(f, "mq.azsbus"),
             MessagingResource::V1Filesystem => write!(f, "mq.filesystem"),
         }
@@ -126,6 +126,34 @@ impl SlightFileInner {
         Ok(())
     }
 
+    /// Validate that handler subscriptions are only bound to messaging resources.
+    pub fn validate_subscriptions(&self) -> Result<()> {
+        if let Some(capabilities) = &self.inner.capability {
+            for cap in capabilities {
+                if cap.subscriptions().is_some()
+                    && !matches!(cap.resource(), Resource::Messaging(_))
+                {
+                    bail!(
+                        "Error: subscriptions can only be defined on messaging capabilities, but found them on {}",
+                        cap.resource()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_messaging_handlers(&self) -> bool {
+        if let Some(capability) = &self.inner.capability {
+            capability.iter().any(|cap| match cap.subscriptions() {
+                Some(subscriptions) => !subscriptions.is_empty(),
+                None => false,
+            })
+        } else {
+            false
+        }
+    }
+
     pub fn has_http_cap(&self) -> bool {
         if let Some(capability) = &self.inner.capability {
             capability.iter().any(|cap| match cap {
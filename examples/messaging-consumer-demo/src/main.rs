@@ -10,17 +10,15 @@ fn main() -> Result<()> {
 
     for _ in 0..3 {
         loop {
-            let msg = ps.receive(&sub_tok)?;
-            if !msg.is_empty() {
-                println!("received message from topic 'rust'> value: {:?}", String::from_utf8(msg));
+            if let Some(msg) = ps.receive(&sub_tok, 1000)? {
+                println!("received message from topic 'rust'> value: {:?}", String::from_utf8(msg.payload));
                 break;
             }
         }
 
         loop {
-            let msg = ps.receive(&sub_tok1)?;
-            if !msg.is_empty() {
-                println!("received message from topic 'global-chat'> value: {:?}", String::from_utf8(msg));
+            if let Some(msg) = ps.receive(&sub_tok1, 1000)? {
+                println!("received message from topic 'global-chat'> value: {:?}", String::from_utf8(msg.payload));
                 break;
             }
         }
@@ -21,8 +21,10 @@ fn main() -> Result<()> {
 
     let mut messages_vec: Vec<String> = vec![];
     for _ in 0..3 {
-        let top_message = s.receive(&sub_tok)?;
-        messages_vec.push(String::from_utf8(top_message)?);
+        let top_message = s
+            .receive(&sub_tok, 1000)?
+            .expect("a message should have been published");
+        messages_vec.push(String::from_utf8(top_message.payload)?);
         println!("top message in the queue: {:#?}", messages_vec.last());
     }
 
@@ -46,6 +46,7 @@ pub async fn handle_run(args: RunArgs) -> Result<()> {
         .path(args.slightfile.clone())?
         .build()?;
     let http_enabled = toml.has_http_cap();
+    let messaging_handlers_enabled = toml.has_messaging_handlers();
     tracing::info!("Starting slight");
     let mut host_builder = Builder::from_module(&args.module)?;
     let mut linked_capabilities: HashSet<String> = HashSet::new();
@@ -69,6 +70,20 @@ pub async fn handle_run(args: RunArgs) -> Result<()> {
     }
     let (mut store, instance) = host_builder.build().await;
 
+    // looking for subscriptions bound to messaging handlers.
+    if cfg!(feature = "messaging") && messaging_handlers_enabled {
+        log::debug!("Messaging handlers enabled");
+        serve_messaging_handlers(
+            toml.as_ref(),
+            &args.slightfile,
+            &args.module,
+            &mut store,
+            args.io_redirects.clone(),
+            args.link_all_capabilities,
+        )
+        .await?;
+    }
+
     // looking for the http capability.
     if cfg!(feature = "http-server") && http_enabled {
         log::debug!("Http capability enabled");
@@ -93,13 +108,24 @@ pub async fn handle_run(args: RunArgs) -> Result<()> {
         }
 
         log::info!("waiting for http to finish...");
-        close_http_server(store).await;
+        close_http_server(&mut store).await;
+    } else if messaging_handlers_enabled {
+        log::info!("waiting for messaging handlers to finish...");
+        shutdown_signal().await;
     } else {
-        instance
+        let started = instance
             .get_typed_func::<(), _>(&mut store, "_start")?
             .call_async(&mut store, ())
-            .await?;
+            .await;
+        // the guest is done, so the subscriptions it left behind can go, even if
+        // it failed
+        shutdown_messaging(&mut store).await;
+        started?;
+        return Ok(());
     }
+    // slight is shutting down, so the messaging handlers can stop, and the
+    // subscriptions they, and the guest, left behind can go
+    shutdown_messaging(&mut store).await;
     Ok(())
 }
 
@@ -123,6 +149,70 @@ async fn update_http_states(
     maybe_stdio: Option<IORedirects>,
     link_all: bool,
 ) -> Result<(), anyhow::Error> {
+    let guest_builder =
+        build_guest_builder(toml, toml_file_path, module, maybe_stdio, link_all).await?;
+    let http_api_resource: &mut HttpServer<Builder> = get_resource(store, "http");
+    http_api_resource.update_state(slight_common::Builder::new(guest_builder))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "messaging"))]
+async fn serve_messaging_handlers(
+    _toml: &SlightFile,
+    _toml_file_path: impl AsRef<Path>,
+    _module: impl AsRef<Path>,
+    _store: &mut Store<slight_runtime::RuntimeContext>,
+    _maybe_stdio: Option<IORedirects>,
+    _link_all: bool,
+) -> Result<(), anyhow::Error> {
+    log::debug!("messaging feature is not enabled");
+    Ok(())
+}
+
+#[cfg(feature = "messaging")]
+async fn serve_messaging_handlers(
+    toml: &SlightFile,
+    toml_file_path: impl AsRef<Path>,
+    module: impl AsRef<Path>,
+    store: &mut Store<slight_runtime::RuntimeContext>,
+    maybe_stdio: Option<IORedirects>,
+    link_all: bool,
+) -> Result<(), anyhow::Error> {
+    let guest_builder = slight_common::Builder::new(
+        build_guest_builder(toml, toml_file_path, module, maybe_stdio, link_all).await?,
+    );
+    let messaging_resource: &mut Messaging = get_resource(store, "messaging");
+    for c in toml.capability.as_ref().unwrap() {
+        if let Some(subscriptions) = c.subscriptions() {
+            messaging_resource
+                .serve_handlers(&c.name().to_string(), subscriptions, guest_builder.clone())
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "messaging"))]
+async fn shutdown_messaging(_store: &mut Store<slight_runtime::RuntimeContext>) {}
+
+#[cfg(feature = "messaging")]
+async fn shutdown_messaging(store: &mut Store<slight_runtime::RuntimeContext>) {
+    // the store only has a messaging resource if the slightfile has one
+    if store.data().slight.contains_key("messaging") {
+        let messaging_resource: &mut Messaging = get_resource(store, "messaging");
+        messaging_resource.shutdown().await;
+    }
+}
+
+/// Builds the runtime builder used to instantiate the guest for each
+/// http request or message delivered to a handler.
+async fn build_guest_builder(
+    toml: &SlightFile,
+    toml_file_path: impl AsRef<Path>,
+    module: impl AsRef<Path>,
+    maybe_stdio: Option<IORedirects>,
+    link_all: bool,
+) -> Result<Builder> {
     let mut guest_builder = Builder::from_module(module)?;
     let mut linked_capabilities = HashSet::new();
 
@@ -137,23 +227,21 @@ async fn update_http_states(
     )
     .await?;
     if let Some(ioredirects) = maybe_stdio {
-        tracing::info!("setting HTTP guest builder io redirects");
+        tracing::info!("setting guest builder io redirects");
         guest_builder = guest_builder.set_io(ioredirects);
     }
-    let http_api_resource: &mut HttpServer<Builder> = get_resource(store, "http");
-    http_api_resource.update_state(slight_common::Builder::new(guest_builder))?;
-    Ok(())
+    Ok(guest_builder)
 }
 
 #[cfg(not(feature = "http-server"))]
-async fn close_http_server(_store: Store<slight_runtime::RuntimeContext>) {
+async fn close_http_server(_store: &mut Store<slight_runtime::RuntimeContext>) {
     log::debug!("http-server feature is not enabled");
 }
 
 #[cfg(feature = "http-server")]
-async fn close_http_server(mut store: Store<slight_runtime::RuntimeContext>) {
+async fn close_http_server(store: &mut Store<slight_runtime::RuntimeContext>) {
     shutdown_signal().await;
-    let http_api_resource: &mut HttpServer<Builder> = get_resource(&mut store, "http");
+    let http_api_resource: &mut HttpServer<Builder> = get_resource(store, "http");
     http_api_resource.close();
 }
 
@@ -281,13 +369,6 @@ async fn build_store_instance(
                     builder.link_capability::<Messaging>()?;
                     linked_capabilities.insert("messaging".to_string());
                 }
-
-                let resource = slight_messaging::Messaging::new(
-                    &c.name().to_string(),
-                    capability_store.clone(),
-                )
-                .await?;
-                builder.add_to_builder("messaging".to_string(), resource);
             }
             #[cfg(feature = "runtime-configs")]
             Resource::Configs(_) => {
@@ -333,6 +414,14 @@ async fn build_store_instance(
         }
     }
 
+    // each named messaging resource gets its' own implementor, so they're all
+    // opened at once, after every capability made it into the store
+    #[cfg(feature = "messaging")]
+    if capability_store.as_ref().contains_key("messaging") {
+        let resource = slight_messaging::Messaging::new(capability_store.clone()).await?;
+        builder.add_to_builder("messaging".to_string(), resource);
+    }
+
     Ok(())
 }
 
@@ -11,12 +11,17 @@ const FILESYSTEM_ACCESS_TEST_PATH: &str = "./filesystem-access-test";
 const IO_TEST_PATH: &str = "./io-test";
 const BLOB_STORE_TEST_PATH: &str = "./blob-store-test";
 const MESSAGING_TEST_PATH: &str = "./messaging-test";
+const MESSAGING_HANDLER_TEST_PATH: &str = "./messaging-handler-test";
 const WILDCARD_TEST_PATH: &str = "./wildcard-test";
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
     println!("cargo:rerun-if-changed={WIT_DIRECTORY}");
     println!("cargo:rerun-if-changed={KEYVALUE_TEST_PATH}/src/lib.rs");
+    println!("cargo:rerun-if-changed={KEYVALUE_TEST_PATH}/src/main.rs");
+    println!("cargo:rerun-if-changed={KEYVALUE_TEST_PATH}/src/bin/keyvalue-test-azblob.rs");
+    println!("cargo:rerun-if-changed={KEYVALUE_TEST_PATH}/src/bin/keyvalue-test-nowatch.rs");
+    println!("cargo:rerun-if-changed={KEYVALUE_TEST_PATH}/src/bin/keyvalue-test-encrypted.rs");
     println!("cargo:rerun-if-changed={HTTP_TEST_PATH}/src/main.rs");
     println!("cargo:rerun-if-changed={CONFIGS_TEST_PATH}/src/main.rs");
     println!("cargo:rerun-if-changed={FILESYSTEM_ACCESS_TEST_PATH}/src/main.rs");
@@ -25,6 +30,12 @@ fn main() {
     println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/src/main.rs");
     println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/consumer_a.rs");
     println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/consumer_b.rs");
+    println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/filesystem_messaging.rs");
+    println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/handler_publisher.rs");
+    println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/cloudevents_messaging.rs");
+    println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/redis_messaging.rs");
+    println!("cargo:rerun-if-changed={MESSAGING_TEST_PATH}/bin/amqp_messaging.rs");
+    println!("cargo:rerun-if-changed={MESSAGING_HANDLER_TEST_PATH}/src/lib.rs");
     println!("cargo:rerun-if-changed={WILDCARD_TEST_PATH}/src/main.rs");
 
     // Check if wasm32-wasi target is installed
@@ -49,6 +60,12 @@ fn main() {
         cargo_wasi_build(MESSAGING_TEST_PATH);
         cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "consumer_a");
         cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "consumer_b");
+        cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "filesystem_messaging");
+        cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "handler_publisher");
+        cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "cloudevents_messaging");
+        cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "redis_messaging");
+        cargo_wasi_build_with_bin_name(MESSAGING_TEST_PATH, "amqp_messaging");
+        cargo_wasi_build(MESSAGING_HANDLER_TEST_PATH);
         cargo_wasi_build(WILDCARD_TEST_PATH);
     }
 }
@@ -0,0 +1,12 @@
+use anyhow::Result;
+
+use keyvalue_test::{run, Supports};
+
+// keyvalue.azblob has no native expiry, nor change notifications
+fn main() -> Result<()> {
+    run(Supports {
+        ttl: false,
+        compare_and_swap: true,
+        watch: false,
+    })
+}
@@ -0,0 +1,20 @@
+#![allow(clippy::enum_variant_names)]
+use anyhow::Result;
+
+use keyvalue::*;
+use keyvalue_test::{run, Supports};
+wit_bindgen_rust::import!("../../wit/keyvalue.wit");
+wit_error_rs::impl_error!(keyvalue::KeyvalueError);
+
+fn main() -> Result<()> {
+    run(Supports {
+        ttl: true,
+        compare_and_swap: true,
+        watch: true,
+    })?;
+
+    // leave a key behind, so that the host can check it is encrypted at rest
+    let keyvalue = Keyvalue::open("slight-keyvalue-test-1")?;
+    keyvalue.set("at-rest-key", "spiderlightning at rest".as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,12 @@
+use anyhow::Result;
+
+use keyvalue_test::{run, Supports};
+
+// only keyvalue.filesystem and keyvalue.redis can watch for changes
+fn main() -> Result<()> {
+    run(Supports {
+        ttl: true,
+        compare_and_swap: true,
+        watch: false,
+    })
+}
@@ -0,0 +1,175 @@
+#![allow(clippy::enum_variant_names)]
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+
+use keyvalue::*;
+wit_bindgen_rust::import!("../../wit/keyvalue.wit");
+wit_error_rs::impl_error!(keyvalue::KeyvalueError);
+
+/// The optional operations of the implementor under test. Each one is tested
+/// to work if it's supported, and to fail if it isn't.
+pub struct Supports {
+    pub ttl: bool,
+    pub compare_and_swap: bool,
+    pub watch: bool,
+}
+
+pub fn run(supports: Supports) -> Result<()> {
+    // test get, set, delete
+    let keyvalue = Keyvalue::open("slight-keyvalue-test-4")?;
+    let value = "spiderlightning".as_bytes();
+    keyvalue.set("key", value)?;
+    println!(
+        "Hello, world! the value is: {}",
+        std::str::from_utf8(&keyvalue.get("key")?)?
+    );
+    keyvalue.delete("key")?;
+    let value = keyvalue.get("key");
+    assert!(value.is_err());
+
+    // test keys
+    let keyvalue = Keyvalue::open("slight-keyvalue-test-4")?;
+    let value = "spiderlightning".as_bytes();
+    keyvalue.set("key", value)?;
+    keyvalue.set("key2", value)?;
+    let keys = keyvalue.keys()?;
+    assert_eq!(keys.len(), 2);
+    assert!(keys.contains(&"key".to_string()));
+    keyvalue.delete("key")?;
+    keyvalue.delete("key2")?;
+
+    let keyvalue1 = Keyvalue::open("slight-keyvalue-test-1")?;
+    let keyvalue2 = Keyvalue::open("slight-keyvalue-test-2")?;
+    keyvalue1.set("key1", "value1".as_bytes())?;
+    keyvalue2.set("key2", "value2".as_bytes())?;
+
+    assert!(keyvalue1.get("key2").is_err());
+    keyvalue1.delete("key1")?;
+    keyvalue2.delete("key2")?;
+
+    let keyvalue1 = Keyvalue::open("slight-keyvalue-test-1")?;
+    let keyvalue2 = Keyvalue::open("slight-keyvalue-test-1")?;
+    keyvalue1.set("key1", "value1".as_bytes())?;
+    keyvalue2.set("key2", "value2".as_bytes())?;
+    assert!(keyvalue1.get("key2")? == "value2".as_bytes());
+    keyvalue1.delete("key1")?;
+    keyvalue2.delete("key2")?;
+
+    // test get empty key
+    let keyvalue3 = Keyvalue::open("slight-keyvalue-test-3")?;
+    let value = keyvalue3.get("");
+    assert!(value.is_err());
+
+    // test keys that look like paths
+    keyvalue3.set("nested/key", "value3".as_bytes())?;
+    assert!(keyvalue3.get("nested/key")? == "value3".as_bytes());
+    assert!(keyvalue3.keys()?.contains(&"nested/key".to_string()));
+    keyvalue3.delete("nested/key")?;
+
+    // test batch get, set, delete
+    keyvalue3.set_many(&[
+        ("batch-key1", "value1".as_bytes()),
+        ("batch-key2", "value2".as_bytes()),
+    ])?;
+    let values = keyvalue3.get_many(&["batch-key1", "batch-key2", "batch-key3"])?;
+    assert_eq!(values.len(), 2);
+    assert!(values.contains(&("batch-key2".to_string(), "value2".as_bytes().to_vec())));
+    keyvalue3.delete_many(&["batch-key1", "batch-key2"])?;
+    assert!(keyvalue3
+        .get_many(&["batch-key1", "batch-key2"])?
+        .is_empty());
+
+    // test paging through keys with a prefix
+    keyvalue3.set_many(&[
+        ("page-a1", "value".as_bytes()),
+        ("page-a2", "value".as_bytes()),
+        ("page-b1", "value".as_bytes()),
+    ])?;
+    let mut keys = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = keyvalue3.list_keys("page-a", cursor.as_deref(), Some(1))?;
+        assert!(page.keys.len() <= 1);
+        keys.extend(page.keys);
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    // list-keys may hand back a key in more than one page, so only compare the
+    // distinct keys (`keys` lists each of them once)
+    let keys: BTreeSet<String> = keys.into_iter().collect();
+    assert_eq!(
+        keys,
+        BTreeSet::from(["page-a1".to_string(), "page-a2".to_string()])
+    );
+    let mut all_keys = keyvalue3.keys()?;
+    let listed = all_keys.len();
+    all_keys.sort();
+    all_keys.dedup();
+    assert_eq!(all_keys.len(), listed);
+    keyvalue3.delete_many(&["page-a1", "page-a2", "page-b1"])?;
+
+    // test ttl
+    if supports.ttl {
+        keyvalue3.set_with_ttl("ttl-key", "value3".as_bytes(), 1)?;
+        assert!(keyvalue3.get("ttl-key")? == "value3".as_bytes());
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        assert!(keyvalue3.get("ttl-key").is_err());
+        assert!(!keyvalue3.keys()?.contains(&"ttl-key".to_string()));
+    } else {
+        assert!(keyvalue3
+            .set_with_ttl("ttl-key", "value3".as_bytes(), 1)
+            .is_err());
+    }
+
+    // test compare-and-swap and increment
+    if supports.compare_and_swap {
+        assert!(keyvalue3.compare_and_swap("cas-key", None, "1".as_bytes())?);
+        assert!(!keyvalue3.compare_and_swap("cas-key", None, "2".as_bytes())?);
+        assert!(!keyvalue3.compare_and_swap("cas-key", Some("2".as_bytes()), "3".as_bytes())?);
+        assert!(keyvalue3.compare_and_swap("cas-key", Some("1".as_bytes()), "2".as_bytes())?);
+        assert_eq!(keyvalue3.increment("cas-key", 5)?, 7);
+        assert_eq!(keyvalue3.increment("counter", -1)?, -1);
+        keyvalue3.delete("cas-key")?;
+        keyvalue3.delete("counter")?;
+    } else {
+        assert!(keyvalue3
+            .compare_and_swap("cas-key", None, "1".as_bytes())
+            .is_err());
+        assert!(keyvalue3.increment("counter", 1).is_err());
+    }
+
+    // test watching for changes
+    if supports.watch {
+        let watch_tok = keyvalue3.watch("watched-")?;
+        keyvalue3.set("watched-key", "value".as_bytes())?;
+        keyvalue3.set("unwatched-key", "value".as_bytes())?;
+        keyvalue3.delete("watched-key")?;
+        let is_set = |c: &ChangeEvent| matches!(c, ChangeEvent::Set(k) if k == "watched-key");
+        let is_delete = |c: &ChangeEvent| matches!(c, ChangeEvent::Delete(k) if k == "watched-key");
+        // changes are picked up in the background, so give them a moment to arrive
+        let mut changes = Vec::new();
+        for _ in 0..10 {
+            changes.extend(keyvalue3.changes(&watch_tok)?);
+            if changes.iter().any(is_delete) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        }
+        assert!(changes.iter().any(is_set));
+        assert!(changes.iter().any(is_delete));
+        assert!(!changes.iter().any(|c| match c {
+            ChangeEvent::Set(k) | ChangeEvent::Delete(k) => k == "unwatched-key",
+        }));
+        keyvalue3.unwatch(&watch_tok)?;
+        assert!(keyvalue3.changes(&watch_tok).is_err());
+        keyvalue3.delete("unwatched-key")?;
+    } else {
+        assert!(keyvalue3.watch("watched-").is_err());
+    }
+
+    println!("finished running keyvalue-test");
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use slight_messaging_handler_macro::register_message_handler;
+
+wit_bindgen_rust::import!("../../wit/messaging.wit");
+wit_error_rs::impl_error!(messaging::MessagingError);
+
+#[register_message_handler]
+fn handle_order(msg: Message) -> Result<(), String> {
+    assert_eq!(msg.topic, "orders");
+    let order_id = msg
+        .headers
+        .iter()
+        .find(|(k, _)| k == "order-id")
+        .map(|(_, v)| v.clone())
+        .ok_or_else(|| "missing order-id header".to_string())?;
+
+    let ps = messaging::Pub::open("my-handler-messaging").map_err(|e| e.to_string())?;
+    let processed = format!("{order_id}: {}", String::from_utf8_lossy(&msg.payload));
+    ps.publish(processed.as_bytes(), "processed-orders")
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use anyhow::Result;
+
+use messaging::*;
+
+wit_bindgen_rust::import!("../../wit/messaging.wit");
+wit_error_rs::impl_error!(messaging::MessagingError);
+
+fn main() -> Result<()> {
+    let ps = Pub::open("my-messaging")?;
+    let sub = Sub::open("my-messaging")?;
+
+    // messages published before subscribing are not delivered
+    ps.publish("before".as_bytes(), "fifo")?;
+    let sub_tok = sub.subscribe("fifo")?;
+
+    // messages come out in the order they went in, byte-for-byte (once the broker
+    // has confirmed them)
+    let messages: [&[u8]; 4] = [b"first", b"second\nline", &[0, b'\n', 255], b""];
+    for message in messages {
+        ps.publish(message, "fifo")?;
+    }
+    for message in messages {
+        let received = sub.receive(&sub_tok, 1000)?.unwrap();
+        assert_eq!(received.payload, message);
+        assert_eq!(received.topic, "fifo");
+        assert!(received.handle.is_empty());
+    }
+    assert!(sub.receive(&sub_tok, 100)?.is_none());
+
+    // nacked messages are redelivered straight away, and acked ones aren't
+    let ack_tok = sub.subscribe("acks")?;
+    ps.publish_with_headers(b"nacked", "acks", &[("correlation-id", "42")])?;
+    let message = sub.receive_message(&ack_tok, None)?.unwrap();
+    assert_eq!(message.payload, b"nacked");
+    assert_eq!(
+        message.headers,
+        vec![("correlation-id".to_string(), "42".to_string())]
+    );
+    assert!(message.system_properties.message_id.is_some());
+    assert!(message.system_properties.timestamp.is_some());
+    assert_eq!(message.system_properties.delivery_count, Some(1));
+    sub.nack(&message.handle)?;
+    let redelivered = sub.receive_message(&ack_tok, None)?.unwrap();
+    assert_eq!(redelivered.payload, b"nacked");
+    assert_eq!(
+        redelivered.system_properties.message_id,
+        message.system_properties.message_id
+    );
+    sub.ack(&redelivered.handle)?;
+    assert!(sub.ack(&redelivered.handle).is_err());
+    assert!(sub.receive_message(&ack_tok, None)?.is_none());
+
+    // members of a consumer group take turns receiving, while other subscriptions
+    // still get every message
+    let all_tok = sub.subscribe("work")?;
+    let worker_a = sub.subscribe_with_group("work", "slight-amqp-test-workers")?;
+    let worker_b = sub.subscribe_with_group("work", "slight-amqp-test-workers")?;
+    for message in ["job 1", "job 2", "job 3"] {
+        ps.publish(message.as_bytes(), "work")?;
+    }
+    for (worker, job) in [
+        (&worker_a, "job 1"),
+        (&worker_b, "job 2"),
+        (&worker_a, "job 3"),
+    ] {
+        let message = sub.receive_message(worker, None)?.unwrap();
+        assert_eq!(message.payload, job.as_bytes());
+        sub.ack(&message.handle)?;
+    }
+    assert!(sub.receive_message(&worker_b, None)?.is_none());
+    for job in ["job 1", "job 2", "job 3"] {
+        assert_eq!(
+            sub.receive(&all_tok, 1000)?.unwrap().payload,
+            job.as_bytes()
+        );
+    }
+
+    // the messages a member leaves unacked are redelivered to the group's other
+    // members once it unsubscribes
+    ps.publish(b"job 4", "work")?;
+    let message = sub.receive_message(&worker_a, None)?.unwrap();
+    assert_eq!(message.payload, b"job 4");
+    assert!(sub.receive_message(&worker_b, None)?.is_none());
+    sub.unsubscribe(&worker_a)?;
+    assert!(sub.ack(&message.handle).is_err());
+    let redelivered = sub.receive_message(&worker_b, None)?.unwrap();
+    assert_eq!(redelivered.payload, b"job 4");
+    assert_eq!(redelivered.system_properties.delivery_count, None);
+    sub.ack(&redelivered.handle)?;
+    sub.unsubscribe(&worker_b)?;
+
+    // wildcard subscriptions get the messages of every topic they match, along w/
+    // the concrete topic each one was published to
+    let one_level_tok = sub.subscribe("orders.*")?;
+    let multi_level_tok = sub.subscribe("orders.>")?;
+    ps.publish(b"all", "orders")?;
+    ps.publish(b"placed", "orders.eu")?;
+    ps.publish(b"paid", "orders.eu.paid")?;
+    let message = sub.receive(&one_level_tok, 1000)?.unwrap();
+    assert_eq!(message.topic, "orders.eu");
+    assert_eq!(message.payload, b"placed");
+    assert!(sub.receive(&one_level_tok, 100)?.is_none());
+    // `>` matches one or more levels, but not none
+    for (topic, payload) in [("orders.eu", "placed"), ("orders.eu.paid", "paid")] {
+        let message = sub.receive(&multi_level_tok, 1000)?.unwrap();
+        assert_eq!(message.topic, topic);
+        assert_eq!(message.payload, payload.as_bytes());
+    }
+    assert!(sub.receive(&multi_level_tok, 100)?.is_none());
+    // wildcards that span levels, or the broker's own, aren't subscribed to as-is
+    assert!(sub.subscribe("orders*").is_err());
+    assert!(sub.subscribe("orders.#").is_err());
+
+    // subscriptions can't be received w/ once they are cancelled
+    sub.unsubscribe(&sub_tok)?;
+    assert!(sub.receive(&sub_tok, 0).is_err());
+    assert!(sub.unsubscribe(&sub_tok).is_err());
+
+    println!("finished running amqp messaging test");
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use anyhow::Result;
+
+use messaging::*;
+
+wit_bindgen_rust::import!("../../wit/messaging.wit");
+wit_error_rs::impl_error!(messaging::MessagingError);
+
+fn main() -> Result<()> {
+    let ps = Pub::open("my-events")?;
+    let sub = Sub::open("my-events")?;
+    let sub_tok = sub.subscribe("orders")?;
+
+    // json data is published as json, and received as-is, w/ the event's attributes
+    // as `ce-` headers
+    ps.publish(br#"{"order":42}"#, "orders")?;
+    let event = sub.receive(&sub_tok, 0)?.unwrap();
+    assert_eq!(event.payload, br#"{"order":42}"#);
+    assert_header(&event, "content-type", "application/json");
+    assert_header(&event, "ce-specversion", "1.0");
+    assert_header(&event, "ce-source", "my-events");
+    assert_header(&event, "ce-type", "com.example.orders");
+    assert!(event.headers.iter().any(|(k, _)| k == "ce-id"));
+
+    // attributes can be set per message through `ce-` headers, and other headers
+    // are left as they are
+    ps.publish_with_headers(
+        b"placed",
+        "orders",
+        &[
+            ("ce-type", "com.example.order.placed"),
+            ("ce-subject", "42"),
+            ("correlation-id", "7"),
+        ],
+    )?;
+    let event = sub.receive(&sub_tok, 0)?.unwrap();
+    assert_eq!(event.payload, b"placed");
+    assert_header(&event, "content-type", "text/plain");
+    assert_header(&event, "ce-type", "com.example.order.placed");
+    assert_header(&event, "ce-subject", "42");
+    assert_header(&event, "correlation-id", "7");
+
+    // data that is neither json, nor text, is carried in base64
+    let bytes: &[u8] = &[0, 159, 146, 150];
+    ps.publish_batch(&[bytes], "orders")?;
+    let event = sub.receive(&sub_tok, 0)?.unwrap();
+    assert_eq!(event.payload, bytes);
+    assert_header(&event, "ce-type", "com.example.orders");
+
+    println!("finished running cloudevents messaging test");
+    Ok(())
+}
+
+fn assert_header(message: &Message, key: &str, value: &str) {
+    assert!(
+        message
+            .headers
+            .contains(&(key.to_string(), value.to_string())),
+        "missing header {key}: {value} in {:?}",
+        message.headers
+    );
+}
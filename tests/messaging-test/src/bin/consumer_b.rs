@@ -10,8 +10,9 @@ fn main() -> Result<()> {
     let sub_token = sub.subscribe("room")?;
     let ps = Pub::open("my-messaging")?;
     loop {
-        let msg = sub.receive(&sub_token)?;
-        println!("Received message: {msg:?}");
-        ps.publish(&msg, "service-b-channel-out")?;
+        if let Some(msg) = sub.receive(&sub_token, 1000)? {
+            println!("Received message: {:?}", msg.payload);
+            ps.publish(&msg.payload, "service-b-channel-out")?;
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,28 @@
+use std::{thread::sleep, time::Duration};
+
+use anyhow::{bail, Result};
+
+use messaging::*;
+
+wit_bindgen_rust::import!("../../wit/messaging.wit");
+wit_error_rs::impl_error!(messaging::MessagingError);
+
+// publishes an order for the `handle_order` handler in `messaging-handler-test`
+// to process, and waits for the result it publishes back
+fn main() -> Result<()> {
+    let ps = Pub::open("my-handler-messaging")?;
+    let sub = Sub::open("my-handler-messaging")?;
+    let sub_tok = sub.subscribe("processed-orders")?;
+
+    ps.publish_with_headers(b"two coffees", "orders", &[("order-id", "42")])?;
+
+    for _ in 0..100 {
+        if let Some(message) = sub.receive_message(&sub_tok, None)? {
+            assert_eq!(message.payload, b"42: two coffees");
+            sub.ack(&message.handle)?;
+            return Ok(());
+        }
+        sleep(Duration::from_millis(100));
+    }
+    bail!("the order was not processed by the handler");
+}
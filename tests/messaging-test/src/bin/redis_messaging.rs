@@ -0,0 +1,149 @@
+use anyhow::Result;
+
+use messaging::*;
+
+wit_bindgen_rust::import!("../../wit/messaging.wit");
+wit_error_rs::impl_error!(messaging::MessagingError);
+
+fn main() -> Result<()> {
+    let ps = Pub::open("my-messaging")?;
+    let sub = Sub::open("my-messaging")?;
+
+    // messages published before subscribing are not delivered
+    ps.publish("before".as_bytes(), "fifo")?;
+    let sub_tok = sub.subscribe("fifo")?;
+
+    // messages come out in the order they went in, byte-for-byte
+    let messages: [&[u8]; 3] = [b"first", b"second\nline", &[0, b'\n', 255]];
+    for message in messages {
+        ps.publish(message, "fifo")?;
+    }
+    for message in messages {
+        assert_eq!(sub.receive(&sub_tok, 0)?.unwrap().payload, message);
+    }
+    assert!(sub.receive(&sub_tok, 0)?.is_none());
+
+    // an empty message can be told apart from no message at all, and a receive
+    // waits up to its' timeout for one to arrive
+    ps.publish(b"", "fifo")?;
+    let message = sub.receive(&sub_tok, 0)?.unwrap();
+    assert!(message.payload.is_empty());
+    assert!(message.handle.is_empty());
+    assert!(sub.receive(&sub_tok, 100)?.is_none());
+
+    // unacknowledged messages are redelivered once their visibility timeout passes
+    let ack_tok = sub.subscribe("acks")?;
+    ps.publish(b"acked", "acks")?;
+    ps.publish(b"nacked", "acks")?;
+    let message = sub.receive_message(&ack_tok, Some(0))?.unwrap();
+    assert_eq!(message.payload, b"acked");
+    let redelivered = sub.receive_message(&ack_tok, None)?.unwrap();
+    assert_eq!(redelivered.payload, b"acked");
+    sub.ack(&redelivered.handle)?;
+    assert!(sub.ack(&redelivered.handle).is_err());
+
+    // nacked messages are redelivered straight away
+    let message = sub.receive_message(&ack_tok, None)?.unwrap();
+    assert_eq!(message.payload, b"nacked");
+    sub.nack(&message.handle)?;
+    let redelivered = sub.receive_message(&ack_tok, None)?.unwrap();
+    assert_eq!(redelivered.payload, b"nacked");
+    sub.ack(&redelivered.handle)?;
+    assert!(sub.receive_message(&ack_tok, None)?.is_none());
+
+    // headers and system properties come along w/ received messages
+    let meta_tok = sub.subscribe("meta")?;
+    ps.publish_with_headers(
+        b"with headers",
+        "meta",
+        &[("content-type", "text/plain"), ("correlation-id", "42")],
+    )?;
+    let message = sub.receive_message(&meta_tok, Some(0))?.unwrap();
+    assert_eq!(message.payload, b"with headers");
+    assert_eq!(
+        message.headers,
+        vec![
+            ("content-type".to_string(), "text/plain".to_string()),
+            ("correlation-id".to_string(), "42".to_string()),
+        ]
+    );
+    assert!(message.system_properties.message_id.is_some());
+    assert!(message.system_properties.timestamp.is_some());
+    assert_eq!(message.system_properties.delivery_count, Some(1));
+    let redelivered = sub.receive_message(&meta_tok, None)?.unwrap();
+    assert_eq!(
+        redelivered.system_properties.message_id,
+        message.system_properties.message_id
+    );
+    assert_eq!(redelivered.system_properties.delivery_count, Some(2));
+    sub.ack(&redelivered.handle)?;
+
+    // members of a consumer group take turns receiving, while other subscriptions
+    // still get every message, and the group's messages are redelivered through it
+    let all_tok = sub.subscribe("work")?;
+    let worker_a = sub.subscribe_with_group("work", "workers")?;
+    let worker_b = sub.subscribe_with_group("work", "workers")?;
+    for message in ["job 1", "job 2", "job 3"] {
+        ps.publish(message.as_bytes(), "work")?;
+    }
+    for (worker, job) in [
+        (&worker_a, "job 1"),
+        (&worker_b, "job 2"),
+        (&worker_a, "job 3"),
+    ] {
+        let message = sub.receive_message(worker, None)?.unwrap();
+        assert_eq!(message.payload, job.as_bytes());
+        sub.ack(&message.handle)?;
+    }
+    assert!(sub.receive_message(&worker_b, None)?.is_none());
+    for job in ["job 1", "job 2", "job 3"] {
+        assert_eq!(sub.receive(&all_tok, 0)?.unwrap().payload, job.as_bytes());
+    }
+    ps.publish(b"job 4", "work")?;
+    let message = sub.receive_message(&worker_a, None)?.unwrap();
+    sub.nack(&message.handle)?;
+    let redelivered = sub.receive_message(&worker_b, None)?.unwrap();
+    assert_eq!(redelivered.payload, b"job 4");
+    assert_eq!(redelivered.system_properties.delivery_count, Some(2));
+    sub.ack(&redelivered.handle)?;
+
+    // a consumer group reads from a single stream, so wildcards aren't supported
+    assert!(sub.subscribe("orders.*").is_err());
+
+    // streams are trimmed as they are published to, so a subscription that falls
+    // behind misses the oldest messages (trimming is approximate, though, so it
+    // can't tell exactly how many)
+    let trimmed_tok = sub.subscribe("trimmed")?;
+    let published: Vec<String> = (0..300).map(|i| format!("message {i}")).collect();
+    let batch: Vec<&[u8]> = published.iter().map(|m| m.as_bytes()).collect();
+    ps.publish_batch(&batch, "trimmed")?;
+    let received = sub.receive_batch(&trimmed_tok, 300, 0)?;
+    assert!(!received.is_empty() && received.len() < 300);
+    assert_eq!(received.last().unwrap().payload, b"message 299");
+
+    // subscriptions can't be received w/ once they are cancelled
+    sub.unsubscribe(&sub_tok)?;
+    sub.unsubscribe(&worker_a)?;
+    assert!(sub.receive(&sub_tok, 0).is_err());
+    assert!(sub.unsubscribe(&sub_tok).is_err());
+
+    // messages delivered more than `MAX_DELIVERY_COUNT` (i.e., 3) times are moved to
+    // the dead-letter topic
+    let poison_tok = sub.subscribe("poison")?;
+    let dead_letter_tok = sub.subscribe("poison-dead-letters")?;
+    ps.publish_with_headers(b"poison", "poison", &[("correlation-id", "43")])?;
+    for _ in 0..3 {
+        let message = sub.receive_message(&poison_tok, None)?.unwrap();
+        sub.nack(&message.handle)?;
+    }
+    assert!(sub.receive_message(&poison_tok, None)?.is_none());
+    let dead_letter = sub.receive(&dead_letter_tok, 0)?.unwrap();
+    assert_eq!(dead_letter.payload, b"poison");
+    assert_eq!(
+        dead_letter.headers,
+        vec![("correlation-id".to_string(), "43".to_string())]
+    );
+
+    println!("finished running redis messaging test");
+    Ok(())
+}
@@ -74,10 +74,10 @@ fn handle_get(request: Request) -> Result<Response, HttpError> {
         .unwrap_or(("".into(), "".into()))
         .1;
     let sub = messaging::Sub::open(&id).unwrap();
-    let msg = sub.receive(&id).unwrap();
+    let msg = sub.receive(&id, 5000).unwrap();
     Ok(Response {
         headers: Some(request.headers),
-        body: Some(msg),
+        body: msg.map(|msg| msg.payload),
         status: 200,
     })
 }